@@ -1,6 +1,8 @@
 use std::sync::mpsc::{Sender, Receiver};
 use std::ptr;
 
+use failure;
+
 use reactor::*;
 use reactor::{Event};
 
@@ -24,67 +26,135 @@ pub fn mem_backend_loop(sender: Sender<Event>, receiver: Receiver<BDRequest>, si
         let event = receiver.recv();
 
         match event {
-            Ok(BDRequest::Read(r)) => {
-
-                let result = if r.offset + r.length <= size as u64 {
-                    let mut data = Vec::with_capacity(r.length as usize);
-                    unsafe {
-                        data.set_len(r.length as usize);
-                        ptr::copy_nonoverlapping(mem[r.offset as usize..].as_ptr(), data.as_mut_ptr(), r.length as usize);
-                    }
-                    
-                    Ok(FutureEvent::ReadResponse(ReadResponse {
-                        data
-                    }))
-                } else {
-                    Err(format_err!("mem backend: read operation ouside of limit: {} + {} > {}", r.offset, r.length, size))
-                };
+            Ok(request) => process_request(&sender, &mut mem, request),
+            Err(_) => {
+                // the channel is closed, exit loop
+                break;
+            }
+        }
+    }
+}
 
-                let event = Event::ToFuture {
-                    event_id: r.event_id,
-                    task_id: r.task_id,
-                    result
-                };
+fn read(mem: &Vec<u8>, offset: u64, length: u64) -> Result<Vec<u8>, failure::Error> {
+    let size = mem.len();
+    if offset + length <= size as u64 {
+        let mut data = Vec::with_capacity(length as usize);
+        unsafe {
+            data.set_len(length as usize);
+            ptr::copy_nonoverlapping(mem[offset as usize..].as_ptr(), data.as_mut_ptr(), length as usize);
+        }
+        Ok(data)
+    } else {
+        Err(format_err!("mem backend: read operation ouside of limit: {} + {} > {}", offset, length, size))
+    }
+}
 
-                sender.send(event).unwrap();
-            },
-            Ok(BDRequest::Write(w)) => {
-                
-                let result = if w.offset + w.data.len() as u64 <= size as u64 {
-                    unsafe {
-                        ptr::copy_nonoverlapping(w.data.as_ptr(), mem[w.offset as usize..].as_mut_ptr(), w.data.len() as usize);
-                    }
-                    
-                    Ok(FutureEvent::WriteResponse(WriteResponse{len: w.data.len() as u64}))
-                } else {
-                    Err(format_err!("mem backend: write operation ouside of limit: {} + {} > {}", w.offset, w.data.len(), size))
-                };
+fn write(mem: &mut Vec<u8>, offset: u64, data: &[u8]) -> Result<u64, failure::Error> {
+    let size = mem.len();
+    if offset + data.len() as u64 <= size as u64 {
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), mem[offset as usize..].as_mut_ptr(), data.len() as usize);
+        }
+        Ok(data.len() as u64)
+    } else {
+        Err(format_err!("mem backend: write operation ouside of limit: {} + {} > {}", offset, data.len(), size))
+    }
+}
 
-                let event = Event::ToFuture {
-                    event_id: w.event_id,
-                    task_id: w.task_id,
-                    result
-                };
+/// Executes `request` and sends its responses, one per request of a batch.
+fn process_request(sender: &Sender<Event>, mem: &mut Vec<u8>, request: BDRequest) {
+    match request {
+        BDRequest::Read(r) => {
 
-                sender.send(event).unwrap();
-            },
-            Ok(BDRequest::Flush(f)) => {
+            let result = read(mem, r.offset, r.length)
+                .map(|data| FutureEvent::ReadResponse(ReadResponse {
+                    data
+                }));
 
-                // no-op
+            let event = Event::ToFuture {
+                event_id: r.event_id,
+                task_id: r.task_id,
+                result
+            };
 
-                let event = 
-                    Event::ToFuture {
-                        event_id: f.event_id,
-                        task_id: f.task_id,
-                        result: Ok(FutureEvent::FlushResponse(FlushResponse{}))
-                    };
+            sender.send(event).unwrap();
+        },
+        BDRequest::Write(w) => {
 
-                sender.send(event).unwrap();
-            },
-            Err(_) => {
-                // the channel is closed, exit loop
-                break;
+            let result = write(mem, w.offset, &w.data)
+                .map(|len| FutureEvent::WriteResponse(WriteResponse{len}));
+
+            let event = Event::ToFuture {
+                event_id: w.event_id,
+                task_id: w.task_id,
+                result
+            };
+
+            sender.send(event).unwrap();
+        },
+        BDRequest::ReadV(r) => {
+
+            let result = r.segments.iter()
+                .map(|s| read(mem, s.offset, s.length))
+                .collect::<Result<Vec<Vec<u8>>, failure::Error>>()
+                .map(|data| FutureEvent::ReadVResponse(ReadVResponse {
+                    data
+                }));
+
+            let event = Event::ToFuture {
+                event_id: r.event_id,
+                task_id: r.task_id,
+                result
+            };
+
+            sender.send(event).unwrap();
+        },
+        BDRequest::WriteV(w) => {
+
+            // the segments are written in order until one fails
+            let mut result = Ok(0);
+            for s in &w.segments {
+                result = result.and_then(|len| write(mem, s.offset, &s.data).map(|l| len + l));
             }
-        }
+            let result = result
+                .map(|len| FutureEvent::WriteResponse(WriteResponse{len}));
+
+            let event = Event::ToFuture {
+                event_id: w.event_id,
+                task_id: w.task_id,
+                result
+            };
+
+            sender.send(event).unwrap();
+        },
+        BDRequest::Flush(f) => {
+
+            // no-op
+
+            let event = 
+                Event::ToFuture {
+                    event_id: f.event_id,
+                    task_id: f.task_id,
+                    result: Ok(FutureEvent::FlushResponse(FlushResponse{}))
+                };
+
+            sender.send(event).unwrap();
+        },
+        BDRequest::Size(s) => {
+
+            let event = 
+                Event::ToFuture {
+                    event_id: s.event_id,
+                    task_id: s.task_id,
+                    result: Ok(FutureEvent::SizeResponse(SizeResponse{size: mem.len() as u64}))
+                };
+
+            sender.send(event).unwrap();
+        },
+        BDRequest::Batch(b) => {
+            for request in b.requests {
+                process_request(sender, mem, request);
+            }
+        },
     }
 }
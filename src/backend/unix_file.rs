@@ -1,14 +1,17 @@
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
 use std::io::SeekFrom;
 use std::sync::mpsc::{Sender, Receiver};
 
+use failure;
+
 use reactor::*;
 use reactor::{Event};
 
 /* TODO
  - enforce block semantics (4k)
  - use logger
+ - use preadv/pwritev for the vectored requests
 */
 
 /// This function implements a unix file backend.
@@ -17,14 +20,15 @@ use reactor::{Event};
 /// It should then be called from inside a thread.
 ///
 /// All data is written to the file `bd.raw`.
+/// If `read_only` is `true`, it is opened without write permission and must already exist.
 ///
 /// All operations are recorded in the file `bd.log`.
-pub fn unix_file_backend_loop(sender: Sender<Event>, receiver: Receiver<BDRequest>) {
+pub fn unix_file_backend_loop(sender: Sender<Event>, receiver: Receiver<BDRequest>, read_only: bool) {
     
     let mut bd = OpenOptions::new()
         .read(true)
-        .write(true)
-        .create(true)
+        .write(!read_only)
+        .create(!read_only)
         .open("bd.raw").expect("failed to open bd.raw");
 
     let mut log = OpenOptions::new()
@@ -38,70 +42,7 @@ pub fn unix_file_backend_loop(sender: Sender<Event>, receiver: Receiver<BDReques
         write!(log, "received : {:?}\n", event).unwrap();
 
         match event {
-            Ok(BDRequest::Read(r)) => {
-
-                bd.seek(SeekFrom::Start(r.offset)).unwrap();
-                let mut data = vec![0;r.length as usize]; // maybe do not initialize it
-                let res = bd.read_exact(&mut data);
-
-                let result = match res {
-                    Ok(()) => 
-                        Ok(FutureEvent::ReadResponse(ReadResponse {
-                                data
-                        }))
-                    ,
-                    Err(e) => 
-                        Err(e.into())
-                };
-
-                let event = Event::ToFuture {
-                    event_id: r.event_id,
-                    task_id: r.task_id,
-                    result
-                };
-
-                write!(log, "sent: {:?}\n", event).unwrap();
-
-                sender.send(event).unwrap();
-            },
-            Ok(BDRequest::Write(w)) => {
-                
-                bd.seek(SeekFrom::Start(w.offset)).unwrap();
-                let res = bd.write_all(&w.data);
-
-                let result = match res {
-                    Ok(()) => 
-                        Ok(FutureEvent::WriteResponse(WriteResponse{len: w.data.len() as u64}))
-                    ,
-                    Err(e) => 
-                        Err(e.into())
-                };
-
-                let event = Event::ToFuture {
-                    event_id: w.event_id,
-                    task_id: w.task_id,
-                    result
-                };
-
-                write!(log, "sent: {:?}\n", event).unwrap();
-
-                sender.send(event).unwrap();
-            },
-            Ok(BDRequest::Flush(f)) => {
-
-                // no-op
-
-                let event = 
-                    Event::ToFuture {
-                        event_id: f.event_id,
-                        task_id: f.task_id,
-                        result: Ok(FutureEvent::FlushResponse(FlushResponse{}))
-                    };
-
-                write!(log, "sent: {:?}\n", event).unwrap();
-
-                sender.send(event).unwrap();
-            },
+            Ok(request) => process_request(&sender, &mut bd, &mut log, request),
             Err(_) => {
                 // the channel is closed, exit loop
                 break;
@@ -109,3 +50,113 @@ pub fn unix_file_backend_loop(sender: Sender<Event>, receiver: Receiver<BDReques
         }
     }
 }
+
+fn read(bd: &mut File, offset: u64, length: u64) -> Result<Vec<u8>, failure::Error> {
+    bd.seek(SeekFrom::Start(offset))?;
+    let mut data = vec![0;length as usize]; // maybe do not initialize it
+    bd.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn write(bd: &mut File, offset: u64, data: &[u8]) -> Result<u64, failure::Error> {
+    bd.seek(SeekFrom::Start(offset))?;
+    bd.write_all(data)?;
+    Ok(data.len() as u64)
+}
+
+/// Executes `request` and sends its responses, one per request of a batch.
+fn process_request(sender: &Sender<Event>, bd: &mut File, log: &mut File, request: BDRequest) {
+    let event = match request {
+        BDRequest::Read(r) => {
+
+            let result = read(bd, r.offset, r.length)
+                .map(|data| FutureEvent::ReadResponse(ReadResponse {
+                    data
+                }));
+
+            Event::ToFuture {
+                event_id: r.event_id,
+                task_id: r.task_id,
+                result
+            }
+        },
+        BDRequest::Write(w) => {
+
+            let result = write(bd, w.offset, &w.data)
+                .map(|len| FutureEvent::WriteResponse(WriteResponse{len}));
+
+            Event::ToFuture {
+                event_id: w.event_id,
+                task_id: w.task_id,
+                result
+            }
+        },
+        BDRequest::ReadV(r) => {
+
+            let result = r.segments.iter()
+                .map(|s| read(bd, s.offset, s.length))
+                .collect::<Result<Vec<Vec<u8>>, failure::Error>>()
+                .map(|data| FutureEvent::ReadVResponse(ReadVResponse {
+                    data
+                }));
+
+            Event::ToFuture {
+                event_id: r.event_id,
+                task_id: r.task_id,
+                result
+            }
+        },
+        BDRequest::WriteV(w) => {
+
+            // the segments are written in order until one fails
+            let mut result = Ok(0);
+            for s in &w.segments {
+                result = result.and_then(|len| write(bd, s.offset, &s.data).map(|l| len + l));
+            }
+            let result = result
+                .map(|len| FutureEvent::WriteResponse(WriteResponse{len}));
+
+            Event::ToFuture {
+                event_id: w.event_id,
+                task_id: w.task_id,
+                result
+            }
+        },
+        BDRequest::Flush(f) => {
+
+            // no-op
+
+            Event::ToFuture {
+                event_id: f.event_id,
+                task_id: f.task_id,
+                result: Ok(FutureEvent::FlushResponse(FlushResponse{}))
+            }
+        },
+        BDRequest::Size(s) => {
+
+            let result = match bd.metadata() {
+                Ok(metadata) => 
+                    Ok(FutureEvent::SizeResponse(SizeResponse{size: metadata.len()}))
+                ,
+                Err(e) => 
+                    Err(e.into())
+            };
+
+            Event::ToFuture {
+                event_id: s.event_id,
+                task_id: s.task_id,
+                result
+            }
+        },
+        BDRequest::Batch(b) => {
+            for request in b.requests {
+                process_request(sender, bd, log, request);
+            }
+            return;
+        },
+    };
+
+    write!(log, "sent: {:?}\n", event).unwrap();
+
+    sender.send(event).unwrap();
+}
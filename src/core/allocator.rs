@@ -0,0 +1,115 @@
+use super::*;
+
+impl Allocator {
+    /// Creates an `Allocator` for objects written during the transaction group `tgx`.
+    ///
+    /// It is not bounded: use `with_free_extents()` to stop at the end of the data area of a pool.
+    pub fn new(free_space_offset: u64, tgx: u64) -> Allocator {
+        Allocator::with_free_extents(free_space_offset, u64::max_value(), tgx, Vec::new())
+    }
+
+    /// Creates an `Allocator` which reuses `free_extents` before writing after `free_space_offset`,
+    /// and fails to allocate past `data_end`.
+    pub fn with_free_extents(free_space_offset: u64, data_end: u64, tgx: u64, free_extents: Vec<Extent>) -> Allocator {
+        let space = FreeSpace {
+            free_space_offset,
+            data_end,
+            free_extents,
+        };
+        Allocator::with_free_space(Rc::new(RefCell::new(space)), tgx)
+    }
+
+    /// Creates an `Allocator` taking its space from `space`, shared with other allocators.
+    pub fn with_free_space(space: Rc<RefCell<FreeSpace>>, tgx: u64) -> Allocator {
+        Allocator {
+            space,
+            tgx,
+            freed: Vec::new(),
+            written_bytes: 0,
+        }
+    }
+
+    /// Offset after which nothing has been written, by this allocator or by the ones sharing its space.
+    pub fn free_space_offset(&self) -> u64 {
+        self.space.borrow().free_space_offset
+    }
+
+    /// The transaction group recorded as the birth of the objects written by this allocator.
+    pub fn tgx(&self) -> u64 {
+        self.tgx
+    }
+
+    /// Moves to the transaction group `tgx`, when others have been committed since the allocator was created.
+    ///
+    /// The objects already written keep their birth, see `rebirth_btree()`.
+    pub fn set_tgx(&mut self, tgx: u64) {
+        self.tgx = tgx;
+    }
+
+    /// Number of bytes written by this allocator, i.e. during the transaction group.
+    pub fn written_bytes(&self) -> u64 {
+        self.written_bytes
+    }
+
+    /// Writes `mem` on the device and returns its offset, length and birth tgx.
+    pub fn write<'f>(&'f mut self, handle: Handle, mem: Vec<u8>) -> Box<Future<Item=(u64, u64, u64), Error=failure::Error> + 'f> { // box not really needed
+        Box::new(async_block! {
+            let offset = self.allocate(mem.len() as u64)?;
+            let len = await!(handle.write(mem, offset))?;
+            self.written_bytes += len;
+
+            Ok((offset, len, self.tgx))
+        })
+    }
+
+    /// Records that the object at `op` has been replaced and is not part of the tree being written anymore.
+    ///
+    /// Its space is not reused by this allocator: the pool decides when nothing can read it anymore.
+    pub fn free(&mut self, op: &ObjectPointer) {
+        if op.len == 0 {
+            return;
+        }
+
+        self.freed.push(DeadObject {
+            extent: Extent {
+                offset: op.offset,
+                len: op.len,
+            },
+            birth_tgx: op.birth_tgx,
+            death_tgx: self.tgx,
+        });
+    }
+
+    /// Takes the objects freed during the transaction group.
+    pub fn take_freed(&mut self) -> Vec<DeadObject> {
+        mem::replace(&mut self.freed, Vec::new())
+    }
+
+    /// Returns the offset of `len` bytes, in the first free extent large enough or at the end of the used space.
+    ///
+    /// Fails when the used space would grow past the end of the data area.
+    fn allocate(&mut self, len: u64) -> Result<u64, failure::Error> {
+        let mut space = self.space.borrow_mut();
+
+        if len > 0 {
+            if let Some(i) = space.free_extents.iter().position(|e| e.len >= len) {
+                let offset = space.free_extents[i].offset;
+                space.free_extents[i].offset += len;
+                space.free_extents[i].len -= len;
+                if space.free_extents[i].len == 0 {
+                    space.free_extents.remove(i);
+                }
+                return Ok(offset);
+            }
+        }
+
+        let offset = space.free_space_offset;
+        match offset.checked_add(len) {
+            Some(end) if end <= space.data_end => {
+                space.free_space_offset = end;
+                Ok(offset)
+            },
+            _ => Err(format_err!("Allocator::allocate: no space left on device (allocating {} bytes at offset {}, data area ends at {})", len, offset, space.data_end))
+        }
+    }
+}
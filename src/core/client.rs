@@ -0,0 +1,128 @@
+use std::thread;
+use std::ops::Range;
+use std::sync::mpsc::channel;
+
+use ::backend::mem::*;
+use ::backend::unix_file::*;
+
+use super::*;
+use super::util::*;
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            backend: BackendConfig::Mem {
+                size: 4096 * 1000
+            },
+            format: false,
+            tree: "default".into(),
+            open_options: OpenOptions::default(),
+        }
+    }
+}
+
+impl<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static> Client<K, V, B> {
+    /// Starts the backend thread and the reactor, then opens the pool and its tree `config.tree`.
+    pub fn open(config: ClientConfig) -> Result<Client<K, V, B>, failure::Error> {
+        let name = TreeName::new(&config.tree)?;
+
+        let (bd_sender, bd_receiver) = channel::<BDRequest>();
+        let (fs_sender, _fs_receiver) = channel::<FSResponse>();
+        let (react_sender, react_receiver) = channel::<Event>();
+
+        // a memory device starts empty
+        let format = match config.backend {
+            BackendConfig::Mem{..} => true,
+            BackendConfig::UnixFile => config.format,
+        };
+
+        // the backend thread stops by itself once the reactor is dropped
+        let read_only = config.open_options.read_only;
+        match config.backend {
+            BackendConfig::Mem{size} => {
+                thread::spawn(move || {
+                    mem_backend_loop(react_sender, bd_receiver, size);
+                });
+            }
+            BackendConfig::UnixFile => {
+                thread::spawn(move || {
+                    unix_file_backend_loop(react_sender, bd_receiver, read_only);
+                });
+            }
+        }
+
+        let mut core = Core::new(bd_sender, fs_sender, react_receiver);
+        let handle = core.handle();
+
+        if format {
+            core.run(format(handle.clone()))?;
+        }
+
+        let store = core.run(
+            Pool::open(handle, config.open_options)
+                .and_then(move |pool| KvStore::open(SharedPool::new(pool), name))
+        )?;
+
+        Ok(
+            Client {
+                core,
+                store,
+            }
+        )
+    }
+
+    pub fn len(&self) -> u64 {
+        self.store.len()
+    }
+
+    pub fn get(&mut self, key: K) -> Result<Option<V>, failure::Error> {
+        let f = self.store.get(key);
+        self.core.run(f)
+    }
+
+    pub fn contains(&mut self, key: K) -> Result<bool, failure::Error> {
+        let f = self.store.contains(key);
+        self.core.run(f)
+    }
+
+    /// Returns the key/value pairs whose key is in `range`, sorted by key.
+    pub fn range(&mut self, range: Range<K>) -> Result<Vec<(K, V)>, failure::Error> {
+        let f = self.store.range(range);
+        self.core.run(f)
+    }
+
+    // A modification which fails leaves the store as it was.
+
+    /// Inserts or replaces the value of `key` and returns the previous one.
+    pub fn put(&mut self, key: K, value: V) -> Result<Option<V>, failure::Error> {
+        self.core.run(self.store.put(key, value))
+    }
+
+    /// Registers the function used by `merge()`.
+    pub fn set_merge_operator(&mut self, merge: MergeFn<V>) {
+        self.store.set_merge_operator(merge);
+    }
+
+    /// Combines the value of `key` with `operand` using the registered merge operator.
+    pub fn merge(&mut self, key: K, operand: V) -> Result<(), failure::Error> {
+        self.core.run(self.store.merge(key, operand))
+    }
+
+    /// Removes `key` and returns its value.
+    pub fn delete(&mut self, key: K) -> Result<Option<V>, failure::Error> {
+        self.core.run(self.store.delete(key))
+    }
+
+    /// Makes all the modifications since the last commit durable.
+    ///
+    /// They can be committed again if it fails.
+    pub fn commit(&mut self) -> Result<(), failure::Error> {
+        self.core.run(self.store.commit())
+    }
+
+    /// Closes the pool. Modifications which have not been committed are lost.
+    pub fn close(self) -> Result<(), failure::Error> {
+        let mut core = self.core;
+        core.run(self.store.close())
+    }
+}
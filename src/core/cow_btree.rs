@@ -1,5 +1,6 @@
 use std::mem;
 use std::u64;
+use std::ops::Range;
 use std::fmt::Debug;
 use super::*;
 use super::util::*;
@@ -13,6 +14,68 @@ impl<K: Serializable + Ord + Copy, V: Serializable> NodeEntry<K, V> {
     }
 }
 
+// a plain insert replaces the value and returns the previous one
+impl<K: Serializable + Ord + Copy, V: Serializable + 'static> LeafUpdate<K, V> for NodeEntry<K, V> {
+    type Output = Option<V>;
+
+    fn key(&self) -> K {
+        self.key
+    }
+    fn update(self, value: &mut V) -> Option<V> {
+        Some(mem::replace(value, self.value))
+    }
+    fn insert(self) -> (Option<V>, Option<V>) {
+        (Some(self.value), None)
+    }
+}
+
+impl<K: Copy, V: 'static> LeafUpdate<K, V> for PutIfAbsent<K, V> {
+    type Output = bool;
+
+    fn key(&self) -> K {
+        self.key
+    }
+    fn update(self, _value: &mut V) -> bool {
+        false
+    }
+    fn insert(self) -> (Option<V>, bool) {
+        (Some(self.value), true)
+    }
+}
+
+impl<K: Copy, V: PartialEq + 'static> LeafUpdate<K, V> for CompareAndSwap<K, V> {
+    type Output = bool;
+
+    fn key(&self) -> K {
+        self.key
+    }
+    fn update(self, value: &mut V) -> bool {
+        if *value == self.expected {
+            *value = self.new;
+            true
+        } else {
+            false
+        }
+    }
+    fn insert(self) -> (Option<V>, bool) {
+        (None, false)
+    }
+}
+
+impl<K: Copy, V: 'static> LeafUpdate<K, V> for Merge<K, V> {
+    type Output = ();
+
+    fn key(&self) -> K {
+        self.key
+    }
+    fn update(self, value: &mut V) {
+        *value = (self.merge)(Some(&*value), self.operand);
+    }
+    fn insert(self) -> (Option<V>, ()) {
+        (Some((self.merge)(None, self.operand)), ())
+    }
+}
+
 impl<K: Serializable + Ord + Copy, V: Serializable, B: ConstUsize, T: ConstObjectType> Node<K, V, B, T> {
     pub fn new() -> Self {
         Self {
@@ -65,14 +128,15 @@ impl<K: Serializable + Ord + Copy, V: Serializable, B: ConstUsize, T: ConstObjec
         Box::new(handle.write(self.to_mem().to_vec(), offset))
     }
 
-    fn cow<'f>(&'f self, handle: Handle, fso: &'f mut u64) -> Box<Future<Item=ObjectPointer, Error=failure::Error> + 'f> { // box not really needed
+    fn cow_with_count<'f>(&'f self, handle: Handle, allocator: &'f mut Allocator, count: u64) -> Box<Future<Item=ObjectPointer, Error=failure::Error> + 'f> { // box not really needed
         Box::new(async_block! {
-            let offset = *fso;
-            let len = await!(self.async_write_at(handle.clone(), offset))?;
-            *fso += len;
+            // the allocator decides where the node goes
+            let (offset, len, birth_tgx) = await!(allocator.write(handle.clone(), self.to_mem().into_vec()))?;
             let op = ObjectPointer {
                 offset,
                 len,
+                birth_tgx,
+                count,
                 object_type: T::OTYPE
             };
             Ok(op)
@@ -81,6 +145,20 @@ impl<K: Serializable + Ord + Copy, V: Serializable, B: ConstUsize, T: ConstObjec
 
 }
 
+impl<K: Serializable + Ord + Copy, V: Serializable, B: ConstUsize> Node<K, V, B, Leaf> {
+    fn cow<'f>(&'f self, handle: Handle, allocator: &'f mut Allocator) -> Box<Future<Item=ObjectPointer, Error=failure::Error> + 'f> {
+        self.cow_with_count(handle, allocator, self.entries.len() as u64)
+    }
+}
+
+impl<K: Serializable + Ord + Copy, B: ConstUsize> Node<K, ObjectPointer, B, Internal> {
+    fn cow<'f>(&'f self, handle: Handle, allocator: &'f mut Allocator) -> Box<Future<Item=ObjectPointer, Error=failure::Error> + 'f> {
+        // the pointer to an internal node carries the size of the whole subtree
+        let count = self.entries.iter().map(|e| e.value.count).sum();
+        self.cow_with_count(handle, allocator, count)
+    }
+}
+
 impl<K: Serializable + Ord + Copy, V: Serializable, B: ConstUsize> NodeTrait<K, V> for Node<K, V, B, Leaf> {
     fn insert(&mut self, mut entry: NodeEntry<K, V>) -> Option<V> {
         // algo invariant: the entries should be sorted
@@ -120,20 +198,39 @@ impl<K: Serializable + Ord + Copy, V: Serializable, B: ConstUsize> NodeTrait<K,
 impl<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static> Node<K, V, B, Leaf> {
     /// insert or go in entry then split 
     #[async(boxed)]  // box not really needed
-    fn insert_in_leaf_node(self, handle: Handle, free_space_offset: u64, entry_to_insert: NodeEntry<K, V>)
-    -> Result<(NodeEntry<K, ObjectPointer>, u64, Option<V>), failure::Error> {
+    fn insert_in_leaf_node<U: LeafUpdate<K, V> + 'static>(self, handle: Handle, allocator: Allocator, update: U)
+    -> Result<(NodeEntry<K, ObjectPointer>, Allocator, U::Output), failure::Error> {
         
         // algo invariant: the entries should be sorted
         debug_assert!(is_sorted(self.entries.iter().map(|l|{l.key})));
 
-        let old_value = self.insert(entry_to_insert);
+        let key = update.key();
+        let output = self.apply_update(update);
 
         // COW node
-        let op = await!(self.cow(handle.clone(), &mut free_space_offset))?;
+        let op = await!(self.cow(handle.clone(), &mut allocator))?;
 
-        let entry = NodeEntry::<K, ObjectPointer>::new(self.entries[0].key, op);
+        // only the root can be empty, if the update inserted nothing
+        let first_key = self.entries.first().map_or(key, |e| e.key);
+        let entry = NodeEntry::<K, ObjectPointer>::new(first_key, op);
 
-        Ok((entry, free_space_offset, old_value))
+        Ok((entry, allocator, output))
+    }
+
+    /// Applies `update` to the value of its key, which may not exist yet.
+    fn apply_update<U: LeafUpdate<K, V>>(&mut self, update: U) -> U::Output {
+        let key = update.key();
+
+        match self.entries.binary_search_by_key(&key, |e| e.key) {
+            Ok(i) => update.update(&mut self.entries[i].value),
+            Err(i) => {
+                let (value, output) = update.insert();
+                if let Some(value) = value {
+                    self.entries.insert(i, NodeEntry::new(key, value));
+                }
+                output
+            }
+        }
     }
 }
 
@@ -141,13 +238,13 @@ impl<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: Const
 impl<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static> Node<K, V, B, Internal> {
     /// insert or go in entry then split 
     #[async(boxed)]
-    fn insert_in_internal_node
-    (handle: Handle, cur_node: Node<K, ObjectPointer, B, Internal>, free_space_offset: u64, entry_to_insert: NodeEntry<K, V>)
-    -> Result<(NodeEntry<K, ObjectPointer>, u64, Option<V>), failure::Error> {
+    fn insert_in_internal_node<U: LeafUpdate<K, V> + 'static>
+    (handle: Handle, cur_node: Node<K, ObjectPointer, B, Internal>, allocator: Allocator, update: U)
+    -> Result<(NodeEntry<K, ObjectPointer>, Allocator, U::Output), failure::Error> {
         // algo invariant: the entries should be sorted
         debug_assert!(is_sorted(cur_node.entries.iter().map(|l|{l.key})));
 
-        let res = cur_node.entries.binary_search_by_key(&entry_to_insert.key, |entry| entry.key);
+        let res = cur_node.entries.binary_search_by_key(&update.key(), |entry| entry.key);
         let index = match res {
             Ok(i) => i, // exact match
             Err(0) => 0, // key is smaller than first entry
@@ -160,13 +257,16 @@ impl<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: Const
         // read pointed object
         let any_object = await!(op.async_read_object::<K, V, B>(handle.clone()))?;
 
+        // it is replaced by its new version or split below
+        allocator.free(&op);
+
         match any_object {
             AnyObject::LeafNode(child_node) => {
                 // algo invariant
                 debug_assert!(child_node.entries.len() >= B::USIZE && child_node.entries.len() <= btree_degree(B::USIZE)); // b <= len <= 2b+1 with b=2 except root
                 let old_value = if child_node.entries.len() < btree_degree(B::USIZE) { // pro-active splitting if the node has the maximum size
-                    let (child_entry, new_free_space_offset, old_value) = await!(child_node.insert_in_leaf_node(handle.clone(), free_space_offset, entry_to_insert))?;
-                    free_space_offset = new_free_space_offset;
+                    let (child_entry, new_allocator, old_value) = await!(child_node.insert_in_leaf_node(handle.clone(), allocator, update))?;
+                    allocator = new_allocator;
 
                     // update current's node selected entry
                     cur_node.entries[index] = child_entry;
@@ -174,8 +274,8 @@ impl<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: Const
                     old_value
                 } else { // split
                     // split the node and insert in relevant child
-                    let (left_entry, right_entry, new_free_space_offset, old_value) = await!(leaf_split_and_insert(handle.clone(), *child_node, free_space_offset, entry_to_insert))?;
-                    free_space_offset = new_free_space_offset;
+                    let (left_entry, right_entry, new_allocator, old_value) = await!(leaf_split_and_insert(handle.clone(), *child_node, allocator, update))?;
+                    allocator = new_allocator;
 
                     // update current's node selected entry
                     cur_node.entries[index] = left_entry;
@@ -187,14 +287,14 @@ impl<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: Const
                 };
 
                 // COW that new node
-                let op = await!(cur_node.cow(handle.clone(), &mut free_space_offset))?;
+                let op = await!(cur_node.cow(handle.clone(), &mut allocator))?;
 
                 let entry = NodeEntry::<K, ObjectPointer>::new(cur_node.entries[0].key, op);
 
                 // return
                 Ok((
                     entry,
-                    free_space_offset,
+                    allocator,
                     old_value
                 ))
             }
@@ -203,8 +303,8 @@ impl<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: Const
                 debug_assert!(child_node.entries.len() >= B::USIZE && child_node.entries.len() <= btree_degree(B::USIZE)); // b <= len <= 2b+1 with b=2 except root
 
                 let old_value = if child_node.entries.len() < btree_degree(B::USIZE) { // pro-active splitting if the node has the maximum size
-                    let (child_entry, new_free_space_offset, old_value) = await!(Node::insert_in_internal_node(handle.clone(), *child_node, free_space_offset, entry_to_insert))?;
-                    free_space_offset = new_free_space_offset;
+                    let (child_entry, new_allocator, old_value) = await!(Node::<K, V, B, Internal>::insert_in_internal_node(handle.clone(), *child_node, allocator, update))?;
+                    allocator = new_allocator;
 
                     // update current's node selected entry
                     cur_node.entries[index] = child_entry;
@@ -212,8 +312,8 @@ impl<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: Const
                     old_value
                 } else { // split
                     // split the node and insert in relevant child
-                    let (left_entry, right_entry, new_free_space_offset, old_value) = await!(internal_split_and_insert(handle.clone(), *child_node, free_space_offset, entry_to_insert))?;
-                    free_space_offset = new_free_space_offset;
+                    let (left_entry, right_entry, new_allocator, old_value) = await!(internal_split_and_insert::<K, V, B, U>(handle.clone(), *child_node, allocator, update))?;
+                    allocator = new_allocator;
 
                     // update current's node selected entry
                     cur_node.entries[index] = left_entry;
@@ -225,14 +325,14 @@ impl<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: Const
                 };
 
                 // COW that new node
-                let op = await!(cur_node.cow(handle.clone(), &mut free_space_offset))?;
+                let op = await!(cur_node.cow(handle.clone(), &mut allocator))?;
 
                 let entry = NodeEntry::<K, ObjectPointer>::new(cur_node.entries[0].key, op);
 
                 // return
                 Ok((
                     entry,
-                    free_space_offset,
+                    allocator,
                     old_value
                 ))
             }
@@ -241,9 +341,9 @@ impl<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: Const
 }
 
 #[async(boxed)] // box not really needed
-fn leaf_split_and_insert<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static>
-(handle: Handle, node: Node<K, V, B, Leaf>, free_space_offset: u64, entry_to_insert: NodeEntry<K, V>)
--> Result<(NodeEntry<K, ObjectPointer>, NodeEntry<K, ObjectPointer>, u64, Option<V>), failure::Error> {
+fn leaf_split_and_insert<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static, U: LeafUpdate<K, V> + 'static>
+(handle: Handle, node: Node<K, V, B, Leaf>, allocator: Allocator, update: U)
+-> Result<(NodeEntry<K, ObjectPointer>, NodeEntry<K, ObjectPointer>, Allocator, U::Output), failure::Error> {
     // rename node to left_node ...
     let mut left_node = node;
     // ... and split off its right half to right_node
@@ -251,29 +351,29 @@ fn leaf_split_and_insert<K: Serializable + Ord + Copy + 'static, V: Serializable
     let mut right_node = Node::<K, V, B, Leaf>::with_entries(right_entries);
 
     // insert entry in either node
-    let (left_entry, right_entry, old_value) = if entry_to_insert.key < right_node.entries[0].key { // are we smaller than the first element of the right half
+    let (left_entry, right_entry, old_value) = if update.key() < right_node.entries[0].key { // are we smaller than the first element of the right half
         // TODO maybe inlining insert_in_internal_node code would be simpler
-        let (left_entry, new_free_space_offset, old_value) = await!(left_node.insert_in_leaf_node(handle.clone(), free_space_offset, entry_to_insert))?;
-        free_space_offset = new_free_space_offset;
-        let right_op = await!(right_node.cow(handle.clone(), &mut free_space_offset))?;
+        let (left_entry, new_allocator, old_value) = await!(left_node.insert_in_leaf_node(handle.clone(), allocator, update))?;
+        allocator = new_allocator;
+        let right_op = await!(right_node.cow(handle.clone(), &mut allocator))?;
         let right_entry = NodeEntry::<K, ObjectPointer>::new(right_node.entries[0].key, right_op);
         (left_entry, right_entry, old_value)
     } else {
         // TODO maybe inlining insert_in_internal_node code would be simpler
-        let (right_entry, new_free_space_offset, old_value) = await!(right_node.insert_in_leaf_node(handle.clone(), free_space_offset, entry_to_insert))?;
-        free_space_offset = new_free_space_offset;
-        let left_op = await!(left_node.cow(handle.clone(), &mut free_space_offset))?;
+        let (right_entry, new_allocator, old_value) = await!(right_node.insert_in_leaf_node(handle.clone(), allocator, update))?;
+        allocator = new_allocator;
+        let left_op = await!(left_node.cow(handle.clone(), &mut allocator))?;
         let left_entry = NodeEntry::<K, ObjectPointer>::new(left_node.entries[0].key, left_op);
         (left_entry, right_entry, old_value)
     };
 
-    Ok((left_entry, right_entry, free_space_offset, old_value))
+    Ok((left_entry, right_entry, allocator, old_value))
 }
 
 #[async(boxed)] // box not really needed
-fn internal_split_and_insert<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static>
-(handle: Handle, node: Node<K, ObjectPointer, B, Internal>, free_space_offset: u64, entry_to_insert: NodeEntry<K, V>)
--> Result<(NodeEntry<K, ObjectPointer>, NodeEntry<K, ObjectPointer>, u64, Option<V>), failure::Error> {
+fn internal_split_and_insert<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static, U: LeafUpdate<K, V> + 'static>
+(handle: Handle, node: Node<K, ObjectPointer, B, Internal>, allocator: Allocator, update: U)
+-> Result<(NodeEntry<K, ObjectPointer>, NodeEntry<K, ObjectPointer>, Allocator, U::Output), failure::Error> {
     // rename node to left_node ...
     let mut left_node = node;
     // ... and split off its right half to right_node
@@ -281,41 +381,54 @@ fn internal_split_and_insert<K: Serializable + Ord + Copy + 'static, V: Serializ
     let mut right_node = Node::<K, ObjectPointer, B, Internal>::with_entries(right_entries);
 
     // insert entry in either node
-    let (left_entry, right_entry, old_value) = if entry_to_insert.key < right_node.entries[0].key { // are we smaller than the first element of the right half
+    let (left_entry, right_entry, old_value) = if update.key() < right_node.entries[0].key { // are we smaller than the first element of the right half
         // TODO maybe inlining insert_in_internal_node code would be simpler
-        let (left_entry, new_free_space_offset, old_value) = await!(Node::insert_in_internal_node(handle.clone(), left_node, free_space_offset, entry_to_insert))?;
-        free_space_offset = new_free_space_offset;
-        let right_op = await!(right_node.cow(handle.clone(), &mut free_space_offset))?;
+        let (left_entry, new_allocator, old_value) = await!(Node::<K, V, B, Internal>::insert_in_internal_node(handle.clone(), left_node, allocator, update))?;
+        allocator = new_allocator;
+        let right_op = await!(right_node.cow(handle.clone(), &mut allocator))?;
         let right_entry = NodeEntry::<K, ObjectPointer>::new(right_node.entries[0].key, right_op);
         (left_entry, right_entry, old_value)
     } else {
         // TODO maybe inlining insert_in_internal_node code would be simpler
-        let (right_entry, new_free_space_offset, old_value) = await!(Node::insert_in_internal_node(handle.clone(), right_node, free_space_offset, entry_to_insert))?;
-        free_space_offset = new_free_space_offset;
-        let left_op = await!(left_node.cow(handle.clone(), &mut free_space_offset))?;
+        let (right_entry, new_allocator, old_value) = await!(Node::<K, V, B, Internal>::insert_in_internal_node(handle.clone(), right_node, allocator, update))?;
+        allocator = new_allocator;
+        let left_op = await!(left_node.cow(handle.clone(), &mut allocator))?;
         let left_entry = NodeEntry::<K, ObjectPointer>::new(left_node.entries[0].key, left_op);
         (left_entry, right_entry, old_value)
     };
 
-    Ok((left_entry, right_entry, free_space_offset, old_value))
+    Ok((left_entry, right_entry, allocator, old_value))
 }
 
 #[async(boxed)] // box not really needed
 pub fn insert_in_btree<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static>
-(handle: Handle, op: ObjectPointer, free_space_offset: u64, entry_to_insert: NodeEntry<K, V>)
--> Result<(ObjectPointer, u64, Option<V>), failure::Error> {
+(handle: Handle, op: ObjectPointer, allocator: Allocator, entry_to_insert: NodeEntry<K, V>)
+-> Result<(ObjectPointer, Allocator, Option<V>), failure::Error> {
+    await!(update_in_btree::<K, V, B, NodeEntry<K, V>>(handle, op, allocator, entry_to_insert))
+}
+
+/// Applies `update` to the value of its key in the leaf reached by the insert descent.
+///
+/// The path to the leaf is rewritten even if the update leaves the value untouched.
+#[async(boxed)] // box not really needed
+pub fn update_in_btree<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static, U: LeafUpdate<K, V> + 'static>
+(handle: Handle, op: ObjectPointer, allocator: Allocator, update: U)
+-> Result<(ObjectPointer, Allocator, U::Output), failure::Error> {
     // read pointed object
     let any_object = await!(op.async_read_object::<K, V, B>(handle.clone()))?;
 
-    let (op, new_free_space_offset, old_value) = match any_object {
+    // the root is always rewritten
+    allocator.free(&op);
+
+    let (op, new_allocator, old_value) = match any_object {
         AnyObject::LeafNode(node) => {
             // algo invariant
             debug_assert!(node.entries.len() <= btree_degree(B::USIZE)); // b <= len <= 2b+1 with b=2 except root
 
             if node.entries.len() >= btree_degree(B::USIZE) { // pro-active splitting if the node has the maximum size
                 // split the node and insert in relevant child
-                let (left_entry, right_entry, new_free_space_offset, old_value) = await!(leaf_split_and_insert(handle.clone(), *node, free_space_offset, entry_to_insert))?;
-                free_space_offset = new_free_space_offset;
+                let (left_entry, right_entry, new_allocator, old_value) = await!(leaf_split_and_insert(handle.clone(), *node, allocator, update))?;
+                allocator = new_allocator;
 
                 // create new root
                 let mut new_root = Node::<K, ObjectPointer, B, Internal>::new();
@@ -324,11 +437,11 @@ pub fn insert_in_btree<K: Serializable + Ord + Copy + 'static, V: Serializable +
                 // no need to sort
 
                 // COW new root
-                let new_op = await!(new_root.cow(handle.clone(), &mut free_space_offset))?;
-                (new_op, free_space_offset, old_value)
+                let new_op = await!(new_root.cow(handle.clone(), &mut allocator))?;
+                (new_op, allocator, old_value)
             } else {
-                let (entry, free_space_offset, old_value) = await!(node.insert_in_leaf_node(handle, free_space_offset, entry_to_insert))?;
-                (entry.value, free_space_offset, old_value)
+                let (entry, allocator, old_value) = await!(node.insert_in_leaf_node(handle, allocator, update))?;
+                (entry.value, allocator, old_value)
             }
         }
         AnyObject::InternalNode(node) => {
@@ -337,8 +450,8 @@ pub fn insert_in_btree<K: Serializable + Ord + Copy + 'static, V: Serializable +
 
             if node.entries.len() >= btree_degree(B::USIZE) { // pro-active splitting if the node has the maximum size
                 // split the node and insert in relevant child
-                let (left_entry, right_entry, new_free_space_offset, old_value) = await!(internal_split_and_insert(handle.clone(), *node, free_space_offset, entry_to_insert))?;
-                free_space_offset = new_free_space_offset;
+                let (left_entry, right_entry, new_allocator, old_value) = await!(internal_split_and_insert::<K, V, B, U>(handle.clone(), *node, allocator, update))?;
+                allocator = new_allocator;
 
                 // create new root
                 let mut new_root = Node::<K, ObjectPointer, B, Internal>::new();
@@ -347,24 +460,24 @@ pub fn insert_in_btree<K: Serializable + Ord + Copy + 'static, V: Serializable +
                 // no need to sort
 
                 // COW new root
-                let new_op = await!(new_root.cow(handle.clone(), &mut free_space_offset))?;
-                (new_op, free_space_offset, old_value)
+                let new_op = await!(new_root.cow(handle.clone(), &mut allocator))?;
+                (new_op, allocator, old_value)
             } else {
-                let (entry, free_space_offset, old_value) = await!(Node::insert_in_internal_node(handle, *node, free_space_offset, entry_to_insert))?;
-                (entry.value, free_space_offset, old_value)
+                let (entry, allocator, old_value) = await!(Node::<K, V, B, Internal>::insert_in_internal_node(handle, *node, allocator, update))?;
+                (entry.value, allocator, old_value)
             }
         }
     };
-    Ok((op, new_free_space_offset, old_value))
+    Ok((op, new_allocator, old_value))
 }
 
 #[async(boxed)] // box not really needed
-pub fn get<K: Serializable + Ord + Copy + 'static, V: Serializable + Copy, B: ConstUsize>(handle: Handle, op: ObjectPointer, key: K) -> Result<Option<V>, failure::Error> {
+pub fn get<K: Serializable + Ord + Copy + 'static, V: Serializable, B: ConstUsize>(handle: Handle, op: ObjectPointer, key: K) -> Result<Option<V>, failure::Error> {
     // read root node
     let any_object = await!(op.async_read_object::<K, V, B>(handle.clone()))?;
 
     match any_object {
-        AnyObject::LeafNode(node) => {
+        AnyObject::LeafNode(mut node) => {
             // algo invariant
             debug_assert!(node.entries.len() <= btree_degree(B::USIZE)); // b <= len <= 2b+1 with b=2 except root
 
@@ -373,7 +486,7 @@ pub fn get<K: Serializable + Ord + Copy + 'static, V: Serializable + Copy, B: Co
 
             let res = node.entries.binary_search_by_key(&key, |entry| entry.key);
             if let Ok(i) = res {
-                return Ok(Some(node.entries[i].value));
+                return Ok(Some(node.entries.swap_remove(i).value));
             } else {
                 return Ok(None);
             }
@@ -388,7 +501,7 @@ pub fn get<K: Serializable + Ord + Copy + 'static, V: Serializable + Copy, B: Co
             let res = node.entries.binary_search_by_key(&key, |entry| entry.key);
             let index = match res {
                 Ok(i) => i, // exact match
-                Err(0) => unreachable!("cow_btree: key should not be smaller than current's node smallest entry"),
+                Err(0) => return Ok(None), // key is smaller than all the keys of the btree
                 Err(i) => i - 1, // match first bigger key
             };
 
@@ -397,10 +510,234 @@ pub fn get<K: Serializable + Ord + Copy + 'static, V: Serializable + Copy, B: Co
     }
 }
 
+/// Inserts `value` only if `key` does not exist. Returns whether it was inserted.
+#[async]
+pub fn put_if_absent<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static>
+(handle: Handle, op: ObjectPointer, allocator: Allocator, key: K, value: V)
+-> Result<(ObjectPointer, Allocator, bool), failure::Error> {
+    // nothing is rewritten if the key exists
+    if await!(get::<K, V, B>(handle.clone(), op.clone(), key))?.is_some() {
+        return Ok((op, allocator, false));
+    }
+
+    await!(update_in_btree::<K, V, B, _>(handle, op, allocator, PutIfAbsent { key, value }))
+}
+
+/// Replaces the value of `key` by `new` only if it is `expected`. Returns whether it was replaced.
+#[async]
+pub fn compare_and_swap<K: Serializable + Ord + Copy + 'static, V: Serializable + PartialEq + 'static, B: ConstUsize + 'static>
+(handle: Handle, op: ObjectPointer, allocator: Allocator, key: K, expected: V, new: V)
+-> Result<(ObjectPointer, Allocator, bool), failure::Error> {
+    // nothing is rewritten if the swap fails
+    if await!(get::<K, V, B>(handle.clone(), op.clone(), key))?.as_ref() != Some(&expected) {
+        return Ok((op, allocator, false));
+    }
+
+    await!(update_in_btree::<K, V, B, _>(handle, op, allocator, CompareAndSwap { key, expected, new }))
+}
+
+/// Removes `key` only if its value is `expected`. Returns whether it was removed.
+#[async]
+pub fn delete_if<K: Serializable + Ord + Copy + 'static, V: Serializable + PartialEq + 'static, B: ConstUsize + 'static>
+(handle: Handle, op: ObjectPointer, allocator: Allocator, key: K, expected: V)
+-> Result<(ObjectPointer, Allocator, bool), failure::Error> {
+    let (op, allocator, removed) = await!(remove_if::<K, V, B, _>(handle, op, allocator, key, move |value: &V| *value == expected))?;
+    Ok((op, allocator, removed.is_some()))
+}
+
+/// Replaces the value of `key` by `merge(value, operand)`, or inserts `merge(None, operand)`.
+///
+/// Unlike a `get()` followed by an `insert_in_btree()`, the tree is only traversed once.
+#[async]
+pub fn merge_in_btree<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static>
+(handle: Handle, op: ObjectPointer, allocator: Allocator, key: K, operand: V, merge: MergeFn<V>)
+-> Result<(ObjectPointer, Allocator), failure::Error> {
+    let (op, allocator, _) = await!(update_in_btree::<K, V, B, _>(handle, op, allocator, Merge { key, operand, merge }))?;
+    Ok((op, allocator))
+}
+
+/// Rewrites the nodes of the btree at `op` born in `tgx` so that they are born in the transaction group of `allocator`.
+///
+/// They are the ones written since the last commit of the tree, the other ones are left as they are.
+#[async(boxed)] // box not really needed
+pub fn rebirth_btree<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static>
+(handle: Handle, op: ObjectPointer, allocator: Allocator, tgx: u64)
+-> Result<(ObjectPointer, Allocator), failure::Error> {
+    // a node written since the last commit only has such nodes above it
+    if op.birth_tgx != tgx {
+        return Ok((op, allocator));
+    }
+
+    let any_object = await!(op.async_read_object::<K, V, B>(handle.clone()))?;
+    allocator.free(&op);
+
+    let op = match any_object {
+        AnyObject::LeafNode(node) => await!(node.cow(handle.clone(), &mut allocator))?,
+        AnyObject::InternalNode(mut node) => {
+            for i in 0..node.entries.len() {
+                let child = node.entries[i].value.clone();
+                let (child, new_allocator) = await!(rebirth_btree::<K, V, B>(handle.clone(), child, allocator, tgx))?;
+                node.entries[i].value = child;
+                allocator = new_allocator;
+            }
+            await!(node.cow(handle.clone(), &mut allocator))?
+        }
+    };
+
+    Ok((op, allocator))
+}
+
+/// Writes an empty btree and returns its root.
+#[async]
+pub fn create_btree<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static>(handle: Handle, allocator: Allocator) -> Result<(ObjectPointer, Allocator), failure::Error> {
+    let node = Node::<K, V, B, Leaf>::new();
+    let op = await!(node.cow(handle.clone(), &mut allocator))?;
+    Ok((op, allocator))
+}
+
+/// Returns the value of `key` and the leaf in which it is, or would be inserted.
+#[async(boxed)] // box not really needed
+pub fn get_with_leaf<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static>(handle: Handle, op: ObjectPointer, key: K) -> Result<(Option<V>, ObjectPointer), failure::Error> {
+    let any_object = await!(op.async_read_object::<K, V, B>(handle.clone()))?;
+
+    match any_object {
+        AnyObject::LeafNode(mut node) => {
+            // algo invariant: the entries should be sorted
+            debug_assert!(is_sorted(node.entries.iter().map(|l|{l.key})));
+
+            let value = match node.entries.binary_search_by_key(&key, |entry| entry.key) {
+                Ok(i) => Some(node.entries.swap_remove(i).value),
+                Err(_) => None
+            };
+            Ok((value, op))
+        }
+        AnyObject::InternalNode(node) => {
+            // algo invariant: the entries should be sorted
+            debug_assert!(is_sorted(node.entries.iter().map(|l|{l.key})));
+
+            // same choice as insert_in_internal_node()
+            let res = node.entries.binary_search_by_key(&key, |entry| entry.key);
+            let index = match res {
+                Ok(i) => i, // exact match
+                Err(0) => 0, // key is smaller than first entry
+                Err(i) => i - 1, // match first bigger entry
+            };
+
+            await!(get_with_leaf::<K, V, B>(handle.clone(), node.entries[index].value.clone(), key))
+        }
+    }
+}
+
+/// Returns the number of keys in the btree.
+pub fn count(op: &ObjectPointer) -> u64 {
+    op.count
+}
+
+/// Returns the number of keys strictly smaller than `key`.
+#[async(boxed)] // box not really needed
+pub fn rank<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static>(handle: Handle, op: ObjectPointer, key: K) -> Result<u64, failure::Error> {
+    let any_object = await!(op.async_read_object::<K, V, B>(handle.clone()))?;
+
+    match any_object {
+        AnyObject::LeafNode(node) => {
+            // algo invariant: the entries should be sorted
+            debug_assert!(is_sorted(node.entries.iter().map(|l|{l.key})));
+
+            match node.entries.binary_search_by_key(&key, |entry| entry.key) {
+                Ok(i) | Err(i) => Ok(i as u64)
+            }
+        }
+        AnyObject::InternalNode(node) => {
+            // algo invariant: the entries should be sorted
+            debug_assert!(is_sorted(node.entries.iter().map(|l|{l.key})));
+
+            let res = node.entries.binary_search_by_key(&key, |entry| entry.key);
+            let index = match res {
+                Ok(i) => i, // exact match
+                Err(0) => return Ok(0), // key is smaller than all the keys of the btree
+                Err(i) => i - 1, // match first bigger key
+            };
+
+            // the subtrees on the left only hold smaller keys
+            let smaller: u64 = node.entries[..index].iter().map(|e| e.value.count).sum();
+            let child_rank = await!(rank::<K, V, B>(handle.clone(), node.entries[index].value.clone(), key))?;
+
+            Ok(smaller + child_rank)
+        }
+    }
+}
+
+/// Returns the `n`th entry in key order, starting from 0.
 #[async(boxed)] // box not really needed
-fn remove_in_leaf<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static>
-(handle: Handle, node: Node<K, V, B, Leaf>, free_space_offset: u64, key: K)
--> Result<(ObjectPointer, u64, Option<V>), failure::Error> {
+pub fn nth<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static>(handle: Handle, op: ObjectPointer, n: u64) -> Result<Option<NodeEntry<K, V>>, failure::Error> {
+    if n >= op.count {
+        return Ok(None);
+    }
+
+    let any_object = await!(op.async_read_object::<K, V, B>(handle.clone()))?;
+
+    match any_object {
+        AnyObject::LeafNode(mut node) => {
+            Ok(Some(node.entries.swap_remove(n as usize)))
+        }
+        AnyObject::InternalNode(node) => {
+            // skip the subtrees on the left
+            let mut n = n;
+            for entry in node.entries {
+                if n < entry.value.count {
+                    return await!(nth::<K, V, B>(handle.clone(), entry.value, n));
+                }
+                n -= entry.value.count;
+            }
+
+            unreachable!("cow_btree: the count of a node should be the sum of the counts of its children")
+        }
+    }
+}
+
+/// Returns the number of keys in `range`.
+#[async(boxed)] // box not really needed
+pub fn count_range<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static>(handle: Handle, op: ObjectPointer, range: Range<K>) -> Result<u64, failure::Error> {
+    let start = await!(rank::<K, V, B>(handle.clone(), op.clone(), range.start))?;
+    let end = await!(rank::<K, V, B>(handle.clone(), op, range.end))?;
+
+    Ok(end.saturating_sub(start))
+}
+
+/// Returns the entries whose key is in `range`, sorted.
+#[async(boxed)] // box not really needed
+pub fn read_range<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static>(handle: Handle, op: ObjectPointer, range: Range<K>) -> Result<Vec<NodeEntry<K, V>>, failure::Error> {
+    let any_object = await!(op.async_read_object::<K, V, B>(handle.clone()))?;
+
+    match any_object {
+        AnyObject::LeafNode(node) => {
+            Ok(node.entries.into_iter().filter(|e| range.start <= e.key && e.key < range.end).collect())
+        }
+        AnyObject::InternalNode(node) => {
+            let mut v = vec![];
+
+            for i in 0..node.entries.len() {
+                // the child i holds keys from entries[i].key up to entries[i + 1].key excluded
+                if node.entries[i].key >= range.end {
+                    break;
+                }
+                if i + 1 < node.entries.len() && node.entries[i + 1].key <= range.start {
+                    continue;
+                }
+
+                let mut res = await!(read_range::<K, V, B>(handle.clone(), node.entries[i].value.clone(), range.clone()))?;
+                v.append(&mut res);
+            }
+
+            Ok(v)
+        }
+    }
+}
+
+#[async(boxed)] // box not really needed
+fn remove_in_leaf<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static, P: FnOnce(&V) -> bool + 'static>
+(handle: Handle, node: Node<K, V, B, Leaf>, allocator: Allocator, key: K, should_remove: P)
+-> Result<(ObjectPointer, Allocator, Option<V>), failure::Error> {
     /*
         Here, we have the following garanties:
         - All nodes have a least one neighbor to fully or partially merge with.
@@ -414,22 +751,21 @@ fn remove_in_leaf<K: Serializable + Ord + Copy + 'static, V: Serializable + 'sta
 
     let res = node.entries.binary_search_by_key(&key, |entry| entry.key);
 
-    let removed = if let Ok(i) = res {
-        Some(node.entries.remove(i).value)
-    } else {
-        None
+    let removed = match res {
+        Ok(i) if should_remove(&node.entries[i].value) => Some(node.entries.remove(i).value),
+        _ => None
     };
     
     // COW node
-    let op = await!(node.cow(handle.clone(), &mut free_space_offset))?;
+    let op = await!(node.cow(handle.clone(), &mut allocator))?;
 
-    Ok((op, free_space_offset, removed))
+    Ok((op, allocator, removed))
 }
 
 #[async(boxed)]
-fn remove_in_internal<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static>
-(handle: Handle, node: Node<K, ObjectPointer, B, Internal>, mut free_space_offset: u64, key: K)
--> Result<(ObjectPointer, u64, Option<V>), failure::Error> {
+fn remove_in_internal<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static, P: FnOnce(&V) -> bool + 'static>
+(handle: Handle, node: Node<K, ObjectPointer, B, Internal>, mut allocator: Allocator, key: K, should_remove: P)
+-> Result<(ObjectPointer, Allocator, Option<V>), failure::Error> {
     /*
         Here, we have the following garanties:
         - All nodes have a least one neighbor to fully or partially merge with.
@@ -451,6 +787,9 @@ fn remove_in_internal<K: Serializable + Ord + Copy + 'static, V: Serializable +
     // read the child on the way to the key to delete
     let child = await!(node.entries[index].value.async_read_object(handle.clone()))?;
 
+    // it is either rewritten or merged into its neighbor
+    allocator.free(&node.entries[index].value);
+
     match child {
         AnyObject::LeafNode(mut child) => {
             // TODO: add asserts
@@ -468,6 +807,7 @@ fn remove_in_internal<K: Serializable + Ord + Copy + 'static, V: Serializable +
                     AnyObject::LeafNode(n) => *n,
                     AnyObject::InternalNode(_) => unreachable!("cow_btree: all sibling should be of the same kind")
                 };
+                allocator.free(&node.entries[neighbor_index].value);
 
                 // TODO: add assert
                 let removed_value = if child.entries.len() + neighbor.entries.len() <= btree_degree(B::USIZE) { // if there is enough space to do a full merge
@@ -487,8 +827,8 @@ fn remove_in_internal<K: Serializable + Ord + Copy + 'static, V: Serializable +
                     debug_assert!(is_sorted(dst_node.entries.iter().map(|l|{l.key})));
 
                     // recursion
-                    let (op, new_free_space_offset, removed_value) = await!(remove_in_leaf(handle.clone(), dst_node, free_space_offset, key))?;
-                    free_space_offset = new_free_space_offset;
+                    let (op, new_allocator, removed_value) = await!(remove_in_leaf(handle.clone(), dst_node, allocator, key, should_remove))?;
+                    allocator = new_allocator;
 
                     // update child entry to point to the new node
                     node.entries[dst_index].value = op;
@@ -542,14 +882,14 @@ fn remove_in_internal<K: Serializable + Ord + Copy + 'static, V: Serializable +
                     debug_assert!(is_sorted(child.entries.iter().map(|l|{l.key})));
 
                     // recursion
-                    let (child_op, new_free_space_offset, removed_value) = await!(remove_in_leaf(handle.clone(), *child, free_space_offset, key))?;
-                    free_space_offset = new_free_space_offset;
+                    let (child_op, new_allocator, removed_value) = await!(remove_in_leaf(handle.clone(), *child, allocator, key, should_remove))?;
+                    allocator = new_allocator;
 
                     // update child entry to point to the new node
                     node.entries[index].value = child_op;
 
                     // COW the neighbor
-                    let neighbor_op = await!(neighbor.cow(handle.clone(), &mut free_space_offset))?;
+                    let neighbor_op = await!(neighbor.cow(handle.clone(), &mut allocator))?;
                     node.entries[neighbor_index].value = neighbor_op;
 
                     removed_value
@@ -557,24 +897,24 @@ fn remove_in_internal<K: Serializable + Ord + Copy + 'static, V: Serializable +
                 
                 if node.entries.len() > 1 { // common case
                     // COW the node
-                    let op = await!(node.cow(handle.clone(), &mut free_space_offset))?;
-                    return Ok((op, free_space_offset, removed_value));
+                    let op = await!(node.cow(handle.clone(), &mut allocator))?;
+                    return Ok((op, allocator, removed_value));
                 } else { // we are the root node and we can pop the head
                     fuzz_marker!("cow_btree_remove_pop_head_leaf");
-                    return Ok((node.entries.remove(0).value, free_space_offset, removed_value));
+                    return Ok((node.entries.remove(0).value, allocator, removed_value));
                 }
             } else { // there is enough entries in the node: no need to merge
                 // TODO: find a way to factorize this code with the merge code
                 // recursion
-                let (op, new_free_space_offset, removed_value) = await!(remove_in_leaf(handle.clone(), *child, free_space_offset, key))?;
-                free_space_offset = new_free_space_offset;
+                let (op, new_allocator, removed_value) = await!(remove_in_leaf(handle.clone(), *child, allocator, key, should_remove))?;
+                allocator = new_allocator;
 
                 // update child entry to point to the new node
                 node.entries[index].value = op;
 
                 // COW the node
-                let op = await!(node.cow(handle.clone(), &mut free_space_offset))?;
-                return Ok((op, free_space_offset, removed_value));
+                let op = await!(node.cow(handle.clone(), &mut allocator))?;
+                return Ok((op, allocator, removed_value));
             }
         }
         AnyObject::InternalNode(mut child) => {
@@ -593,6 +933,7 @@ fn remove_in_internal<K: Serializable + Ord + Copy + 'static, V: Serializable +
                     AnyObject::InternalNode(n) => *n,
                     AnyObject::LeafNode(_) => unreachable!("cow_btree: all sibling should be of the same kind")
                 };
+                allocator.free(&node.entries[neighbor_index].value);
 
                 // TODO: add assert
                 let removed_value = if child.entries.len() + neighbor.entries.len() <= btree_degree(B::USIZE) { // if there is enough space to do a full merge
@@ -611,8 +952,8 @@ fn remove_in_internal<K: Serializable + Ord + Copy + 'static, V: Serializable +
                     debug_assert!(is_sorted(dst_node.entries.iter().map(|l|{l.key})));
 
                     // recursion
-                    let (op, new_free_space_offset, removed_value) = await!(remove_in_internal(handle.clone(), dst_node, free_space_offset, key))?;
-                    free_space_offset = new_free_space_offset;
+                    let (op, new_allocator, removed_value) = await!(remove_in_internal(handle.clone(), dst_node, allocator, key, should_remove))?;
+                    allocator = new_allocator;
 
                     // update child entry to point to the new node
                     node.entries[dst_index].value = op;
@@ -666,14 +1007,14 @@ fn remove_in_internal<K: Serializable + Ord + Copy + 'static, V: Serializable +
                     debug_assert!(is_sorted(child.entries.iter().map(|l|{l.key})));
 
                     // recursion
-                    let (child_op, new_free_space_offset, removed_value) = await!(remove_in_internal(handle.clone(), *child, free_space_offset, key))?;
-                    free_space_offset = new_free_space_offset;
+                    let (child_op, new_allocator, removed_value) = await!(remove_in_internal(handle.clone(), *child, allocator, key, should_remove))?;
+                    allocator = new_allocator;
 
                     // update child entry to point to the new node
                     node.entries[index].value = child_op;
 
                     // COW the neighbor
-                    let neighbor_op = await!(neighbor.cow(handle.clone(), &mut free_space_offset))?;
+                    let neighbor_op = await!(neighbor.cow(handle.clone(), &mut allocator))?;
                     node.entries[neighbor_index].value = neighbor_op;
 
                     removed_value
@@ -681,24 +1022,24 @@ fn remove_in_internal<K: Serializable + Ord + Copy + 'static, V: Serializable +
                 
                 if node.entries.len() > 1 { // common case
                     // COW the node
-                    let op = await!(node.cow(handle.clone(), &mut free_space_offset))?;
-                    return Ok((op, free_space_offset, removed_value));
+                    let op = await!(node.cow(handle.clone(), &mut allocator))?;
+                    return Ok((op, allocator, removed_value));
                 } else { // we are the root node and we can pop the head
                     fuzz_marker!("cow_btree_remove_pop_head_internal");
-                    return Ok((node.entries.remove(0).value, free_space_offset, removed_value));
+                    return Ok((node.entries.remove(0).value, allocator, removed_value));
                 }
             } else { // there is enough entries in the node: no need to merge
                 // TODO: find a way to factorize this code with the merge code
                 // recursion
-                let (op, new_free_space_offset, removed_value) = await!(remove_in_internal(handle.clone(), *child, free_space_offset, key))?;
-                free_space_offset = new_free_space_offset;
+                let (op, new_allocator, removed_value) = await!(remove_in_internal(handle.clone(), *child, allocator, key, should_remove))?;
+                allocator = new_allocator;
 
                 // update child entry to point to the new node
                 node.entries[index].value = op;
 
                 // COW the node
-                let op = await!(node.cow(handle.clone(), &mut free_space_offset))?;
-                return Ok((op, free_space_offset, removed_value));
+                let op = await!(node.cow(handle.clone(), &mut allocator))?;
+                return Ok((op, allocator, removed_value));
             }
         }
     }
@@ -709,9 +1050,19 @@ fn remove_in_internal<K: Serializable + Ord + Copy + 'static, V: Serializable +
 
 // TODO: write a version that does not do any modifications if the entry to remove doesn't exist
 #[async(boxed)] // box not really needed
-pub fn remove<K: Serializable + Ord + Copy + 'static, V: Serializable, B: ConstUsize>
-(handle: Handle, op: ObjectPointer, free_space_offset: u64, key: K)
--> Result<(ObjectPointer, u64, Option<V>), failure::Error> {
+pub fn remove<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static>
+(handle: Handle, op: ObjectPointer, allocator: Allocator, key: K)
+-> Result<(ObjectPointer, Allocator, Option<V>), failure::Error> {
+    await!(remove_if::<K, V, B, _>(handle, op, allocator, key, |_: &V| true))
+}
+
+/// Removes `key` only if `should_remove` returns `true` for its value.
+///
+/// The tree is first read to decide, so that nothing is rewritten if nothing is removed.
+#[async(boxed)] // box not really needed
+pub fn remove_if<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static, P: FnOnce(&V) -> bool + 'static>
+(handle: Handle, op: ObjectPointer, allocator: Allocator, key: K, should_remove: P)
+-> Result<(ObjectPointer, Allocator, Option<V>), failure::Error> {
     /*
         Here, we have the following garanties:
         - All nodes have a least one neighbor to fully or partially merge with.
@@ -720,25 +1071,36 @@ pub fn remove<K: Serializable + Ord + Copy + 'static, V: Serializable, B: ConstU
         - All non-root nodes have between B and 2B+1 entries  
     */
 
+    let value = await!(get::<K, V, B>(handle.clone(), op.clone(), key))?;
+    if !value.as_ref().map_or(false, should_remove) {
+        return Ok((op, allocator, None));
+    }
+
     // read pointed object
     let any_object = await!(op.async_read_object::<K, V, B>(handle.clone()))?;
 
-    let (op, new_free_space_offset, removed_value) = match any_object {
+    let (op, new_allocator, removed_value) = match any_object {
         AnyObject::LeafNode(node) => {
             match node.entries.len() > 0 && key < node.entries[0].key { // if the key is smaller than the smallest key
-                true  => (op, free_space_offset, None), // the key cannot be in the btree
-                false => await!(remove_in_leaf(handle.clone(), *node, free_space_offset, key))?
+                true  => (op, allocator, None), // the key cannot be in the btree
+                false => {
+                    allocator.free(&op); // the root is rewritten
+                    await!(remove_in_leaf(handle.clone(), *node, allocator, key, |_: &V| true))?
+                }
             }
         }
         AnyObject::InternalNode(node) => {
             match node.entries.len() > 0 && key < node.entries[0].key { // if the key is smaller than the smallest key
-                true  => (op, free_space_offset, None), // the key cannot be in the btree
-                false => await!(remove_in_internal(handle.clone(), *node, free_space_offset, key))?
+                true  => (op, allocator, None), // the key cannot be in the btree
+                false => {
+                    allocator.free(&op); // the root is rewritten
+                    await!(remove_in_internal(handle.clone(), *node, allocator, key, |_: &V| true))?
+                }
             }
         }
     };
 
-    Ok((op, new_free_space_offset, removed_value))
+    Ok((op, new_allocator, removed_value))
 }
 
 #[async(boxed)]
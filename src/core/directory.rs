@@ -0,0 +1,231 @@
+use std::str;
+use super::*;
+use super::cow_btree::*;
+
+// The directory is a btree mapping the name of each tree hosted in the pool to its root and descriptor.
+// Its root is recorded in the uberblock, so all the trees are committed together.
+
+impl TreeName {
+    pub fn new(name: &str) -> Result<TreeName, failure::Error> {
+        if name.is_empty() || name.len() > TREE_NAME_LEN {
+            return Err(format_err!("Tree names should be between 1 and {} bytes long, found: {:?}", TREE_NAME_LEN, name));
+        }
+        if name.as_bytes().contains(&0) {
+            return Err(format_err!("Tree names should not contain NUL bytes, found: {:?}", name));
+        }
+
+        let mut bytes = [0; TREE_NAME_LEN];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(TreeName(bytes))
+    }
+
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|&b| b == 0).unwrap_or(TREE_NAME_LEN);
+        str::from_utf8(&self.0[..len]).unwrap() // checked when created or read
+    }
+}
+
+impl fmt::Debug for TreeName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TreeName({:?})", self.as_str())
+    }
+}
+
+impl Serializable for TreeName {
+    const SIZE: usize = TREE_NAME_LEN;
+    const TYPE_ID: u16 = 3;
+
+    fn to_bytes(&self, bytes: &mut Cursor<&mut [u8]>) {
+        bytes.put_slice(&self.0);
+    }
+    fn from_bytes(bytes: &mut Cursor<&[u8]>) -> Result<Self, failure::Error> {
+        let mut name = [0; TREE_NAME_LEN];
+        bytes.copy_to_slice(&mut name);
+
+        let len = name.iter().position(|&b| b == 0).unwrap_or(TREE_NAME_LEN);
+        str::from_utf8(&name[..len]).map_err(|_| format_err!("Tree name is not valid UTF-8: {:?}", &name[..len]))?;
+
+        Ok(TreeName(name))
+    }
+}
+
+impl TreeDescriptor {
+    /// Describes a tree with keys `K`, values `V` and fanout `B`.
+    pub fn new<K: Serializable, V: Serializable, B: ConstUsize>() -> TreeDescriptor {
+        TreeDescriptor {
+            key_type: K::TYPE_ID,
+            key_size: K::SIZE as u32,
+            value_type: V::TYPE_ID,
+            value_size: V::SIZE as u32,
+            fanout: B::USIZE as u32,
+        }
+    }
+}
+
+impl TreeEntry {
+    pub fn root(&self) -> ObjectPointer {
+        self.root.clone()
+    }
+
+    pub fn descriptor(&self) -> TreeDescriptor {
+        self.descriptor
+    }
+
+    /// Returns the root of the tree `name` if it holds keys `K` and values `V` with fanout `B`.
+    fn check<K: Serializable, V: Serializable, B: ConstUsize>(self, name: TreeName) -> Result<ObjectPointer, failure::Error> {
+        let expected = TreeDescriptor::new::<K, V, B>();
+        if self.descriptor != expected {
+            return Err(format_err!("Tree {:?} does not have the expected types. found: {:?}, expected: {:?}", name.as_str(), self.descriptor, expected));
+        }
+        Ok(self.root)
+    }
+}
+
+impl Serializable for TreeEntry {
+    const SIZE: usize = ObjectPointer::SIZE + (2 + 4 + 2 + 4 + 4);
+    const TYPE_ID: u16 = 4;
+
+    fn to_bytes(&self, bytes: &mut Cursor<&mut [u8]>) {
+        self.root.to_bytes(bytes);
+        bytes.put_u16::<LittleEndian>(self.descriptor.key_type);
+        bytes.put_u32::<LittleEndian>(self.descriptor.key_size);
+        bytes.put_u16::<LittleEndian>(self.descriptor.value_type);
+        bytes.put_u32::<LittleEndian>(self.descriptor.value_size);
+        bytes.put_u32::<LittleEndian>(self.descriptor.fanout);
+    }
+    fn from_bytes(bytes: &mut Cursor<&[u8]>) -> Result<Self, failure::Error> {
+        let root = ObjectPointer::from_bytes(bytes)?;
+        let descriptor = TreeDescriptor {
+            key_type: bytes.get_u16::<LittleEndian>(),
+            key_size: bytes.get_u32::<LittleEndian>(),
+            value_type: bytes.get_u16::<LittleEndian>(),
+            value_size: bytes.get_u32::<LittleEndian>(),
+            fanout: bytes.get_u32::<LittleEndian>(),
+        };
+
+        Ok(TreeEntry {root, descriptor})
+    }
+}
+
+/// Returns the entry of the tree `name`, if it exists, whatever it holds.
+#[async]
+pub fn lookup_tree_entry(handle: Handle, directory: ObjectPointer, name: TreeName) -> Result<Option<TreeEntry>, failure::Error> {
+    await!(get::<TreeName, TreeEntry, ConstUsize2>(handle, directory, name))
+}
+
+/// Returns the root of the tree `name`, if it exists.
+///
+/// Fails if the tree does not hold keys `K` and values `V` with fanout `B`.
+#[async]
+pub fn lookup_tree<K: Serializable + 'static, V: Serializable + 'static, B: ConstUsize + 'static>
+(handle: Handle, directory: ObjectPointer, name: TreeName)
+-> Result<Option<ObjectPointer>, failure::Error> {
+    match await!(lookup_tree_entry(handle, directory, name))? {
+        Some(entry) => Ok(Some(entry.check::<K, V, B>(name)?)),
+        None => Ok(None),
+    }
+}
+
+/// Returns the names of all the trees, sorted.
+#[async]
+pub fn list_trees(handle: Handle, directory: ObjectPointer) -> Result<Vec<TreeName>, failure::Error> {
+    let entries = await!(read_btree::<TreeName, TreeEntry, ConstUsize2>(handle, directory))?;
+    Ok(entries.into_iter().map(|e| e.key).collect())
+}
+
+/// Creates the empty tree `name` with keys `K` and values `V`.
+///
+/// Returns the new root of the directory.
+#[async]
+pub fn create_tree<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static>
+(handle: Handle, directory: ObjectPointer, allocator: Allocator, name: TreeName)
+-> Result<(ObjectPointer, Allocator), failure::Error> {
+    if await!(lookup_tree_entry(handle.clone(), directory.clone(), name))?.is_some() {
+        return Err(format_err!("Tree {:?} already exists", name.as_str()));
+    }
+
+    let (root, allocator) = await!(create_btree::<K, V, B>(handle.clone(), allocator))?;
+    let entry = TreeEntry {
+        root,
+        descriptor: TreeDescriptor::new::<K, V, B>(),
+    };
+    let (directory, allocator, _) = await!(insert_in_btree::<TreeName, TreeEntry, ConstUsize2>(
+        handle, directory, allocator, NodeEntry::new(name, entry)))?;
+
+    Ok((directory, allocator))
+}
+
+/// Records `root` as the new root of the existing tree `name`, which keeps its descriptor.
+///
+/// Returns the new root of the directory.
+#[async]
+pub fn update_tree(handle: Handle, directory: ObjectPointer, allocator: Allocator, name: TreeName, root: ObjectPointer)
+-> Result<(ObjectPointer, Allocator), failure::Error> {
+    let entry = await!(lookup_tree_entry(handle.clone(), directory.clone(), name))?
+        .ok_or(format_err!("Tree {:?} does not exist", name.as_str()))?;
+    let entry = TreeEntry {root, ..entry};
+
+    let (directory, allocator, _) = await!(insert_in_btree::<TreeName, TreeEntry, ConstUsize2>(
+        handle, directory, allocator, NodeEntry::new(name, entry)))?;
+
+    Ok((directory, allocator))
+}
+
+/// Records `root` as the root of the tree `name` with keys `K`, values `V` and fanout `B`,
+/// creating its entry if it does not exist yet.
+///
+/// Fails if the tree exists with other types.
+/// Returns the new root of the directory.
+#[async]
+pub fn put_tree<K: Serializable + 'static, V: Serializable + 'static, B: ConstUsize + 'static>
+(handle: Handle, directory: ObjectPointer, allocator: Allocator, name: TreeName, root: ObjectPointer)
+-> Result<(ObjectPointer, Allocator), failure::Error> {
+    await!(lookup_tree::<K, V, B>(handle.clone(), directory.clone(), name))?;
+
+    let entry = TreeEntry {
+        root,
+        descriptor: TreeDescriptor::new::<K, V, B>(),
+    };
+    let (directory, allocator, _) = await!(insert_in_btree::<TreeName, TreeEntry, ConstUsize2>(
+        handle, directory, allocator, NodeEntry::new(name, entry)))?;
+
+    Ok((directory, allocator))
+}
+
+/// Renames the tree `from` to `to`, which should not exist.
+///
+/// Returns the new root of the directory.
+#[async]
+pub fn rename_tree(handle: Handle, directory: ObjectPointer, allocator: Allocator, from: TreeName, to: TreeName)
+-> Result<(ObjectPointer, Allocator), failure::Error> {
+    let entry = await!(lookup_tree_entry(handle.clone(), directory.clone(), from))?
+        .ok_or(format_err!("Tree {:?} does not exist", from.as_str()))?;
+    if await!(lookup_tree_entry(handle.clone(), directory.clone(), to))?.is_some() {
+        return Err(format_err!("Tree {:?} already exists", to.as_str()));
+    }
+
+    let (directory, allocator, _) = await!(remove::<TreeName, TreeEntry, ConstUsize2>(
+        handle.clone(), directory, allocator, from))?;
+
+    let (directory, allocator, _) = await!(insert_in_btree::<TreeName, TreeEntry, ConstUsize2>(
+        handle, directory, allocator, NodeEntry::new(to, entry)))?;
+
+    Ok((directory, allocator))
+}
+
+/// Removes the tree `name` from the directory.
+///
+/// The space used by the tree is not reclaimed.
+/// Returns the new root of the directory.
+#[async]
+pub fn destroy_tree(handle: Handle, directory: ObjectPointer, allocator: Allocator, name: TreeName)
+-> Result<(ObjectPointer, Allocator), failure::Error> {
+    if await!(lookup_tree_entry(handle.clone(), directory.clone(), name))?.is_none() {
+        return Err(format_err!("Tree {:?} does not exist", name.as_str()));
+    }
+
+    let (directory, allocator, _) = await!(remove::<TreeName, TreeEntry, ConstUsize2>(
+        handle, directory, allocator, name))?;
+
+    Ok((directory, allocator))
+}
@@ -113,18 +113,18 @@ fn async_btree_insert_and_read<'f>(handle: Handle, vec: &'f Vec<(u64, u64)>) ->
     async_block!{
         await!(format(handle.clone()))?;
         let uberblock = await!(find_latest_uberblock(handle.clone()))?;
-        let (mut op, mut free_space_offset) = (uberblock.tree_root_pointer, uberblock.free_space_offset);
+        let (mut op, mut allocator) = (uberblock.tree_root_pointer, Allocator::new(uberblock.free_space_offset, uberblock.tgx + 1));
 
         // insert the vector in the btree
         for i in 0..vec.len() {
             let res = await!(insert_in_btree::<u64, u64, ConstUsize2>(
                 handle.clone(),
                 op.clone(),
-                free_space_offset,
+                allocator,
                 NodeEntry::<u64, u64>::new(vec[i].0 as u64, vec[i].1 as u64)
                 ))?;
             op = res.0;
-            free_space_offset = res.1;
+            allocator = res.1;
 
             // check that the key wasn't already there
             assert!(res.2 == None);
@@ -144,7 +144,7 @@ fn async_btree_insert_and_remove_checked<'f>(handle: Handle, vec: &'f Vec<Operat
         // format
         await!(format(handle.clone()))?;
         let uberblock = await!(find_latest_uberblock(handle.clone()))?;
-        let (mut op, mut free_space_offset) = (uberblock.tree_root_pointer, uberblock.free_space_offset);
+        let (mut op, mut allocator) = (uberblock.tree_root_pointer, Allocator::new(uberblock.free_space_offset, uberblock.tgx + 1));
 
         // process operations
         for o in vec {
@@ -154,11 +154,11 @@ fn async_btree_insert_and_remove_checked<'f>(handle: Handle, vec: &'f Vec<Operat
                     let res = await!(insert_in_btree::<u64, u64, ConstUsize2>(
                         handle.clone(),
                         op.clone(),
-                        free_space_offset,
+                        allocator,
                         NodeEntry::<u64, u64>::new(*k, *v)
                         ))?;
                     op = res.0;
-                    free_space_offset = res.1;
+                    allocator = res.1;
 
                     // insert in std btree
                     let std_old_value = std_btree.insert(*k, *v);
@@ -171,11 +171,11 @@ fn async_btree_insert_and_remove_checked<'f>(handle: Handle, vec: &'f Vec<Operat
                     let res = await!(remove::<u64, u64, ConstUsize2>(
                         handle.clone(),
                         op.clone(),
-                        free_space_offset,
+                        allocator,
                         *k
                         ))?;
                     op = res.0;
-                    free_space_offset = res.1;
+                    allocator = res.1;
 
                     // remove in std btree
                     let std_old_value = std_btree.remove(k);
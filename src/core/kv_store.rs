@@ -0,0 +1,178 @@
+use std::ops::Range;
+use super::*;
+use super::cow_btree::*;
+use super::directory::*;
+
+impl<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static> KvStore<K, V, B> {
+    /// Opens the store kept in the tree `name` of `pool`.
+    ///
+    /// The tree is created if it does not exist yet, it is then recorded in the pool by the next `commit()`.
+    /// Fails if it exists with other types or another fanout, or if another store has it open.
+    #[async]
+    pub fn open(pool: SharedPool, name: TreeName) -> Result<KvStore<K, V, B>, failure::Error> {
+        let tree = pool.open_tree(name)?;
+        let handle = pool.handle();
+        let mut allocator = pool.allocator();
+
+        let root_pointer = match await!(lookup_tree::<K, V, B>(handle.clone(), pool.directory_root_pointer(), name))? {
+            Some(root_pointer) => root_pointer,
+            None => {
+                if pool.read_only() {
+                    return Err(format_err!("Tree {:?} does not exist and the pool is read-only", name.as_str()));
+                }
+
+                let res = await!(create_btree::<K, V, B>(handle, allocator))?;
+                allocator = res.1;
+                res.0
+            }
+        };
+
+        Ok(
+            KvStore {
+                tree,
+                root_pointer,
+                allocator,
+                merge_operator: None,
+                _kv: PhantomData,
+            }
+        )
+    }
+
+    pub fn pool(&self) -> &SharedPool {
+        self.tree.pool()
+    }
+
+    /// Returns the number of keys in the store.
+    pub fn len(&self) -> u64 {
+        count(&self.root_pointer)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of bytes written since the last commit.
+    pub fn dirty_bytes(&self) -> u64 {
+        self.allocator.written_bytes()
+    }
+
+    pub fn get(&self, key: K) -> impl Future<Item=Option<V>, Error=failure::Error> {
+        get::<K, V, B>(self.tree.pool.handle(), self.root_pointer.clone(), key)
+    }
+
+    pub fn contains(&self, key: K) -> impl Future<Item=bool, Error=failure::Error> {
+        self.get(key).map(|value| value.is_some())
+    }
+
+    /// Returns the key/value pairs whose key is in `range`, sorted by key.
+    pub fn range(&self, range: Range<K>) -> impl Future<Item=Vec<(K, V)>, Error=failure::Error> {
+        read_range::<K, V, B>(self.tree.pool.handle(), self.root_pointer.clone(), range)
+            .map(|entries| entries.into_iter().map(|e| (e.key, e.value)).collect())
+    }
+
+    /// Returns a `Snapshot` of the store as of its last commit.
+    ///
+    /// It is not affected by the modifications and commits which come after.
+    pub fn snapshot(&self) -> impl Future<Item=Snapshot<K, V, B>, Error=failure::Error> {
+        Snapshot::open(self.tree.pool.reader(), self.tree.name)
+    }
+
+    // The modifications work on a copy of the allocator, so that the store is left as it was if they fail.
+
+    /// Inserts or replaces the value of `key` and returns the previous one.
+    pub fn put<'f>(&'f mut self, key: K, value: V) -> Box<Future<Item=Option<V>, Error=failure::Error> + 'f> { // box not really needed
+        Box::new(async_block! {
+            self.check_writable()?;
+
+            let (root_pointer, allocator, old_value) = await!(insert_in_btree::<K, V, B>(
+                self.tree.pool.handle(), self.root_pointer.clone(), self.allocator.clone(), NodeEntry::new(key, value)))?;
+            self.root_pointer = root_pointer;
+            self.allocator = allocator;
+
+            Ok(old_value)
+        })
+    }
+
+    /// Registers the function used by `merge()`, e.g. to increment counters or append to values.
+    pub fn set_merge_operator(&mut self, merge: MergeFn<V>) {
+        self.merge_operator = Some(merge);
+    }
+
+    /// Combines the value of `key` with `operand` using the registered merge operator.
+    pub fn merge<'f>(&'f mut self, key: K, operand: V) -> Box<Future<Item=(), Error=failure::Error> + 'f> { // box not really needed
+        Box::new(async_block! {
+            self.check_writable()?;
+            let merge = self.merge_operator
+                .ok_or(format_err!("No merge operator registered"))?;
+
+            let (root_pointer, allocator) = await!(merge_in_btree::<K, V, B>(
+                self.tree.pool.handle(), self.root_pointer.clone(), self.allocator.clone(), key, operand, merge))?;
+            self.root_pointer = root_pointer;
+            self.allocator = allocator;
+
+            Ok(())
+        })
+    }
+
+    /// Removes `key` and returns its value.
+    pub fn delete<'f>(&'f mut self, key: K) -> Box<Future<Item=Option<V>, Error=failure::Error> + 'f> { // box not really needed
+        Box::new(async_block! {
+            self.check_writable()?;
+
+            let (root_pointer, allocator, old_value) = await!(remove::<K, V, B>(
+                self.tree.pool.handle(), self.root_pointer.clone(), self.allocator.clone(), key))?;
+            self.root_pointer = root_pointer;
+            self.allocator = allocator;
+
+            Ok(old_value)
+        })
+    }
+
+    /// Makes all the modifications since the last commit durable, in a new transaction group.
+    ///
+    /// The other trees of the pool are committed as they were by their last commit.
+    /// The modifications can be committed again if it fails.
+    pub fn commit<'f>(&'f mut self) -> Box<Future<Item=(), Error=failure::Error> + 'f> { // box not really needed
+        Box::new(async_block! {
+            self.check_writable()?;
+            let pool = self.tree.pool.clone();
+
+            // the other users of the pool may have committed since this store was opened
+            let lock = await!(pool.lock())?;
+            let mut root_pointer = self.root_pointer.clone();
+            let mut allocator = self.allocator.clone();
+
+            // then the objects written since the last commit are not born in the next transaction group
+            let stale_tgx = allocator.tgx();
+            if stale_tgx != pool.tgx() + 1 {
+                allocator.set_tgx(pool.tgx() + 1);
+                let res = await!(rebirth_btree::<K, V, B>(pool.handle(), root_pointer, allocator, stale_tgx))?;
+                root_pointer = res.0;
+                allocator = res.1;
+            }
+
+            let (directory_root_pointer, allocator) = await!(put_tree::<K, V, B>(
+                pool.handle(), pool.directory_root_pointer(), allocator, self.tree.name, root_pointer.clone()))?;
+            await!(lock.commit_trees(pool.tree_root_pointer(), directory_root_pointer, allocator))?;
+
+            // the next objects belong to the next transaction group
+            self.root_pointer = root_pointer;
+            self.allocator = pool.allocator();
+
+            Ok(())
+        })
+    }
+
+    /// Closes the pool for all its users. Modifications which have not been committed are lost.
+    #[async]
+    pub fn close(self) -> Result<(), failure::Error> {
+        await!(self.tree.pool.clone().close())
+    }
+
+    fn check_writable(&self) -> Result<(), failure::Error> {
+        if self.tree.pool.read_only() {
+            return Err(format_err!("Cannot modify the store: the pool is opened read-only"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,316 @@
+use super::*;
+use super::util::*;
+use super::uberblock::*;
+
+impl Label {
+    pub const SIZE: usize = 8 + 16 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 8;
+
+    /// Creates the label of a new pool with a random UUID.
+    pub fn new(device_size: u64, fanout: u64, options: &FormatOptions) -> Label {
+        Label {
+            pool_uuid: random_uuid(),
+            version: FORMAT_VERSION,
+            compat_features: SUPPORTED_COMPAT_FEATURES,
+            incompat_features: SUPPORTED_INCOMPAT_FEATURES,
+            device_size,
+            block_size: BLOCK_SIZE as u64,
+            fanout,
+            uberblock_ring_size: options.uberblock_ring_size,
+            uberblock_slot_size: options.uberblock_slot_size,
+            state: PoolState::Exported,
+            hostid: hostid(),
+        }
+    }
+
+    pub fn pool_uuid(&self) -> [u8; 16] {
+        self.pool_uuid
+    }
+
+    pub fn device_size(&self) -> u64 {
+        self.device_size
+    }
+
+    pub fn fanout(&self) -> u64 {
+        self.fanout
+    }
+
+    pub fn state(&self) -> PoolState {
+        self.state
+    }
+
+    pub fn hostid(&self) -> u64 {
+        self.hostid
+    }
+
+    /// Changes the state of the pool on behalf of this host.
+    pub fn set_state(&mut self, state: PoolState) {
+        self.state = state;
+        self.hostid = hostid();
+    }
+
+    pub fn uberblock_ring_size(&self) -> u64 {
+        self.uberblock_ring_size
+    }
+
+    pub fn uberblock_slot_size(&self) -> u64 {
+        self.uberblock_slot_size
+    }
+
+    /// Size of one copy of the uberblock ring.
+    pub fn uberblock_ring_len(&self) -> u64 {
+        self.uberblock_ring_size * self.uberblock_slot_size
+    }
+
+    /// Returns the offsets of the copies of the uberblock ring:
+    /// right after the first label and right before the last one.
+    pub fn uberblock_ring_offsets(&self) -> [u64; LABEL_REGIONS] {
+        // the geometry has been checked: the last label is after the first one
+        let last_label_offset = device_end(self.device_size) - BLOCK_SIZE as u64;
+        [BLOCK_SIZE as u64, last_label_offset - self.uberblock_ring_len()]
+    }
+
+    /// Returns the offsets of the copies of uberblock `slot`.
+    pub fn uberblock_slot_offsets(&self, slot: u64) -> [u64; LABEL_REGIONS] {
+        let mut offsets = self.uberblock_ring_offsets();
+        for offset in offsets.iter_mut() {
+            *offset += slot * self.uberblock_slot_size;
+        }
+        offsets
+    }
+
+    /// Offset of the first byte available for data.
+    pub fn data_start(&self) -> u64 {
+        self.uberblock_ring_offsets()[0] + self.uberblock_ring_len()
+    }
+
+    /// Offset of the first byte after the data, where the last label region starts.
+    pub fn data_end(&self) -> u64 {
+        self.uberblock_ring_offsets()[1]
+    }
+
+    pub fn from_bytes(bytes: &mut Cursor<&[u8]>) -> Result<Label, failure::Error> {
+        assert!(bytes.remaining() >= Label::SIZE);
+
+        // the checksum covers everything but itself
+        let start = bytes.position() as usize;
+        let checksum = content_hash(&bytes.get_ref()[start..start + Label::SIZE - 8]);
+
+        let mut magic = [0;8];
+        bytes.copy_to_slice(&mut magic);
+        if magic != LABEL_MAGIC_NUMBER {
+            return Err(format_err!("Incorrect label magic number. found: {:?}, expected: {:?}", magic, LABEL_MAGIC_NUMBER));
+        }
+        let mut pool_uuid = [0;16];
+        bytes.copy_to_slice(&mut pool_uuid);
+
+        let label = Label {
+            pool_uuid,
+            version: bytes.get_u64::<LittleEndian>(),
+            compat_features: bytes.get_u64::<LittleEndian>(),
+            incompat_features: bytes.get_u64::<LittleEndian>(),
+            device_size: bytes.get_u64::<LittleEndian>(),
+            block_size: bytes.get_u64::<LittleEndian>(),
+            fanout: bytes.get_u64::<LittleEndian>(),
+            uberblock_ring_size: bytes.get_u64::<LittleEndian>(),
+            uberblock_slot_size: bytes.get_u64::<LittleEndian>(),
+            state: PoolState::from_u64(bytes.get_u64::<LittleEndian>())
+                .ok_or(format_err!("Unknown pool state"))?,
+            hostid: bytes.get_u64::<LittleEndian>(),
+        };
+
+        let found = bytes.get_u64::<LittleEndian>();
+        if found != checksum {
+            return Err(format_err!("Incorrect label checksum. found: {:#x}, expected: {:#x}", found, checksum));
+        }
+
+        Ok(label)
+    }
+
+    pub fn to_bytes(&self, bytes: &mut Cursor<&mut [u8]>) {
+        assert!(bytes.remaining_mut() >= Label::SIZE);
+        let start = bytes.position() as usize;
+
+        bytes.put_slice(LABEL_MAGIC_NUMBER);
+        bytes.put_slice(&self.pool_uuid);
+        bytes.put_u64::<LittleEndian>(self.version);
+        bytes.put_u64::<LittleEndian>(self.compat_features);
+        bytes.put_u64::<LittleEndian>(self.incompat_features);
+        bytes.put_u64::<LittleEndian>(self.device_size);
+        bytes.put_u64::<LittleEndian>(self.block_size);
+        bytes.put_u64::<LittleEndian>(self.fanout);
+        bytes.put_u64::<LittleEndian>(self.uberblock_ring_size);
+        bytes.put_u64::<LittleEndian>(self.uberblock_slot_size);
+        bytes.put_u64::<LittleEndian>(self.state.to_u64().unwrap()); // there is less than 2^64 states
+        bytes.put_u64::<LittleEndian>(self.hostid);
+
+        let checksum = content_hash(&bytes.get_ref()[start..start + Label::SIZE - 8]);
+        bytes.put_u64::<LittleEndian>(checksum);
+    }
+
+    pub fn to_mem(&self) -> Box<[u8]> {
+        let mut mem = Vec::with_capacity(Label::SIZE);
+        unsafe{mem.set_len(Label::SIZE)};
+        self.to_bytes(&mut Cursor::new(&mut mem));
+        return mem.into_boxed_slice();
+    }
+
+    /// Checks that this version knows how to handle the pool described by the label.
+    ///
+    /// Unknown compatible features are fine, unknown incompatible features are not.
+    pub fn check_compatibility(&self) -> Result<(), failure::Error> {
+        // there is no upgrade path: older layouts can't be read either
+        if self.version != FORMAT_VERSION {
+            return Err(format_err!("Unsupported on-disk format version. found: {}, supported: {}", self.version, FORMAT_VERSION));
+        }
+
+        let unknown_incompat_features = self.incompat_features & !SUPPORTED_INCOMPAT_FEATURES;
+        if unknown_incompat_features != 0 {
+            return Err(format_err!("Unsupported incompatible features: {:#x}", unknown_incompat_features));
+        }
+
+        if self.block_size != BLOCK_SIZE as u64 {
+            return Err(format_err!("Unsupported block size. found: {}, supported: {}", self.block_size, BLOCK_SIZE));
+        }
+
+        self.check_geometry()
+    }
+
+    /// Checks that the uberblock rings fit in their slots and that the label regions leave room for data.
+    pub fn check_geometry(&self) -> Result<(), failure::Error> {
+        if self.uberblock_ring_size == 0 {
+            return Err(format_err!("The uberblock ring should have at least one slot"));
+        }
+
+        if self.uberblock_slot_size < Uberblock::SIZE as u64 {
+            return Err(format_err!("Uberblock slots too small. found: {}, minimum: {}", self.uberblock_slot_size, Uberblock::SIZE));
+        }
+
+        // only whole blocks are used, see `label_offsets()`
+        let label_regions_len = LABEL_REGIONS as u64 * (BLOCK_SIZE as u64 + self.uberblock_ring_len());
+        if device_end(self.device_size) < label_regions_len + BLOCK_SIZE as u64 {
+            return Err(format_err!("Device too small: {} bytes", self.device_size));
+        }
+
+        Ok(())
+    }
+
+    pub fn async_write_at(&self, handle: Handle, offset: u64) -> impl Future<Item=u64, Error=failure::Error> {
+        handle.write(self.to_mem().to_vec(), offset)
+    }
+}
+
+/// Returns the offsets of the copies of the label: the first and the last block of the device.
+///
+/// They don't depend on the geometry recorded in the label so that any copy can be found.
+/// Fails when the device is too small to hold a copy in each of its own block.
+pub fn label_offsets(device_size: u64) -> Result<[u64; LABEL_REGIONS], failure::Error> {
+    let end = device_end(device_size);
+    if end < LABEL_REGIONS as u64 * BLOCK_SIZE as u64 {
+        return Err(format_err!("Device too small for its labels: {} bytes, minimum: {}", device_size, LABEL_REGIONS * BLOCK_SIZE));
+    }
+
+    Ok([0, end - BLOCK_SIZE as u64])
+}
+
+/// Returns the end of the last whole block of the device.
+fn device_end(device_size: u64) -> u64 {
+    device_size / BLOCK_SIZE as u64 * BLOCK_SIZE as u64
+}
+
+/// Reads the label of the pool and checks that it can be opened by this version.
+///
+/// All copies are tried in turn, the first undamaged one is returned.
+/// A copy which can't be read is considered damaged.
+#[async]
+pub fn read_label(handle: Handle) -> Result<Label, failure::Error> {
+    let device_size = await!(handle.size())?;
+    let offsets = label_offsets(device_size)?;
+
+    let mut error = None;
+    for i in 0..LABEL_REGIONS {
+        let mem = match await!(handle.read(offsets[i], Label::SIZE as u64)) {
+            Ok(mem) => mem,
+            Err(e) => {
+                error = Some(e);
+                continue;
+            }
+        };
+        match Label::from_bytes(&mut Cursor::new(&mem)) {
+            Ok(label) => {
+                label.check_compatibility()?;
+                return Ok(label);
+            }
+            Err(e) => error = Some(e)
+        }
+    }
+
+    Err(error.unwrap()) // there is at least one region
+}
+
+/// Writes `label` over all its copies.
+///
+/// The copies are written one after the other, each one flushed before the next one is started,
+/// so that a crash can't damage all of them at once.
+#[async]
+pub fn write_label(handle: Handle, label: Label) -> Result<(), failure::Error> {
+    let device_size = await!(handle.size())?;
+
+    for offset in label_offsets(device_size)?.iter() {
+        await!(label.async_write_at(handle.clone(), *offset))?;
+        await!(handle.flush())?;
+    }
+
+    Ok(())
+}
+
+/// Rewrites the damaged copies of the label and of the uberblocks from the undamaged ones.
+///
+/// Returns the number of copies which have been rewritten.
+#[async]
+pub fn repair_label_regions(handle: Handle) -> Result<u64, failure::Error> {
+    let label = await!(read_label(handle.clone()))?;
+    let device_size = await!(handle.size())?;
+    let offsets = label_offsets(device_size)?;
+
+    let mut writes = vec![];
+
+    // labels: all copies should be identical to the good one
+    let label_mem = label.to_mem().into_vec();
+    for i in 0..LABEL_REGIONS {
+        let damaged = match await!(handle.read(offsets[i], Label::SIZE as u64)) {
+            Ok(mem) => mem != label_mem,
+            Err(_) => true
+        };
+        if damaged {
+            writes.push((offsets[i], label_mem.clone()));
+        }
+    }
+
+    // uberblocks: all copies of a slot should be identical to its latest one
+    let rings = await!(read_uberblock_rings(handle.clone(), label.clone()))?;
+    for slot in 0..label.uberblock_ring_size as usize {
+        let latest = match rings.iter().filter_map(|ring| ring[slot].as_ref()).max_by_key(|u| u.tgx) {
+            Some(u) => u,
+            None => continue // nothing to repair from
+        };
+
+        let slot_offsets = label.uberblock_slot_offsets(slot as u64);
+        for i in 0..LABEL_REGIONS {
+            let damaged = match rings[i][slot] {
+                Some(ref u) => u.tgx != latest.tgx,
+                None => true
+            };
+            if damaged {
+                writes.push((slot_offsets[i], latest.to_mem().into_vec()));
+            }
+        }
+    }
+
+    let repaired = writes.len() as u64;
+    let writes: Vec<_> = writes.into_iter()
+        .map(|(offset, mem)| handle.write(mem, offset))
+        .collect();
+    await!(future::join_all(writes))?;
+
+    Ok(repaired)
+}
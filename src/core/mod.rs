@@ -11,10 +11,23 @@ use itertools::Itertools;
 use num_traits::{FromPrimitive, ToPrimitive};
 use bytes::{Buf, BufMut, LittleEndian};
 use std::io::Cursor;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
 
 mod object_pointer;
+mod label;
 mod uberblock;
 mod cow_btree;
+mod pool;
+mod allocator;
+mod directory;
+mod kv_store;
+mod client;
+mod transaction;
+mod reader;
+mod txg_sync;
 mod util;
 
 #[cfg(any(feature="instrumentation", test, fuzzing))]
@@ -24,16 +37,170 @@ pub mod instrumentation;
 mod tests;
 
 const MAGIC_NUMBER: &[u8] = b"ReactFS0";
+const LABEL_MAGIC_NUMBER: &[u8] = b"ReactFSL";
 const BLOCK_SIZE: usize = 4096;
 
+/// On-disk format version written by `format()`, bumped by every change of the layout:
+///
+/// 1. label
+/// 2. label and uberblock ring at both ends of the device
+/// 3. uberblock ring geometry recorded in the label
+/// 4. timestamp in uberblocks
+/// 5. pool state and host id in the label, host id in uberblocks
+/// 6. birth `tgx` in object pointers
+/// 7. subtree counts in object pointers
+/// 8. directory of named trees, with the key type, value type and fanout of each tree
+///
+/// Pools with another version are refused.
+const FORMAT_VERSION: u64 = 8;
+
+/// Compatible features known by this version.
+///
+/// Unknown compatible features are ignored.
+const SUPPORTED_COMPAT_FEATURES: u64 = 0;
+
+/// Incompatible features known by this version.
+///
+/// Pools with unknown incompatible features are refused.
+const SUPPORTED_INCOMPAT_FEATURES: u64 = 0;
+
+// device layout: a label region at each end of the device holds a copy of the label
+// in its outermost block next to a copy of the uberblock ring, the data lies in between
+const LABEL_REGIONS: usize = 2;
+const DEFAULT_UBERBLOCK_RING_SIZE: u64 = 10;
+const DEFAULT_UBERBLOCK_SLOT_SIZE: u64 = BLOCK_SIZE as u64;
+
 const fn btree_degree(b: usize) -> usize {b * 2 + 1}
 const fn btree_split(b: usize) -> usize {b + 1}
 
-#[derive(Debug)]
+const TREE_NAME_LEN: usize = 32;
+
+const DEFAULT_SYNC_INTERVAL_SECS: u64 = 5;
+const DEFAULT_SYNC_DIRTY_BYTES: u64 = 16 * 1024 * 1024;
+
+/// The label describes the pool and the geometry of the device.
+///
+/// It is written once by `format()` and read first when opening a pool.
+#[derive(Debug, Clone)]
+pub struct Label {
+    pool_uuid: [u8; 16],
+    version: u64,
+    compat_features: u64,
+    incompat_features: u64,
+    device_size: u64,
+    block_size: u64,
+    fanout: u64,
+    uberblock_ring_size: u64,
+    uberblock_slot_size: u64,
+    state: PoolState,
+    hostid: u64, // host which last changed the state
+}
+
+/// Whether a pool is currently opened for writing.
+#[derive(Debug, Clone, Copy, PartialEq, Primitive)]
+pub enum PoolState {
+    Exported = 0,
+    Active = 1,
+}
+
+/// Options chosen when formatting a pool. They are recorded in the `Label`.
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// number of uberblocks kept in a ring, and so of past `tgx` which can be recovered
+    pub uberblock_ring_size: u64,
+    /// space reserved for each uberblock in the ring
+    pub uberblock_slot_size: u64,
+}
+
+#[derive(Debug, Clone)]
 pub struct Uberblock {
     tgx: u64,
+    timestamp: u64,
+    hostid: u64,
     free_space_offset: u64,
     tree_root_pointer: ObjectPointer,
+    directory_root_pointer: ObjectPointer,
+}
+
+/// An opened pool: its label and the uberblock of the transaction group it is at.
+pub struct Pool {
+    handle: Handle,
+    label: Label,
+    uberblock: Uberblock,
+    read_only: bool,
+    pins: Pins,
+    dead_objects: Vec<DeadObject>, // freed but maybe still readable
+    free_space: Rc<RefCell<FreeSpace>>, // shared by its allocators
+}
+
+/// A `Pool` shared by the stores of several tasks of the reactor.
+///
+/// Clones share the same pool. Commits are serialized by `lock()`, so that each one
+/// starts from the latest transaction group.
+#[derive(Clone)]
+pub struct SharedPool {
+    handle: Handle,
+    state: Rc<RefCell<SharedPoolState>>,
+}
+
+struct SharedPoolState {
+    pool: Pool,
+    locked: bool, // a commit is in progress
+    waiters: Vec<TaskId>, // tasks waiting for the lock
+    open_trees: Vec<TreeName>, // see `OpenTree`
+    closed: bool,
+}
+
+/// The right to commit a transaction group in a `SharedPool`, released when dropped.
+pub struct PoolLock {
+    pool: SharedPool,
+}
+
+/// A tree of a `SharedPool` reserved by the store which modifies it, released when dropped.
+///
+/// Two stores of the same tree would free the same objects and overwrite each other's commits.
+pub struct OpenTree {
+    pool: SharedPool,
+    name: TreeName,
+}
+
+/// Number of `Reader`s of each `tgx`, shared by the pool and its readers.
+pub type Pins = Rc<RefCell<BTreeMap<u64, usize>>>;
+
+/// Keeps the objects of the transaction group `tgx` from being reused while it exists.
+pub struct Pin {
+    pins: Pins,
+    tgx: u64,
+}
+
+/// A read-only view of a pool at a committed transaction group.
+///
+/// It stays valid while later transaction groups are committed, see `Pool::reader()`.
+#[derive(Clone)]
+pub struct Reader {
+    handle: Handle,
+    uberblock: Uberblock,
+    pin: Pin,
+}
+
+/// A named tree as committed in the transaction group of a `Reader`.
+pub struct Snapshot<K: Serializable + Ord + Copy, V: Serializable, B: ConstUsize = ConstUsize2> {
+    reader: Reader,
+    root_pointer: ObjectPointer,
+    _kv: PhantomData<(K, V, B)>,
+}
+
+/// Options used by `Pool::open()`.
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    /// never write anything to the device
+    pub read_only: bool,
+    /// open the pool at this past `tgx` instead of the latest one, read-only unless `rewind` is set
+    pub tgx: Option<u64>,
+    /// discard all the transaction groups after `tgx` and continue from there
+    pub rewind: bool,
+    /// open the pool for writing even if it looks active, i.e. in use by another process
+    pub force: bool,
 }
 
 #[derive(Debug, Clone, Primitive)]
@@ -52,10 +219,173 @@ pub enum AnyObject<K: Serializable + Ord + Copy, V: Serializable, B: ConstUsize>
 pub struct ObjectPointer {
     offset: u64,
     len: u64,
+    birth_tgx: u64,
+    count: u64, // number of keys in the subtree
     object_type: ObjectType
     // checksum
 }
 
+/// Hands out space for new objects.
+///
+/// Clones share the same space but each one records the objects freed so far:
+/// only one of them should be committed.
+#[derive(Debug, Clone)]
+pub struct Allocator {
+    space: Rc<RefCell<FreeSpace>>,
+    tgx: u64,
+    freed: Vec<DeadObject>, // objects replaced during tgx
+    written_bytes: u64,
+}
+
+/// The space of the device which is not used, shared by the allocators of a pool
+/// so that they never hand out the same bytes twice.
+#[derive(Debug)]
+pub struct FreeSpace {
+    free_space_offset: u64, // nothing is written after it
+    data_end: u64, // allocations must not go past it
+    free_extents: Vec<Extent>, // reused before the space after free_space_offset
+}
+
+/// A range of the device.
+#[derive(Debug, Clone, Copy)]
+pub struct Extent {
+    offset: u64,
+    len: u64,
+}
+
+/// An object replaced during the transaction group `death_tgx`.
+///
+/// It is still referenced by the roots of the transaction groups from `birth_tgx` to `death_tgx - 1`.
+#[derive(Debug, Clone, Copy)]
+pub struct DeadObject {
+    extent: Extent,
+    birth_tgx: u64,
+    death_tgx: u64,
+}
+
+/// Name of a tree in the directory of a pool: up to `TREE_NAME_LEN` bytes of UTF-8, zero padded.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TreeName([u8; TREE_NAME_LEN]);
+
+/// Value of the directory for each tree: its root and what it holds.
+#[derive(Debug, Clone)]
+pub struct TreeEntry {
+    root: ObjectPointer,
+    descriptor: TreeDescriptor,
+}
+
+/// Types of the keys and values of a tree and its fanout, checked whenever the tree is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TreeDescriptor {
+    key_type: u16, // Serializable::TYPE_ID
+    key_size: u32,
+    value_type: u16,
+    value_size: u32,
+    fanout: u32, // ConstUsize::USIZE
+}
+
+/// A typed key/value store kept in a named tree of a pool, with fanout `B`.
+///
+/// Modifications are written right away but only become durable with `commit()`.
+/// Several stores can share a pool, each with its own tree: a tree is opened by one store at a time.
+/// A modification which fails leaves the store as it was.
+pub struct KvStore<K: Serializable + Ord + Copy, V: Serializable, B: ConstUsize = ConstUsize2> {
+    tree: OpenTree,
+    root_pointer: ObjectPointer,
+    allocator: Allocator,
+    merge_operator: Option<MergeFn<V>>,
+    _kv: PhantomData<(K, V, B)>,
+}
+
+/// A named tree of a pool with fanout `B`, shared by the transactions of several tasks of the reactor.
+///
+/// Clones share the same state.
+pub struct SharedTree<K: Serializable + Ord + Copy, V: Serializable, B: ConstUsize = ConstUsize2> {
+    handle: Handle,
+    state: Rc<RefCell<SharedTreeState>>,
+    _kv: PhantomData<(K, V, B)>,
+}
+
+struct SharedTreeState {
+    tree: OpenTree,
+    root_pointer: ObjectPointer, // of the last commit, or of the new empty tree
+}
+
+/// An optimistic transaction: it reads from the snapshot taken when it began and buffers its writes.
+///
+/// At commit, the keys it read are checked against the latest committed tree.
+pub struct Transaction<K: Serializable + Ord + Copy, V: Serializable, B: ConstUsize = ConstUsize2> {
+    tree: SharedTree<K, V, B>,
+    snapshot: ObjectPointer,
+    _pin: Pin, // keeps the snapshot readable
+    reads: BTreeMap<K, ObjectPointer>, // leaf through which each key was read
+    writes: BTreeMap<K, Option<V>>, // None for a removal
+}
+
+/// Error returned when a transaction could not commit because of a concurrent one. It can be retried.
+#[derive(Debug)]
+pub struct TransactionConflict;
+
+/// When a `SyncedKvStore` commits its transaction group.
+#[derive(Debug, Clone)]
+pub struct SyncOptions {
+    /// maximum time between two commits
+    pub interval: Duration,
+    /// commit as soon as this many bytes have been written since the last commit
+    pub dirty_bytes: u64,
+}
+
+/// A `KvStore` whose transaction groups are committed by a background task of the reactor.
+///
+/// It can be cloned and used by several tasks, clones share the same store.
+/// A failed operation or commit leaves the store as it was: the modifications are committed
+/// again later. The error of a failed commit of the background task is returned by the next operation.
+pub struct SyncedKvStore<K: Serializable + Ord + Copy, V: Serializable, B: ConstUsize = ConstUsize2> {
+    handle: Handle,
+    state: Rc<RefCell<SyncedKvStoreState<K, V, B>>>,
+}
+
+struct SyncedKvStoreState<K: Serializable + Ord + Copy, V: Serializable, B: ConstUsize> {
+    store: Option<KvStore<K, V, B>>, // taken out while it is used
+    waiters: Vec<TaskId>, // tasks waiting for the store to be put back
+    sync_error: Option<failure::Error>, // of the background task, not reported yet
+    options: SyncOptions,
+    last_sync: Instant,
+    tgx: u64, // of the last commit
+    closed: bool,
+    sync_task: Option<JoinHandle<(), failure::Error>>,
+}
+
+/// Block device used by a `Client`.
+#[derive(Debug, Clone)]
+pub enum BackendConfig {
+    /// volatile memory of `size` bytes
+    Mem {
+        size: usize
+    },
+    /// the file `bd.raw` in the current directory
+    UnixFile,
+}
+
+/// Configuration of a `Client`.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub backend: BackendConfig,
+    /// format the device before opening the pool, erasing it; always done for `BackendConfig::Mem`
+    pub format: bool,
+    /// name of the tree of the pool holding the data
+    pub tree: String,
+    pub open_options: OpenOptions,
+}
+
+/// A blocking client for synchronous code.
+///
+/// It owns the reactor and the backend thread, and runs each operation on a `KvStore` to completion.
+pub struct Client<K: Serializable + Ord + Copy, V: Serializable, B: ConstUsize = ConstUsize2> {
+    core: Core,
+    store: KvStore<K, V, B>,
+}
+
 // traits
 
 trait Index {
@@ -66,6 +396,18 @@ trait NodeTrait<K: Serializable + Ord + Copy, V: Serializable> {
     fn insert(&mut self, entry: NodeEntry<K, V>) -> Option<V>;
 }
 
+/// A modification of the value of a key, decided in its leaf during the insert descent.
+pub trait LeafUpdate<K, V> {
+    /// Result returned to the caller
+    type Output: 'static;
+
+    fn key(&self) -> K;
+    /// Called when the key exists, may modify its value in place.
+    fn update(self, value: &mut V) -> Self::Output;
+    /// Called when the key does not exist, may return a value to insert.
+    fn insert(self) -> (Option<V>, Self::Output);
+}
+
 // poor man's const generic
 
 pub trait ConstUsize {
@@ -108,6 +450,31 @@ pub struct NodeEntry<K: Serializable + Ord + Copy, V: Serializable> {
     value: V,
 }
 
+/// Inserts a value only if its key does not exist yet.
+#[derive(Debug)]
+pub struct PutIfAbsent<K, V> {
+    key: K,
+    value: V,
+}
+
+/// Replaces the value of an existing key only if it is equal to `expected`.
+#[derive(Debug)]
+pub struct CompareAndSwap<K, V> {
+    key: K,
+    expected: V,
+    new: V,
+}
+
+/// A user function combining the current value of a key, if any, with an operand.
+pub type MergeFn<V> = fn(Option<&V>, V) -> V;
+
+/// Replaces the value of a key by the result of `merge` on it and `operand`.
+pub struct Merge<K, V> {
+    key: K,
+    operand: V,
+    merge: MergeFn<V>,
+}
+
 #[derive(Debug)]
 pub struct Node<K: Serializable + Ord + Copy, V: Serializable, B: ConstUsize, T: ConstObjectType> {
     entries: Vec<NodeEntry<K, V>>,
@@ -119,6 +486,8 @@ pub struct Node<K: Serializable + Ord + Copy, V: Serializable, B: ConstUsize, T:
 
 pub trait Serializable: Sized {
     const SIZE: usize;
+    /// Identifies the type in the descriptors of the trees, different for all the types stored in a pool.
+    const TYPE_ID: u16;
 
     fn to_bytes(&self, bytes: &mut Cursor<&mut [u8]>);
     fn from_bytes(bytes: &mut Cursor<&[u8]>) -> Result<Self, failure::Error>;
@@ -126,6 +495,7 @@ pub trait Serializable: Sized {
 
 impl Serializable for u64 {
     const SIZE: usize = 8;
+    const TYPE_ID: u16 = 1;
 
     fn to_bytes(&self, bytes: &mut Cursor<&mut [u8]>) {
         bytes.put_u64::<LittleEndian>(*self);
@@ -136,7 +506,8 @@ impl Serializable for u64 {
 }
 
 impl Serializable for ObjectPointer {
-    const SIZE: usize = (8 + 8 + 1);
+    const SIZE: usize = (8 + 8 + 8 + 8 + 1);
+    const TYPE_ID: u16 = 2;
 
     fn to_bytes(&self, bytes: &mut Cursor<&mut [u8]>) {
         self.to_bytes(bytes);
@@ -1,19 +1,33 @@
 use super::*;
 
 impl ObjectPointer {
-    pub fn new(offset: u64, len: u64, object_type: ObjectType) -> ObjectPointer {
+    pub fn new(offset: u64, len: u64, birth_tgx: u64, count: u64, object_type: ObjectType) -> ObjectPointer {
         ObjectPointer {
             offset,
             len,
+            birth_tgx,
+            count,
             object_type,
         }
     }
 
+    /// The transaction group in which the object was written.
+    pub fn birth_tgx(&self) -> u64 {
+        self.birth_tgx
+    }
+
+    /// The number of keys in the pointed subtree.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
     pub fn from_bytes(bytes: &mut Cursor<&[u8]>) -> Result<ObjectPointer, failure::Error> {
-        assert!(bytes.remaining() >= 8 + 8 + 1);
+        assert!(bytes.remaining() >= 8 + 8 + 8 + 8 + 1);
         
         let offset = bytes.get_u64::<LittleEndian>();
         let len = bytes.get_u64::<LittleEndian>();
+        let birth_tgx = bytes.get_u64::<LittleEndian>();
+        let count = bytes.get_u64::<LittleEndian>();
         let object_type = ObjectType::from_u8(bytes.get_u8()).
             ok_or(format_err!("Unknown ObjectType"))?;
 
@@ -21,16 +35,20 @@ impl ObjectPointer {
             ObjectPointer {
                 offset,
                 len,
+                birth_tgx,
+                count,
                 object_type,
             }
         )
     }
 
     pub fn to_bytes(&self, bytes: &mut Cursor<&mut [u8]>) {
-        assert!(bytes.remaining_mut() >= 8 + 8 + 1);
+        assert!(bytes.remaining_mut() >= 8 + 8 + 8 + 8 + 1);
         
         bytes.put_u64::<LittleEndian>(self.offset);
         bytes.put_u64::<LittleEndian>(self.len);
+        bytes.put_u64::<LittleEndian>(self.birth_tgx);
+        bytes.put_u64::<LittleEndian>(self.count);
         bytes.put_u8(self.object_type.to_u8().unwrap()); // there is less than 2^8 types
     }
 
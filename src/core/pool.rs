@@ -0,0 +1,386 @@
+use super::*;
+use super::label::*;
+use super::uberblock::*;
+
+impl Pool {
+    /// Opens the pool on the device behind `handle`.
+    ///
+    /// By default the pool is opened at its latest transaction group.
+    /// `options.tgx` selects an older one which is still in the uberblock ring:
+    /// the pool is then read-only unless `options.rewind` is set, in which case
+    /// the transaction groups which came after it are discarded.
+    ///
+    /// With `options.read_only`, `handle` is made read-only as well so that nothing,
+    /// including the caller, can write to the device anymore.
+    ///
+    /// A pool opened for writing is marked active in its label until `close()` is called.
+    /// Opening an active pool for writing fails unless `options.force` is set:
+    /// it is either used by another process or it has not been closed properly.
+    #[async]
+    pub fn open(handle: Handle, options: OpenOptions) -> Result<Pool, failure::Error> {
+        if options.read_only && options.rewind {
+            return Err(format_err!("Cannot rewind a pool opened read-only"));
+        }
+
+        if options.read_only {
+            handle.set_read_only();
+        }
+
+        let mut label = await!(read_label(handle.clone()))?;
+        let mut uberblocks = await!(read_uberblocks(handle.clone(), label.clone()))?;
+
+        let index = match options.tgx {
+            None => uberblocks.len().checked_sub(1)
+                .ok_or(format_err!("All the uberblocks are damaged"))?,
+            Some(tgx) => uberblocks.iter().position(|u| u.tgx == tgx)
+                .ok_or(format_err!("No uberblock found for tgx {}", tgx))?,
+        };
+        let newer_tgxs: Vec<u64> = uberblocks.split_off(index + 1).iter().map(|u| u.tgx).collect();
+        let uberblock = uberblocks.pop().unwrap(); // guaranted to succeed
+
+        // an older transaction group can't be written to without discarding the newer ones
+        let read_only = options.read_only || (!newer_tgxs.is_empty() && !options.rewind);
+
+        if !read_only {
+            // make sure we are alone before writing anything
+            if label.state() == PoolState::Active && !options.force {
+                return Err(format_err!("The pool is active on host {:#x}: it is used by another process or has not been closed. \
+                                        Use OpenOptions::force to open it anyway", label.hostid()));
+            }
+
+            label.set_state(PoolState::Active);
+            await!(write_label(handle.clone(), label.clone()))?;
+        }
+
+        if options.rewind && !newer_tgxs.is_empty() {
+            // the next commit would otherwise not be the latest uberblock
+            await!(erase_uberblocks(handle.clone(), label.clone(), newer_tgxs))?;
+        }
+
+        let free_space = FreeSpace {
+            free_space_offset: uberblock.free_space_offset,
+            data_end: label.data_end(),
+            free_extents: Vec::new(),
+        };
+
+        Ok(
+            Pool {
+                handle,
+                label,
+                uberblock,
+                read_only,
+                pins: Rc::new(RefCell::new(BTreeMap::new())),
+                dead_objects: Vec::new(),
+                free_space: Rc::new(RefCell::new(free_space)),
+            }
+        )
+    }
+
+    /// Returns all the uberblocks still available in the ring, sorted by `tgx`.
+    ///
+    /// Any of them can be given to `Pool::open()` through `OpenOptions::tgx`.
+    #[async]
+    pub fn list_uberblocks(handle: Handle) -> Result<Vec<Uberblock>, failure::Error> {
+        let label = await!(read_label(handle.clone()))?;
+        await!(read_uberblocks(handle, label))
+    }
+
+    pub fn label(&self) -> &Label {
+        &self.label
+    }
+
+    pub fn uberblock(&self) -> &Uberblock {
+        &self.uberblock
+    }
+
+    pub fn tgx(&self) -> u64 {
+        self.uberblock.tgx
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn tree_root_pointer(&self) -> ObjectPointer {
+        self.uberblock.tree_root_pointer.clone()
+    }
+
+    /// Root of the directory of the named trees, see the `directory` module.
+    pub fn directory_root_pointer(&self) -> ObjectPointer {
+        self.uberblock.directory_root_pointer.clone()
+    }
+
+    /// Returns an `Allocator` for the next transaction group.
+    ///
+    /// It reuses the space of the objects which can't be read anymore.
+    /// All the allocators of the pool share its free space, so that none of its bytes is handed out twice,
+    /// and the space they don't use stays in the pool whether their transaction group is committed or not.
+    pub fn allocator(&self) -> Allocator {
+        Allocator::with_free_space(self.free_space.clone(), self.uberblock.tgx + 1)
+    }
+
+    /// Returns a `Reader` of the current transaction group.
+    ///
+    /// The objects it can read are not reused by the next transaction groups until it is dropped,
+    /// so that long scans never see them overwritten.
+    pub fn reader(&self) -> Reader {
+        Reader::new(self.handle.clone(), self.uberblock.clone(), self.pin())
+    }
+
+    /// Pins the current transaction group, see `reader()`.
+    pub fn pin(&self) -> Pin {
+        Pin::new(self.pins.clone(), self.uberblock.tgx)
+    }
+
+    /// Returns the number of bytes freed by the last transaction groups which can't be reused yet,
+    /// either because they are pinned or because an uberblock of the ring can still read them.
+    ///
+    /// The space freed before the pool was opened is not tracked and never reused.
+    pub fn deferred_free_space(&self) -> u64 {
+        self.dead_objects.iter().map(|d| d.extent.len).sum()
+    }
+
+    /// Marks the pool as not active anymore so that it can be opened for writing again.
+    #[async]
+    pub fn close(self) -> Result<(), failure::Error> {
+        let mut pool = self;
+
+        if !pool.read_only {
+            pool.label.set_state(PoolState::Exported);
+            await!(write_label(pool.handle.clone(), pool.label.clone()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Commits a new transaction group whose tree is at `tree_root_pointer`.
+    ///
+    /// `allocator` should be the one used to write that tree.
+    #[async]
+    pub fn commit(self, tree_root_pointer: ObjectPointer, allocator: Allocator) -> Result<(Pool, Allocator), failure::Error> {
+        let directory_root_pointer = self.directory_root_pointer();
+        await!(self.commit_trees(tree_root_pointer, directory_root_pointer, allocator))
+    }
+
+    /// Commits a new transaction group with both the default tree and the directory of named trees.
+    ///
+    /// All the named trees modified since the last commit are committed atomically.
+    #[async]
+    pub fn commit_trees(self, tree_root_pointer: ObjectPointer, directory_root_pointer: ObjectPointer, allocator: Allocator) -> Result<(Pool, Allocator), failure::Error> {
+        let mut pool = self;
+        let mut allocator = allocator;
+
+        let uberblock = pool.next_uberblock(tree_root_pointer, directory_root_pointer, &allocator)?;
+        await!(write_new_uberblock(pool.handle.clone(), pool.label.clone(), uberblock.clone()))?;
+        pool.committed(uberblock, &mut allocator);
+
+        Ok((pool, allocator))
+    }
+
+    /// Returns the uberblock of the next transaction group, to be written by a commit.
+    fn next_uberblock(&self, tree_root_pointer: ObjectPointer, directory_root_pointer: ObjectPointer, allocator: &Allocator) -> Result<Uberblock, failure::Error> {
+        if self.read_only {
+            return Err(format_err!("Cannot commit: the pool is opened read-only"));
+        }
+
+        Ok(Uberblock::new(self.uberblock.tgx + 1, tree_root_pointer, allocator.free_space_offset(), directory_root_pointer))
+    }
+
+    /// Moves to the transaction group of `uberblock`, once it is written.
+    fn committed(&mut self, uberblock: Uberblock, allocator: &mut Allocator) {
+        self.uberblock = uberblock;
+
+        // the allocator may have been created before other transaction groups were committed
+        let tgx = self.uberblock.tgx;
+        self.dead_objects.extend(allocator.take_freed().into_iter().map(|d| DeadObject {death_tgx: tgx, ..d}));
+        self.release_dead_objects();
+    }
+
+    /// Moves the dead objects which can't be read anymore to the free extents.
+    fn release_dead_objects(&mut self) {
+        // the uberblocks still in the ring can be opened with OpenOptions::tgx
+        let oldest_tgx = (self.uberblock.tgx + 1).saturating_sub(self.label.uberblock_ring_size);
+        let pins = self.pins.borrow();
+
+        let (released, kept): (Vec<DeadObject>, Vec<DeadObject>) = self.dead_objects.drain(..)
+            .partition(|d| d.death_tgx <= oldest_tgx && pins.range(d.birth_tgx..d.death_tgx).next().is_none());
+        self.dead_objects = kept;
+
+        if released.is_empty() {
+            return;
+        }
+
+        // merge the contiguous extents, objects are often freed in the order they were written
+        let mut space = self.free_space.borrow_mut();
+        space.free_extents.extend(released.into_iter().map(|d| d.extent));
+        space.free_extents.sort_by_key(|e| e.offset);
+        let mut merged: Vec<Extent> = Vec::with_capacity(space.free_extents.len());
+        for extent in space.free_extents.drain(..) {
+            match merged.last_mut() {
+                Some(ref mut last) if last.offset + last.len == extent.offset => last.len += extent.len,
+                _ => merged.push(extent),
+            }
+        }
+        space.free_extents = merged;
+    }
+}
+
+impl SharedPool {
+    pub fn new(pool: Pool) -> SharedPool {
+        SharedPool {
+            handle: pool.handle.clone(),
+            state: Rc::new(RefCell::new(SharedPoolState {
+                pool,
+                locked: false,
+                waiters: Vec::new(),
+                open_trees: Vec::new(),
+                closed: false,
+            })),
+        }
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /// `tgx` of the latest commit.
+    pub fn tgx(&self) -> u64 {
+        self.state.borrow().pool.tgx()
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.state.borrow().pool.read_only()
+    }
+
+    pub fn tree_root_pointer(&self) -> ObjectPointer {
+        self.state.borrow().pool.tree_root_pointer()
+    }
+
+    pub fn directory_root_pointer(&self) -> ObjectPointer {
+        self.state.borrow().pool.directory_root_pointer()
+    }
+
+    /// See `Pool::allocator()`.
+    pub fn allocator(&self) -> Allocator {
+        self.state.borrow().pool.allocator()
+    }
+
+    /// See `Pool::reader()`.
+    pub fn reader(&self) -> Reader {
+        self.state.borrow().pool.reader()
+    }
+
+    /// See `Pool::pin()`.
+    pub fn pin(&self) -> Pin {
+        self.state.borrow().pool.pin()
+    }
+
+    /// Reserves the tree `name` for the store which modifies it, fails if another one has it open.
+    pub fn open_tree(&self, name: TreeName) -> Result<OpenTree, failure::Error> {
+        let mut state = self.state.borrow_mut();
+        if state.open_trees.contains(&name) {
+            return Err(format_err!("Tree {:?} is already open", name.as_str()));
+        }
+        state.open_trees.push(name);
+
+        Ok(OpenTree {pool: self.clone(), name})
+    }
+
+    /// Waits for the commit in progress, if any, and returns the right to commit the next transaction group.
+    ///
+    /// The roots read from the pool while holding it are the latest ones until it is released.
+    pub fn lock(&self) -> impl Future<Item=PoolLock, Error=failure::Error> {
+        let shared = self.clone();
+        future::poll_fn(move || {
+            let mut state = shared.state.borrow_mut();
+            if state.closed {
+                return Err(format_err!("The pool is closed"));
+            }
+
+            if !state.locked {
+                state.locked = true;
+                return Ok(Async::Ready(PoolLock {pool: shared.clone()}));
+            }
+
+            // woken up when the lock is released
+            let task_id = shared.handle.current_task_id();
+            if !state.waiters.contains(&task_id) {
+                state.waiters.push(task_id);
+            }
+            Ok(Async::NotReady)
+        })
+    }
+
+    /// Waits for the commit in progress, if any, and closes the pool for all its users.
+    ///
+    /// Modifications which have not been committed are lost.
+    #[async]
+    pub fn close(self) -> Result<(), failure::Error> {
+        let _lock = await!(self.lock())?;
+
+        let (handle, label) = {
+            let mut state = self.state.borrow_mut();
+            state.closed = true;
+            if state.pool.read_only {
+                return Ok(());
+            }
+            state.pool.label.set_state(PoolState::Exported);
+            (state.pool.handle.clone(), state.pool.label.clone())
+        };
+        await!(write_label(handle, label))
+    }
+}
+
+impl PoolLock {
+    /// Commits a new transaction group, see `Pool::commit_trees()`, and returns its `tgx`.
+    ///
+    /// The pool is left at its previous transaction group if the commit fails.
+    #[async]
+    pub fn commit_trees(self, tree_root_pointer: ObjectPointer, directory_root_pointer: ObjectPointer, allocator: Allocator) -> Result<u64, failure::Error> {
+        let lock = self;
+        let mut allocator = allocator;
+
+        let (handle, label, uberblock) = {
+            let state = lock.pool.state.borrow();
+            let uberblock = state.pool.next_uberblock(tree_root_pointer, directory_root_pointer, &allocator)?;
+            (state.pool.handle.clone(), state.pool.label.clone(), uberblock)
+        };
+        await!(write_new_uberblock(handle, label, uberblock.clone()))?;
+
+        let mut state = lock.pool.state.borrow_mut();
+        state.pool.committed(uberblock, &mut allocator);
+        Ok(state.pool.tgx())
+    }
+}
+
+impl Drop for PoolLock {
+    fn drop(&mut self) {
+        let waiters = {
+            let mut state = self.pool.state.borrow_mut();
+            state.locked = false;
+            mem::replace(&mut state.waiters, Vec::new())
+        };
+
+        // the first one polled gets the lock
+        for task_id in waiters {
+            self.pool.handle.wake(task_id);
+        }
+    }
+}
+
+impl OpenTree {
+    pub fn pool(&self) -> &SharedPool {
+        &self.pool
+    }
+
+    pub fn name(&self) -> TreeName {
+        self.name
+    }
+}
+
+impl Drop for OpenTree {
+    fn drop(&mut self) {
+        let name = self.name;
+        self.pool.state.borrow_mut().open_trees.retain(|n| *n != name);
+    }
+}
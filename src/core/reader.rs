@@ -0,0 +1,113 @@
+use std::ops::Range;
+use super::*;
+use super::cow_btree::*;
+use super::directory::*;
+
+impl Pin {
+    pub fn new(pins: Pins, tgx: u64) -> Pin {
+        *pins.borrow_mut().entry(tgx).or_insert(0) += 1;
+        Pin {
+            pins,
+            tgx,
+        }
+    }
+
+    pub fn tgx(&self) -> u64 {
+        self.tgx
+    }
+}
+
+impl Clone for Pin {
+    fn clone(&self) -> Pin {
+        Pin::new(self.pins.clone(), self.tgx)
+    }
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        let mut pins = self.pins.borrow_mut();
+        let last = {
+            let count = pins.get_mut(&self.tgx).unwrap(); // inserted by Pin::new()
+            *count -= 1;
+            *count == 0
+        };
+        if last {
+            pins.remove(&self.tgx);
+        }
+    }
+}
+
+impl Reader {
+    pub fn new(handle: Handle, uberblock: Uberblock, pin: Pin) -> Reader {
+        Reader {
+            handle,
+            uberblock,
+            pin,
+        }
+    }
+
+    pub fn tgx(&self) -> u64 {
+        self.pin.tgx()
+    }
+
+    pub fn uberblock(&self) -> &Uberblock {
+        &self.uberblock
+    }
+
+    pub fn tree_root_pointer(&self) -> ObjectPointer {
+        self.uberblock.tree_root_pointer.clone()
+    }
+
+    pub fn directory_root_pointer(&self) -> ObjectPointer {
+        self.uberblock.directory_root_pointer.clone()
+    }
+
+    /// Returns the names of all the trees committed in the transaction group, sorted.
+    pub fn list_trees(&self) -> impl Future<Item=Vec<TreeName>, Error=failure::Error> {
+        list_trees(self.handle.clone(), self.directory_root_pointer())
+    }
+}
+
+impl<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static> Snapshot<K, V, B> {
+    /// Opens the tree `name` as committed in the transaction group of `reader`.
+    #[async]
+    pub fn open(reader: Reader, name: TreeName) -> Result<Snapshot<K, V, B>, failure::Error> {
+        let root_pointer = await!(lookup_tree::<K, V, B>(reader.handle.clone(), reader.directory_root_pointer(), name))?
+            .ok_or(format_err!("Tree {:?} does not exist in tgx {}", name.as_str(), reader.tgx()))?;
+
+        Ok(
+            Snapshot {
+                reader,
+                root_pointer,
+                _kv: PhantomData,
+            }
+        )
+    }
+
+    pub fn tgx(&self) -> u64 {
+        self.reader.tgx()
+    }
+
+    /// Returns the number of keys in the tree.
+    pub fn len(&self) -> u64 {
+        count(&self.root_pointer)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, key: K) -> impl Future<Item=Option<V>, Error=failure::Error> {
+        get::<K, V, B>(self.reader.handle.clone(), self.root_pointer.clone(), key)
+    }
+
+    pub fn contains(&self, key: K) -> impl Future<Item=bool, Error=failure::Error> {
+        self.get(key).map(|value| value.is_some())
+    }
+
+    /// Returns the key/value pairs whose key is in `range`, sorted by key.
+    pub fn range(&self, range: Range<K>) -> impl Future<Item=Vec<(K, V)>, Error=failure::Error> {
+        read_range::<K, V, B>(self.reader.handle.clone(), self.root_pointer.clone(), range)
+            .map(|entries| entries.into_iter().map(|e| (e.key, e.value)).collect())
+    }
+}
@@ -8,7 +8,9 @@ use ::backend::mem::*;
 use super::*;
 use super::util::*;
 use super::uberblock::*;
+use super::label::*;
 use super::cow_btree::*;
+use super::directory::*;
 
 use instrumentation::*;
 
@@ -22,6 +24,15 @@ proptest! {
         assert!(res.tgx == 9 + n as u64);
     }
 
+    #[test]
+    fn format_small_uberblock_ring(n in 0usize..20) {
+        let res = run_in_reactor_on_mem_backend(|handle| {
+            Box::new(format_small_uberblock_ring_async(handle.clone(), n))
+        }).unwrap();
+
+        assert!(res.tgx == 3 + n as u64);
+    }
+
     #[test]
     fn cow_btree_increasing(n in 0usize..100) {
         run_in_reactor_on_mem_backend(|handle| {
@@ -37,6 +48,209 @@ fn cow_btree_random() {
     }).unwrap();
 }
 
+#[test]
+fn label_unknown_incompat_features() {
+    let res = run_in_reactor_on_mem_backend(|handle| {
+        Box::new(label_incompatible_async(handle.clone(), FORMAT_VERSION, 1 << 63))
+    });
+
+    assert!(res.unwrap_err().to_string() == format!("Unsupported incompatible features: {:#x}", 1u64 << 63));
+}
+
+#[test]
+fn label_newer_version() {
+    let res = run_in_reactor_on_mem_backend(|handle| {
+        Box::new(label_incompatible_async(handle.clone(), FORMAT_VERSION + 1, 0))
+    });
+
+    assert!(res.unwrap_err().to_string() == format!("Unsupported on-disk format version. found: {}, supported: {}", FORMAT_VERSION + 1, FORMAT_VERSION));
+}
+
+#[test]
+fn label_older_version() {
+    let res = run_in_reactor_on_mem_backend(|handle| {
+        Box::new(label_incompatible_async(handle.clone(), FORMAT_VERSION - 1, 0))
+    });
+
+    assert!(res.unwrap_err().to_string() == format!("Unsupported on-disk format version. found: {}, supported: {}", FORMAT_VERSION - 1, FORMAT_VERSION));
+}
+
+#[test]
+fn label_regions_repair() {
+    let repaired = run_in_reactor_on_mem_backend(|handle| {
+        Box::new(label_regions_repair_async(handle.clone()))
+    }).unwrap();
+
+    // the label and all the uberblocks of the first region
+    assert!(repaired == 1 + DEFAULT_UBERBLOCK_RING_SIZE);
+}
+
+#[test]
+fn label_offsets_small_device() {
+    assert!(label_offsets(0).is_err());
+    assert!(label_offsets(BLOCK_SIZE as u64 - 1).is_err());
+    assert!(label_offsets(2 * BLOCK_SIZE as u64 - 1).is_err());
+    assert!(label_offsets(2 * BLOCK_SIZE as u64).unwrap() == [0, BLOCK_SIZE as u64]);
+    assert!(label_offsets(3 * BLOCK_SIZE as u64 + 1).unwrap() == [0, 2 * BLOCK_SIZE as u64]);
+}
+
+#[test]
+fn allocator_out_of_space() {
+    run_in_reactor_on_mem_backend(|handle| {
+        Box::new(allocator_out_of_space_async(handle.clone()))
+    }).unwrap();
+}
+
+#[test]
+fn pool_allocators_share_free_space() {
+    run_in_reactor_on_mem_backend(|handle| {
+        Box::new(pool_allocators_share_free_space_async(handle.clone()))
+    }).unwrap();
+}
+
+#[test]
+fn pool_open_older_tgx() {
+    run_in_reactor_on_mem_backend(|handle| {
+        Box::new(pool_open_older_tgx_async(handle.clone()))
+    }).unwrap();
+}
+
+#[test]
+fn pool_open_read_only() {
+    run_in_reactor_on_mem_backend(|handle| {
+        Box::new(pool_open_read_only_async(handle.clone()))
+    }).unwrap();
+}
+
+#[test]
+fn pool_open_active() {
+    run_in_reactor_on_mem_backend(|handle| {
+        Box::new(pool_open_active_async(handle.clone()))
+    }).unwrap();
+}
+
+#[test]
+fn object_pointer_birth_tgx() {
+    run_in_reactor_on_mem_backend(|handle| {
+        Box::new(object_pointer_birth_tgx_async(handle.clone()))
+    }).unwrap();
+}
+
+#[test]
+fn cow_btree_order_statistics() {
+    run_in_reactor_on_mem_backend(|handle| {
+        Box::new(cow_btree_order_statistics_async(handle.clone()))
+    }).unwrap();
+}
+
+#[test]
+fn pool_named_trees() {
+    run_in_reactor_on_mem_backend(|handle| {
+        Box::new(pool_named_trees_async(handle.clone()))
+    }).unwrap();
+}
+
+#[test]
+fn kv_store() {
+    run_in_reactor_on_mem_backend(|handle| {
+        Box::new(kv_store_async(handle.clone()))
+    }).unwrap();
+}
+
+#[test]
+fn kv_store_shared_pool() {
+    run_in_reactor_on_mem_backend(|handle| {
+        Box::new(kv_store_shared_pool_async(handle.clone()))
+    }).unwrap();
+}
+
+#[test]
+fn kv_store_birth_tgx() {
+    run_in_reactor_on_mem_backend(|handle| {
+        Box::new(kv_store_birth_tgx_async(handle.clone()))
+    }).unwrap();
+}
+
+#[test]
+fn client_mem_backend() {
+    let mut client = Client::<u64, u64>::open(ClientConfig::default()).unwrap();
+
+    for i in 0..20 {
+        assert!(client.put(i, 1000 + i).unwrap().is_none());
+    }
+    assert!(client.delete(4).unwrap() == Some(1004));
+    client.commit().unwrap();
+
+    assert!(client.len() == 19);
+    assert!(client.get(3).unwrap() == Some(1003));
+    assert!(!client.contains(4).unwrap());
+    assert!(client.range(3..6).unwrap() == vec![(3, 1003), (5, 1005)]);
+
+    client.close().unwrap();
+}
+
+#[test]
+fn cow_btree_conditional_writes() {
+    run_in_reactor_on_mem_backend(|handle| {
+        Box::new(cow_btree_conditional_writes_async(handle.clone()))
+    }).unwrap();
+}
+
+#[test]
+fn cow_btree_merge() {
+    run_in_reactor_on_mem_backend(|handle| {
+        Box::new(cow_btree_merge_async(handle.clone()))
+    }).unwrap();
+}
+
+#[test]
+fn client_merge_counters() {
+    let mut client = Client::<u64, u64>::open(ClientConfig::default()).unwrap();
+    assert!(client.merge(1, 1).is_err()); // nothing registered yet, the store is kept
+
+    client.set_merge_operator(|value, operand| value.map_or(0, |v| *v) + operand);
+    for i in 0..10 {
+        client.merge(i % 2, i).unwrap();
+    }
+    assert!(client.get(0).unwrap() == Some(0 + 2 + 4 + 6 + 8));
+    assert!(client.get(1).unwrap() == Some(1 + 3 + 5 + 7 + 9));
+}
+
+#[test]
+fn transaction_conflict() {
+    run_in_reactor_on_mem_backend(|handle| {
+        Box::new(transaction_conflict_async(handle.clone()))
+    }).unwrap();
+}
+
+#[test]
+fn transaction_concurrent_increments() {
+    run_in_reactor_on_mem_backend(|handle| {
+        Box::new(transaction_concurrent_increments_async(handle.clone()))
+    }).unwrap();
+}
+
+#[test]
+fn pool_reader() {
+    run_in_reactor_on_mem_backend(|handle| {
+        Box::new(pool_reader_async(handle.clone()))
+    }).unwrap();
+}
+
+#[test]
+fn kv_store_snapshot() {
+    run_in_reactor_on_mem_backend(|handle| {
+        Box::new(kv_store_snapshot_async(handle.clone()))
+    }).unwrap();
+}
+
+#[test]
+fn synced_kv_store() {
+    run_in_reactor_on_mem_backend(|handle| {
+        Box::new(synced_kv_store_async(handle.clone()))
+    }).unwrap();
+}
+
 #[bench]
 fn cow_btree_random_bench(b: &mut Bencher) {
     b.iter(|| {
@@ -49,11 +263,30 @@ fn cow_btree_random_bench(b: &mut Bencher) {
 #[async]
 fn format_read_and_write_uberblock_async(handle: Handle, n: usize) -> Result<Uberblock, failure::Error> {
     await!(format(handle.clone()))?;
+    let label = await!(read_label(handle.clone()))?;
+
+    for _ in 0..n {
+        let mut u = await!(find_latest_uberblock(handle.clone()))?;
+        u.tgx += 1;
+        await!(write_new_uberblock(handle.clone(), label.clone(), u))?;
+    }
+
+    await!(find_latest_uberblock(handle.clone()))
+}
+
+#[async]
+fn format_small_uberblock_ring_async(handle: Handle, n: usize) -> Result<Uberblock, failure::Error> {
+    await!(format_with_options(handle.clone(), FormatOptions {
+        uberblock_ring_size: 4,
+        uberblock_slot_size: 512,
+    }))?;
+    let label = await!(read_label(handle.clone()))?;
+    assert!(label.uberblock_ring_size() == 4);
 
     for _ in 0..n {
         let mut u = await!(find_latest_uberblock(handle.clone()))?;
         u.tgx += 1;
-        await!(write_new_uberblock(handle.clone(), u))?;
+        await!(write_new_uberblock(handle.clone(), label.clone(), u))?;
     }
 
     await!(find_latest_uberblock(handle.clone()))
@@ -63,17 +296,17 @@ fn format_read_and_write_uberblock_async(handle: Handle, n: usize) -> Result<Ube
 fn cow_btree_increasing_async(handle: Handle, n: usize) -> Result<(), failure::Error> {
     await!(format(handle.clone()))?;
     let uberblock = await!(find_latest_uberblock(handle.clone()))?;
-    let (mut op, mut free_space_offset) = (uberblock.tree_root_pointer, uberblock.free_space_offset);
+    let (mut op, mut allocator) = (uberblock.tree_root_pointer, Allocator::new(uberblock.free_space_offset, uberblock.tgx + 1));
 
     for i in (0..n) {
         let res = await!(insert_in_btree(
             handle.clone(),
             op.clone(),
-            free_space_offset,
+            allocator,
             LeafNodeEntry{key: i as u64, value: 1000+i as u64}
             ))?;
         op = res.0;
-        free_space_offset = res.1;
+        allocator = res.1;
     }
 
     let res = await!(read_btree(handle.clone(), op.clone()))?;
@@ -85,11 +318,794 @@ fn cow_btree_increasing_async(handle: Handle, n: usize) -> Result<(), failure::E
     Ok(())
 }
 
+#[async]
+fn label_incompatible_async(handle: Handle, version: u64, incompat_features: u64) -> Result<Uberblock, failure::Error> {
+    await!(format(handle.clone()))?;
+
+    // pretend the pool was created by another version, the other copies are not read
+    let mut label = await!(read_label(handle.clone()))?;
+    label.version = version;
+    label.incompat_features |= incompat_features;
+    await!(label.async_write_at(handle.clone(), label_offsets(label.device_size())?[0]))?;
+
+    await!(find_latest_uberblock(handle.clone()))
+}
+
+#[async]
+fn label_regions_repair_async(handle: Handle) -> Result<u64, failure::Error> {
+    await!(format(handle.clone()))?;
+    let label = await!(read_label(handle.clone()))?;
+
+    // destroy the start of the device
+    await!(handle.write(vec![0; label.data_start() as usize], 0))?;
+
+    // the pool can still be opened from the other region
+    let uberblock = await!(find_latest_uberblock(handle.clone()))?;
+    assert!(uberblock.tgx == DEFAULT_UBERBLOCK_RING_SIZE - 1);
+
+    let repaired = await!(repair_label_regions(handle.clone()))?;
+
+    // the first region is back and there is nothing left to repair
+    let mem = await!(handle.read(0, Label::SIZE as u64))?;
+    Label::from_bytes(&mut Cursor::new(&mem))?;
+    assert!(await!(repair_label_regions(handle.clone()))? == 0);
+
+    Ok(repaired)
+}
+
+#[async]
+fn allocator_out_of_space_async(handle: Handle) -> Result<(), failure::Error> {
+    await!(format(handle.clone()))?;
+    let uberblock = await!(find_latest_uberblock(handle.clone()))?;
+
+    // only 100 bytes left before the end of the data area
+    let data_end = uberblock.free_space_offset + 100;
+    let mut allocator = Allocator::with_free_extents(uberblock.free_space_offset, data_end, uberblock.tgx + 1, Vec::new());
+    assert!(await!(allocator.write(handle.clone(), vec![1; 60]))?.0 + 60 <= data_end);
+    let err = await!(allocator.write(handle.clone(), vec![2; 60])).unwrap_err();
+    assert!(err.to_string().contains("no space left on device"));
+    assert!(allocator.free_space_offset() == uberblock.free_space_offset + 60);
+
+    // what still fits can be written
+    await!(allocator.write(handle.clone(), vec![3; 40]))?;
+    assert!(allocator.free_space_offset() == data_end);
+
+    Ok(())
+}
+
+#[async]
+fn pool_allocators_share_free_space_async(handle: Handle) -> Result<(), failure::Error> {
+    await!(format(handle.clone()))?;
+    let mut pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+
+    // free some space by rewriting the tree until the dead objects are released
+    for i in 0..20 {
+        let (op, allocator, _) = await!(insert_in_btree::<u64, u64, ConstUsize2>(
+            handle.clone(),
+            pool.tree_root_pointer(),
+            pool.allocator(),
+            NodeEntry::new(i % 2, i),
+        ))?;
+        pool = await!(pool.commit(op, allocator))?.0;
+    }
+
+    // two allocators alive at the same time never write at the same place
+    let mut a = pool.allocator();
+    let mut b = pool.allocator();
+    let mut extents = Vec::new();
+    for _ in 0..10 {
+        let (offset, len, _) = await!(a.write(handle.clone(), vec![1; 100]))?;
+        extents.push((offset, len));
+        let (offset, len, _) = await!(b.write(handle.clone(), vec![2; 100]))?;
+        extents.push((offset, len));
+    }
+    extents.sort();
+    for w in extents.windows(2) {
+        assert!(w[0].0 + w[0].1 <= w[1].0);
+    }
+
+    // and the next allocator starts where they stopped
+    let free_space_offset = a.free_space_offset();
+    assert!(b.free_space_offset() == free_space_offset);
+    assert!(pool.allocator().free_space_offset() == free_space_offset);
+
+    Ok(())
+}
+
+#[async]
+fn pool_open_older_tgx_async(handle: Handle) -> Result<(), failure::Error> {
+    await!(format(handle.clone()))?;
+
+    // commit one key per transaction group
+    let mut pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+    for i in 0..3 {
+        let (op, allocator, _) = await!(insert_in_btree::<u64, u64, ConstUsize2>(
+            handle.clone(),
+            pool.tree_root_pointer(),
+            pool.allocator(),
+            NodeEntry::<u64, u64>::new(i, 1000 + i)
+            ))?;
+        pool = await!(pool.commit(op, allocator))?.0;
+    }
+    let latest_tgx = pool.tgx();
+    await!(pool.close())?;
+
+    let uberblocks = await!(Pool::list_uberblocks(handle.clone()))?;
+    assert!(uberblocks.last().unwrap().tgx() == latest_tgx);
+    let first_tgx = latest_tgx - 2;
+
+    // an older transaction group is read-only
+    let pool = await!(Pool::open(handle.clone(), OpenOptions {tgx: Some(first_tgx), ..OpenOptions::default()}))?;
+    assert!(pool.read_only());
+    let res = await!(read_btree::<u64, u64, ConstUsize2>(handle.clone(), pool.tree_root_pointer()))?;
+    assert!(res.len() == 1);
+    let allocator = pool.allocator();
+    let op = pool.tree_root_pointer();
+    assert!(await!(pool.commit(op, allocator)).is_err());
+
+    // the latest one is still there
+    let pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+    assert!(pool.tgx() == latest_tgx);
+    await!(pool.close())?;
+
+    // until we rewind
+    let pool = await!(Pool::open(handle.clone(), OpenOptions {tgx: Some(first_tgx), rewind: true, ..OpenOptions::default()}))?;
+    assert!(!pool.read_only());
+    await!(pool.close())?;
+    let pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+    assert!(pool.tgx() == first_tgx);
+
+    Ok(())
+}
+
+#[async]
+fn pool_open_read_only_async(handle: Handle) -> Result<(), failure::Error> {
+    await!(format(handle.clone()))?;
+
+    let pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+    let (op, allocator, _) = await!(insert_in_btree::<u64, u64, ConstUsize2>(
+        handle.clone(),
+        pool.tree_root_pointer(),
+        pool.allocator(),
+        NodeEntry::<u64, u64>::new(1, 1001)
+        ))?;
+    let (pool, _) = await!(pool.commit(op, allocator))?;
+    await!(pool.close())?;
+
+    let pool = await!(Pool::open(handle.clone(), OpenOptions {read_only: true, ..OpenOptions::default()}))?;
+    assert!(pool.read_only());
+    assert!(handle.is_read_only());
+
+    // neither the btree nor the uberblocks can be written
+    let res = await!(insert_in_btree::<u64, u64, ConstUsize2>(
+        handle.clone(),
+        pool.tree_root_pointer(),
+        pool.allocator(),
+        NodeEntry::<u64, u64>::new(1, 1)
+        ));
+    assert!(res.is_err());
+
+    let allocator = pool.allocator();
+    let op = pool.tree_root_pointer();
+    assert!(await!(pool.commit(op, allocator)).is_err());
+
+    // but reads still work
+    let pool = await!(Pool::open(handle.clone(), OpenOptions {read_only: true, ..OpenOptions::default()}))?;
+    let root = pool.tree_root_pointer();
+    assert!(await!(get::<u64, u64, ConstUsize2>(handle.clone(), root.clone(), 1))? == Some(1001));
+    assert!(await!(read_btree::<u64, u64, ConstUsize2>(handle.clone(), root))?.len() == 1);
+
+    Ok(())
+}
+
+#[async]
+fn pool_open_active_async(handle: Handle) -> Result<(), failure::Error> {
+    await!(format(handle.clone()))?;
+
+    let pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+    assert!(pool.label().state() == PoolState::Active);
+
+    // a second writer is refused, unless forced
+    assert!(await!(Pool::open(handle.clone(), OpenOptions::default())).is_err());
+    let pool = await!(Pool::open(handle.clone(), OpenOptions {force: true, ..OpenOptions::default()}))?;
+
+    // the commits are signed with our host id
+    let allocator = pool.allocator();
+    let op = pool.tree_root_pointer();
+    let (pool, _) = await!(pool.commit(op, allocator))?;
+    assert!(pool.uberblock().hostid() == hostid());
+
+    // once closed, it can be opened again
+    await!(pool.close())?;
+    let pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+    await!(pool.close())?;
+
+    Ok(())
+}
+
+#[async]
+fn object_pointer_birth_tgx_async(handle: Handle) -> Result<(), failure::Error> {
+    await!(format(handle.clone()))?;
+    let mut pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+
+    // a first transaction group builds a tree with several leaves
+    let (mut op, mut allocator) = (pool.tree_root_pointer(), pool.allocator());
+    for i in 0..20 {
+        let res = await!(insert_in_btree::<u64, u64, ConstUsize2>(handle.clone(), op, allocator, NodeEntry::<u64, u64>::new(i, i)))?;
+        op = res.0;
+        allocator = res.1;
+    }
+    pool = await!(pool.commit(op, allocator))?.0;
+    let first_tgx = pool.tgx();
+    assert!(pool.tree_root_pointer().birth_tgx() == first_tgx);
+
+    // a second one only rewrites the path to the last leaf
+    let (op, allocator, _) = await!(insert_in_btree::<u64, u64, ConstUsize2>(
+        handle.clone(),
+        pool.tree_root_pointer(),
+        pool.allocator(),
+        NodeEntry::<u64, u64>::new(100, 100)
+        ))?;
+    pool = await!(pool.commit(op, allocator))?.0;
+    let second_tgx = pool.tgx();
+    assert!(pool.tree_root_pointer().birth_tgx() == second_tgx);
+
+    match await!(pool.tree_root_pointer().async_read_object::<u64, u64, ConstUsize2>(handle.clone()))? {
+        AnyObject::InternalNode(node) => {
+            let births: Vec<u64> = node.entries.iter().map(|e| e.value.birth_tgx()).collect();
+            assert!(births.iter().all(|&b| b == first_tgx || b == second_tgx));
+            assert!(births.first() == Some(&first_tgx));
+            assert!(births.last() == Some(&second_tgx));
+        }
+        AnyObject::LeafNode(_) => panic!("the root should be an internal node"),
+    }
+
+    await!(pool.close())?;
+    Ok(())
+}
+
+#[async]
+fn cow_btree_order_statistics_async(handle: Handle) -> Result<(), failure::Error> {
+    await!(format(handle.clone()))?;
+    let uberblock = await!(find_latest_uberblock(handle.clone()))?;
+    let (mut op, mut allocator) = (uberblock.tree_root_pointer, Allocator::new(uberblock.free_space_offset, uberblock.tgx + 1));
+
+    // even keys from 0 to 198, in a scrambled order
+    for i in 0..100u64 {
+        let key = (i * 37 % 100) * 2;
+        let res = await!(insert_in_btree::<u64, u64, ConstUsize2>(handle.clone(), op, allocator, NodeEntry::<u64, u64>::new(key, key)))?;
+        op = res.0;
+        allocator = res.1;
+    }
+    assert!(count(&op) == 100);
+
+    for i in 0..100u64 {
+        assert!(await!(rank::<u64, u64, ConstUsize2>(handle.clone(), op.clone(), i * 2))? == i);
+        assert!(await!(rank::<u64, u64, ConstUsize2>(handle.clone(), op.clone(), i * 2 + 1))? == i + 1);
+        let entry = await!(nth::<u64, u64, ConstUsize2>(handle.clone(), op.clone(), i))?.unwrap();
+        assert!(entry.key == i * 2);
+    }
+    assert!(await!(nth::<u64, u64, ConstUsize2>(handle.clone(), op.clone(), 100))?.is_none());
+    assert!(await!(count_range::<u64, u64, ConstUsize2>(handle.clone(), op.clone(), 10..20))? == 5);
+    assert!(await!(count_range::<u64, u64, ConstUsize2>(handle.clone(), op.clone(), 20..10))? == 0);
+
+    // the counts follow the merges of remove
+    for i in 0..50u64 {
+        let res = await!(remove::<u64, u64, ConstUsize2>(handle.clone(), op, allocator, i * 4))?;
+        op = res.0;
+        allocator = res.1;
+    }
+    assert!(count(&op) == 50);
+    assert!(await!(rank::<u64, u64, ConstUsize2>(handle.clone(), op.clone(), 100))? == 25);
+    let entry = await!(nth::<u64, u64, ConstUsize2>(handle.clone(), op.clone(), 0))?.unwrap();
+    assert!(entry.key == 2);
+
+    Ok(())
+}
+
+#[async]
+fn pool_named_trees_async(handle: Handle) -> Result<(), failure::Error> {
+    await!(format(handle.clone()))?;
+    let pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+    let (a, b, c) = (TreeName::new("a")?, TreeName::new("b")?, TreeName::new("c")?);
+
+    // two trees with different types, committed in the same transaction group
+    let (directory, allocator) = (pool.directory_root_pointer(), pool.allocator());
+    let (directory, allocator) = await!(create_tree::<u64, u64, ConstUsize2>(handle.clone(), directory, allocator, a))?;
+    let (directory, allocator) = await!(create_tree::<u64, ObjectPointer, ConstUsize3>(handle.clone(), directory, allocator, b))?;
+    assert!(await!(create_tree::<u64, u64, ConstUsize2>(handle.clone(), directory.clone(), pool.allocator(), a)).is_err());
+
+    let root = await!(lookup_tree::<u64, u64, ConstUsize2>(handle.clone(), directory.clone(), a))?.unwrap();
+    let (root, allocator, _) = await!(insert_in_btree::<u64, u64, ConstUsize2>(handle.clone(), root, allocator, NodeEntry::new(1, 1001)))?;
+    let (directory, allocator) = await!(update_tree(handle.clone(), directory, allocator, a, root))?;
+
+    let tree_root_pointer = pool.tree_root_pointer();
+    let (pool, _) = await!(pool.commit_trees(tree_root_pointer, directory, allocator))?;
+    await!(pool.close())?;
+
+    // they are found back after reopening
+    let pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+    let directory = pool.directory_root_pointer();
+    assert!(await!(list_trees(handle.clone(), directory.clone()))? == vec![a, b]);
+    let root = await!(lookup_tree::<u64, u64, ConstUsize2>(handle.clone(), directory.clone(), a))?.unwrap();
+    assert!(await!(get::<u64, u64, ConstUsize2>(handle.clone(), root, 1))? == Some(1001));
+
+    // the types and the fanout of a tree are checked when it is looked up
+    assert!(await!(lookup_tree::<u64, ObjectPointer, ConstUsize3>(handle.clone(), directory.clone(), b))?.is_some());
+    let err = await!(lookup_tree::<u64, ObjectPointer, ConstUsize2>(handle.clone(), directory.clone(), b)).unwrap_err();
+    assert!(err.to_string().contains("does not have the expected types"));
+    assert!(await!(lookup_tree::<u64, u64, ConstUsize3>(handle.clone(), directory.clone(), b)).is_err());
+    assert!(await!(lookup_tree::<u64, u64, ConstUsize2>(handle.clone(), directory.clone(), c))?.is_none());
+
+    // rename and destroy
+    let allocator = pool.allocator();
+    let (directory, allocator) = await!(rename_tree(handle.clone(), directory, allocator, a, c))?;
+    let (directory, allocator) = await!(destroy_tree(handle.clone(), directory, allocator, b))?;
+    assert!(await!(destroy_tree(handle.clone(), directory.clone(), pool.allocator(), b)).is_err());
+
+    let tree_root_pointer = pool.tree_root_pointer();
+    let (pool, _) = await!(pool.commit_trees(tree_root_pointer, directory, allocator))?;
+    let directory = pool.directory_root_pointer();
+    assert!(await!(list_trees(handle.clone(), directory.clone()))? == vec![c]);
+    let root = await!(lookup_tree::<u64, u64, ConstUsize2>(handle.clone(), directory.clone(), c))?.unwrap();
+    assert!(await!(get::<u64, u64, ConstUsize2>(handle.clone(), root, 1))? == Some(1001));
+
+    await!(pool.close())?;
+    Ok(())
+}
+
+#[async]
+fn kv_store_async(handle: Handle) -> Result<(), failure::Error> {
+    await!(format(handle.clone()))?;
+    let pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+    let mut store = await!(KvStore::<u64, u64>::open(SharedPool::new(pool), TreeName::new("kv")?))?;
+    assert!(store.is_empty());
+
+    for i in 0..20 {
+        await!(store.put(i, 1000 + i))?;
+    }
+    assert!(await!(store.put(3, 3))? == Some(1003));
+    assert!(await!(store.delete(4))? == Some(1004));
+
+    assert!(store.len() == 19);
+    assert!(await!(store.get(3))? == Some(3));
+    assert!(!await!(store.contains(4))?);
+    assert!(await!(store.range(2..6))? == vec![(2, 1002), (3, 3), (5, 1005)]);
+
+    await!(store.commit())?;
+    await!(store.put(100, 100))?; // never committed
+    await!(store.close())?;
+
+    // only the committed modifications are found back
+    let pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+    let store = await!(KvStore::<u64, u64>::open(SharedPool::new(pool), TreeName::new("kv")?))?;
+    assert!(store.len() == 19);
+    assert!(await!(store.get(3))? == Some(3));
+    assert!(await!(store.get(100))?.is_none());
+    await!(store.close())?;
+
+    // the tree can't be opened with other types
+    let pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+    let res = await!(KvStore::<u64, ObjectPointer>::open(SharedPool::new(pool), TreeName::new("kv")?));
+    assert!(res.unwrap_err().to_string().contains("does not have the expected types"));
+
+    Ok(())
+}
+
+#[async]
+fn kv_store_shared_pool_async(handle: Handle) -> Result<(), failure::Error> {
+    await!(format(handle.clone()))?;
+    let pool = SharedPool::new(await!(Pool::open(handle.clone(), OpenOptions::default()))?);
+    let mut a = await!(KvStore::<u64, u64>::open(pool.clone(), TreeName::new("a")?))?;
+    let mut b = await!(KvStore::<u64, u64, ConstUsize3>::open(pool.clone(), TreeName::new("b")?))?;
+
+    // a tree is modified by a single store
+    let res = await!(KvStore::<u64, u64>::open(pool.clone(), TreeName::new("a")?));
+    assert!(res.unwrap_err().to_string().contains("is already open"));
+
+    // each commit keeps the trees committed by the other store
+    await!(a.put(1, 1))?;
+    await!(b.put(2, 2))?;
+    await!(a.commit())?;
+    await!(b.commit())?;
+    await!(a.put(3, 3))?;
+    await!(a.commit())?;
+    assert!(pool.tgx() == a.pool().tgx());
+
+    // until it is dropped
+    drop(b);
+    let b = await!(KvStore::<u64, u64, ConstUsize3>::open(pool.clone(), TreeName::new("b")?))?;
+    assert!(await!(b.range(0..10))? == vec![(2, 2)]);
+    drop(b);
+    await!(a.close())?;
+
+    let pool = SharedPool::new(await!(Pool::open(handle.clone(), OpenOptions::default()))?);
+    let mut a = await!(KvStore::<u64, u64>::open(pool.clone(), TreeName::new("a")?))?;
+    let b = await!(KvStore::<u64, u64, ConstUsize3>::open(pool.clone(), TreeName::new("b")?))?;
+    assert!(await!(a.range(0..10))? == vec![(1, 1), (3, 3)]);
+    assert!(await!(b.range(0..10))? == vec![(2, 2)]);
+    drop(b);
+
+    // the fanout is part of the types of the tree
+    let res = await!(KvStore::<u64, u64>::open(pool.clone(), TreeName::new("b")?));
+    assert!(res.unwrap_err().to_string().contains("does not have the expected types"));
+
+    await!(pool.close())?;
+    assert!(await!(a.commit()).is_err());
+    Ok(())
+}
+
+#[async]
+fn kv_store_birth_tgx_async(handle: Handle) -> Result<(), failure::Error> {
+    await!(format(handle.clone()))?;
+    let pool = SharedPool::new(await!(Pool::open(handle.clone(), OpenOptions::default()))?);
+    let mut a = await!(KvStore::<u64, u64>::open(pool.clone(), TreeName::new("a")?))?;
+    let mut b = await!(KvStore::<u64, u64>::open(pool.clone(), TreeName::new("b")?))?;
+
+    // both write in the next transaction group, but b commits it first
+    for i in 0..20 {
+        await!(a.put(i, i))?;
+        await!(b.put(i, i))?;
+    }
+    await!(b.commit())?;
+    let b_tgx = pool.tgx();
+    await!(a.commit())?;
+    let a_tgx = pool.tgx();
+    assert!(a_tgx == b_tgx + 1);
+
+    // the objects written by a before b committed are born when a committed them
+    let directory = pool.directory_root_pointer();
+    for &(name, tgx) in &[("a", a_tgx), ("b", b_tgx)] {
+        let root = await!(lookup_tree::<u64, u64, ConstUsize2>(handle.clone(), directory.clone(), TreeName::new(name)?))?.unwrap();
+        assert!(root.birth_tgx() == tgx);
+        for i in 0..20 {
+            let (_, leaf) = await!(get_with_leaf::<u64, u64, ConstUsize2>(handle.clone(), root.clone(), i))?;
+            assert!(leaf.birth_tgx() == tgx);
+        }
+    }
+    assert!(await!(a.range(0..100))? == (0..20).map(|i| (i, i)).collect::<Vec<_>>());
+
+    // and the next ones in the next transaction group
+    await!(a.put(100, 100))?;
+    await!(a.commit())?;
+    let root = await!(lookup_tree::<u64, u64, ConstUsize2>(handle.clone(), pool.directory_root_pointer(), TreeName::new("a")?))?.unwrap();
+    assert!(root.birth_tgx() == a_tgx + 1);
+
+    drop(b);
+    await!(a.close())?;
+    Ok(())
+}
+
+#[async]
+fn cow_btree_conditional_writes_async(handle: Handle) -> Result<(), failure::Error> {
+    await!(format(handle.clone()))?;
+    let uberblock = await!(find_latest_uberblock(handle.clone()))?;
+    let (mut op, mut allocator) = (uberblock.tree_root_pointer, Allocator::new(uberblock.free_space_offset, uberblock.tgx + 1));
+
+    // nothing happens on an empty tree
+    let (op_, allocator_, swapped) = await!(compare_and_swap::<u64, u64, ConstUsize2>(handle.clone(), op.clone(), allocator, 3, 1003, 3))?;
+    assert!(!swapped);
+    assert!(op_.offset == op.offset);
+    assert!(allocator_.written_bytes() == 0);
+    op = op_;
+    allocator = allocator_;
+
+    for i in 0..20 {
+        let res = await!(insert_in_btree::<u64, u64, ConstUsize2>(handle.clone(), op, allocator, NodeEntry::new(i, 1000 + i)))?;
+        op = res.0;
+        allocator = res.1;
+    }
+
+    // the failed ones leave the tree as it is, without writing or freeing anything
+    let (root_offset, written_bytes) = (op.offset, allocator.written_bytes());
+    let (op, allocator, inserted) = await!(put_if_absent::<u64, u64, ConstUsize2>(handle.clone(), op, allocator, 5, 5))?;
+    assert!(!inserted);
+    let (op, allocator, swapped) = await!(compare_and_swap::<u64, u64, ConstUsize2>(handle.clone(), op, allocator, 3, 0, 3))?;
+    assert!(!swapped);
+    let (op, allocator, swapped) = await!(compare_and_swap::<u64, u64, ConstUsize2>(handle.clone(), op, allocator, 50, 0, 50))?;
+    assert!(!swapped);
+    let (op, allocator, removed) = await!(delete_if::<u64, u64, ConstUsize2>(handle.clone(), op, allocator, 7, 0))?;
+    assert!(!removed);
+    let (op, mut allocator, removed) = await!(delete_if::<u64, u64, ConstUsize2>(handle.clone(), op, allocator, 50, 0))?;
+    assert!(!removed);
+    assert!(op.offset == root_offset);
+    assert!(allocator.written_bytes() == written_bytes);
+    assert!(allocator.take_freed().is_empty());
+
+    let (op, allocator, inserted) = await!(put_if_absent::<u64, u64, ConstUsize2>(handle.clone(), op, allocator, 100, 100))?;
+    assert!(inserted);
+    let (op, allocator, swapped) = await!(compare_and_swap::<u64, u64, ConstUsize2>(handle.clone(), op, allocator, 3, 1003, 3))?;
+    assert!(swapped);
+    let (op, _, removed) = await!(delete_if::<u64, u64, ConstUsize2>(handle.clone(), op, allocator, 7, 1007))?;
+    assert!(removed);
+
+    assert!(count(&op) == 20);
+    assert!(await!(get::<u64, u64, ConstUsize2>(handle.clone(), op.clone(), 3))? == Some(3));
+    assert!(await!(get::<u64, u64, ConstUsize2>(handle.clone(), op.clone(), 5))? == Some(1005));
+    assert!(await!(get::<u64, u64, ConstUsize2>(handle.clone(), op.clone(), 7))?.is_none());
+    assert!(await!(get::<u64, u64, ConstUsize2>(handle.clone(), op.clone(), 50))?.is_none());
+
+    Ok(())
+}
+
+fn add(value: Option<&u64>, operand: u64) -> u64 {
+    value.map_or(0, |v| *v) + operand
+}
+
+#[async]
+fn cow_btree_merge_async(handle: Handle) -> Result<(), failure::Error> {
+    await!(format(handle.clone()))?;
+    let uberblock = await!(find_latest_uberblock(handle.clone()))?;
+    let (mut op, mut allocator) = (uberblock.tree_root_pointer, Allocator::new(uberblock.free_space_offset, uberblock.tgx + 1));
+
+    // 30 counters incremented 10 times each
+    for i in 0..300u64 {
+        let res = await!(merge_in_btree::<u64, u64, ConstUsize2>(handle.clone(), op, allocator, i % 30, 1, add))?;
+        op = res.0;
+        allocator = res.1;
+    }
+
+    assert!(count(&op) == 30);
+    let entries = await!(read_btree::<u64, u64, ConstUsize2>(handle.clone(), op))?;
+    assert!(entries.iter().all(|e| e.value == 10));
+
+    Ok(())
+}
+
+#[async]
+fn transaction_conflict_async(handle: Handle) -> Result<(), failure::Error> {
+    await!(format(handle.clone()))?;
+    let pool = SharedPool::new(await!(Pool::open(handle.clone(), OpenOptions::default()))?);
+    let tree = await!(SharedTree::<u64, u64>::open(pool.clone(), TreeName::new("tx")?))?;
+    assert!(await!(KvStore::<u64, u64>::open(pool.clone(), TreeName::new("tx")?)).is_err());
+
+    // tx1 reads a key which tx2 modifies before tx1 commits
+    let (mut tx1, value) = await!(tree.begin().get(1))?;
+    assert!(value.is_none());
+    let mut tx2 = tree.begin();
+    tx2.put(1, 10);
+    await!(tx2.commit())?;
+
+    tx1.put(2, 20);
+    let err = await!(tx1.commit()).err().unwrap();
+    assert!(err.downcast_ref::<TransactionConflict>().is_some());
+
+    // a new transaction sees the committed state, and its own writes
+    let (mut tx3, value) = await!(tree.begin().get(1))?;
+    assert!(value == Some(10));
+    tx3.delete(1);
+    let (tx3, value) = await!(tx3.get(1))?;
+    assert!(value.is_none());
+    await!(tx3.commit())?;
+
+    let (_, value) = await!(tree.begin().get(1))?;
+    assert!(value.is_none());
+
+    // transactions which read nothing wait for each other instead of conflicting
+    let commits: Vec<_> = (0..3).map(|i| {
+        let mut tx = tree.begin();
+        tx.put(i, i);
+        tx.commit()
+    }).collect();
+    let mut tgxs = await!(::futures::future::join_all(commits))?;
+    tgxs.dedup();
+    assert!(tgxs.len() == 3);
+    let (_, value) = await!(tree.begin().get(2))?;
+    assert!(value == Some(2));
+    await!(tree.close())?;
+
+    // the transactions are committed in the directory
+    let pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+    let store = await!(KvStore::<u64, u64>::open(SharedPool::new(pool), TreeName::new("tx")?))?;
+    assert!(await!(store.range(0..10))? == vec![(0, 0), (1, 1), (2, 2)]);
+    await!(store.close())?;
+
+    Ok(())
+}
+
+#[async]
+fn increment_in_transaction(tree: SharedTree<u64, u64>, key: u64) -> Result<u64, failure::Error> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+
+        let (mut tx, value) = await!(tree.begin().get(key))?;
+        tx.put(key, value.unwrap_or(0) + 1);
+
+        match await!(tx.commit()) {
+            Ok(_) => return Ok(attempts),
+            Err(e) => if e.downcast_ref::<TransactionConflict>().is_none() {
+                return Err(e);
+            }
+        }
+    }
+}
+
+#[async]
+fn transaction_concurrent_increments_async(handle: Handle) -> Result<(), failure::Error> {
+    await!(format(handle.clone()))?;
+    let pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+    let tree = await!(SharedTree::<u64, u64>::open(SharedPool::new(pool), TreeName::new("tx")?))?;
+
+    // the transactions interleave at each I/O
+    let increments: Vec<_> = (0..5).map(|_| increment_in_transaction(tree.clone(), 42)).collect();
+    let attempts = await!(::futures::future::join_all(increments))?;
+    assert!(attempts.iter().sum::<u64>() > 5); // some had to retry
+
+    let (_, value) = await!(tree.begin().get(42))?;
+    assert!(value == Some(5));
+
+    await!(tree.close())?;
+    Ok(())
+}
+
+#[async]
+fn pool_reader_async(handle: Handle) -> Result<(), failure::Error> {
+    // a small ring so that the freed objects are reused quickly
+    await!(format_with_options(handle.clone(), FormatOptions {
+        uberblock_ring_size: 2,
+        ..FormatOptions::default()
+    }))?;
+    let mut pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+
+    let (mut op, mut allocator) = (pool.tree_root_pointer(), pool.allocator());
+    for i in 0..50 {
+        let res = await!(insert_in_btree::<u64, u64, ConstUsize2>(handle.clone(), op, allocator, NodeEntry::new(i, i)))?;
+        op = res.0;
+        allocator = res.1;
+    }
+    pool = await!(pool.commit(op, allocator))?.0;
+
+    let reader = pool.reader();
+    assert!(reader.tgx() == pool.tgx());
+    let expected: Vec<(u64, u64)> = (0..50).map(|i| (i, i)).collect();
+
+    // rewrite all the keys in each transaction group, the reader keeps seeing the old ones
+    for round in 1..6 {
+        let (mut op, mut allocator) = (pool.tree_root_pointer(), pool.allocator());
+        for i in 0..50 {
+            let res = await!(insert_in_btree::<u64, u64, ConstUsize2>(handle.clone(), op, allocator, NodeEntry::new(i, round * 100 + i)))?;
+            op = res.0;
+            allocator = res.1;
+        }
+        pool = await!(pool.commit(op, allocator))?.0;
+
+        let entries = await!(read_btree::<u64, u64, ConstUsize2>(handle.clone(), reader.tree_root_pointer()))?;
+        assert!(entries.into_iter().map(|e| (e.key, e.value)).collect::<Vec<_>>() == expected);
+    }
+    assert!(pool.deferred_free_space() > 0);
+
+    // once the reader is gone and the ring has moved on, everything freed can be reused
+    drop(reader);
+    for _ in 0..2 {
+        let (op, allocator) = (pool.tree_root_pointer(), pool.allocator());
+        pool = await!(pool.commit(op, allocator))?.0;
+    }
+    assert!(pool.deferred_free_space() == 0);
+
+    let free_space_offset = pool.uberblock().free_space_offset;
+    let (mut op, mut allocator) = (pool.tree_root_pointer(), pool.allocator());
+    for i in 0..50 {
+        let res = await!(insert_in_btree::<u64, u64, ConstUsize2>(handle.clone(), op, allocator, NodeEntry::new(i, i)))?;
+        op = res.0;
+        allocator = res.1;
+    }
+    pool = await!(pool.commit(op, allocator))?.0;
+    assert!(pool.uberblock().free_space_offset == free_space_offset);
+
+    let entries = await!(read_btree::<u64, u64, ConstUsize2>(handle.clone(), pool.tree_root_pointer()))?;
+    assert!(entries.into_iter().map(|e| (e.key, e.value)).collect::<Vec<_>>() == expected);
+
+    await!(pool.close())?;
+    Ok(())
+}
+
+#[async]
+fn kv_store_snapshot_async(handle: Handle) -> Result<(), failure::Error> {
+    await!(format(handle.clone()))?;
+    let pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+    let mut store = await!(KvStore::<u64, u64>::open(SharedPool::new(pool), TreeName::new("kv")?))?;
+
+    // the tree only exists in the pool once committed
+    assert!(await!(store.snapshot()).is_err());
+
+    await!(store.put(1, 1))?;
+    await!(store.commit())?;
+    let snapshot = await!(store.snapshot())?;
+
+    await!(store.put(1, 2))?;
+    await!(store.put(2, 2))?;
+    await!(store.commit())?;
+
+    assert!(snapshot.tgx() + 1 == store.pool().tgx());
+    assert!(snapshot.len() == 1);
+    assert!(await!(snapshot.get(1))? == Some(1));
+    assert!(await!(snapshot.range(0..10))? == vec![(1, 1)]);
+    assert!(await!(store.range(0..10))? == vec![(1, 2), (2, 2)]);
+
+    drop(snapshot);
+    await!(store.close())?;
+    Ok(())
+}
+
+#[async]
+fn synced_kv_store_async(handle: Handle) -> Result<(), failure::Error> {
+    await!(format(handle.clone()))?;
+
+    // committed by the background task, once
+    let pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+    let store = await!(KvStore::<u64, u64>::open(SharedPool::new(pool), TreeName::new("kv")?))?;
+    let synced = SyncedKvStore::open(store, SyncOptions {
+        interval: Duration::from_millis(10),
+        ..SyncOptions::default()
+    })?;
+    let tgx = synced.tgx();
+    await!(synced.clone().put(1, 1))?;
+    for _ in 0..100 {
+        if synced.tgx() != tgx {
+            break;
+        }
+        await!(handle.sleep(Duration::from_millis(10)))?;
+    }
+    assert!(synced.tgx() == tgx + 1);
+    await!(handle.sleep(Duration::from_millis(50)))?;
+    assert!(synced.tgx() == tgx + 1); // nothing to commit after that
+    await!(synced.close())?;
+
+    // committed by the writers, or explicitly
+    let pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+    let store = await!(KvStore::<u64, u64>::open(SharedPool::new(pool), TreeName::new("kv")?))?;
+    let synced = SyncedKvStore::open(store, SyncOptions {
+        interval: Duration::from_secs(3600),
+        dirty_bytes: 1,
+    })?;
+    let tgx = synced.tgx();
+    assert!(await!(synced.clone().get(1))? == Some(1));
+    assert!(await!(synced.clone().merge(1, 1)).is_err()); // no merge operator, the store is kept
+    await!(synced.clone().put(2, 2))?;
+    assert!(synced.tgx() == tgx + 1);
+    assert!(await!(synced.clone().delete(1))? == Some(1));
+    assert!(synced.tgx() == tgx + 2);
+    await!(synced.clone().sync())?; // nothing to commit
+    assert!(synced.tgx() == tgx + 2);
+    await!(synced.clone().close())?;
+    assert!(await!(synced.get(2)).is_err());
+
+    let pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+    let store = await!(KvStore::<u64, u64>::open(SharedPool::new(pool), TreeName::new("kv")?))?;
+    let synced = SyncedKvStore::open(store, SyncOptions {
+        interval: Duration::from_secs(3600),
+        ..SyncOptions::default()
+    })?;
+    let tgx = synced.tgx();
+    assert!(await!(synced.clone().get(1))?.is_none());
+    assert!(await!(synced.clone().get(2))? == Some(2));
+    await!(synced.clone().put(3, 3))?;
+    await!(synced.clone().sync())?;
+    assert!(synced.tgx() == tgx + 1);
+    await!(synced.close())?;
+
+    // concurrent writers wait for each other
+    let pool = await!(Pool::open(handle.clone(), OpenOptions::default()))?;
+    let store = await!(KvStore::<u64, u64>::open(SharedPool::new(pool), TreeName::new("kv")?))?;
+    let synced = SyncedKvStore::open(store, SyncOptions::default())?;
+    let writers: Vec<_> = (10..20)
+        .map(|i| handle.spawn(synced.clone().put(i, i)))
+        .collect();
+    await!(future::join_all(writers)).map_err(|e| format_err!("{}", e))?;
+    for i in 10..20 {
+        assert!(await!(synced.clone().get(i))? == Some(i));
+    }
+    await!(synced.close())?;
+
+    Ok(())
+}
+
 #[async]
 fn cow_btree_random_async(handle: Handle) -> Result<(), failure::Error> {
     await!(format(handle.clone()))?;
     let uberblock = await!(find_latest_uberblock(handle.clone()))?;
-    let (mut op, mut free_space_offset) = (uberblock.tree_root_pointer, uberblock.free_space_offset);
+    let (mut op, mut allocator) = (uberblock.tree_root_pointer, Allocator::new(uberblock.free_space_offset, uberblock.tgx + 1));
 
     // 0 to 999 shuffled
     let v:Vec<u64> = vec![
@@ -149,11 +1165,11 @@ fn cow_btree_random_async(handle: Handle) -> Result<(), failure::Error> {
         let res = await!(insert_in_btree(
             handle.clone(),
             op.clone(),
-            free_space_offset,
+            allocator,
             LeafNodeEntry{key: i as u64, value: 1000+i as u64}
             ))?;
         op = res.0;
-        free_space_offset = res.1;
+        allocator = res.1;
     }
 
     let res = await!(read_btree(handle.clone(), op.clone()))?;
@@ -0,0 +1,163 @@
+use super::*;
+use super::cow_btree::*;
+use super::directory::*;
+
+impl fmt::Display for TransactionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Transaction conflict: a key it read has been modified")
+    }
+}
+
+impl failure::Fail for TransactionConflict {}
+
+impl<K: Serializable + Ord + Copy, V: Serializable, B: ConstUsize> Clone for SharedTree<K, V, B> {
+    fn clone(&self) -> SharedTree<K, V, B> {
+        SharedTree {
+            handle: self.handle.clone(),
+            state: self.state.clone(),
+            _kv: PhantomData,
+        }
+    }
+}
+
+impl<K: Serializable + Ord + Copy + 'static, V: Serializable + Clone + 'static, B: ConstUsize + 'static> SharedTree<K, V, B> {
+    /// Opens the tree `name` of `pool` for transactions.
+    ///
+    /// The tree is created in a new transaction group if it does not exist yet.
+    /// Fails if it exists with other types or another fanout, or if a store has it open.
+    #[async]
+    pub fn open(pool: SharedPool, name: TreeName) -> Result<SharedTree<K, V, B>, failure::Error> {
+        let tree = pool.open_tree(name)?;
+        let handle = pool.handle();
+
+        let root_pointer = match await!(lookup_tree::<K, V, B>(handle.clone(), pool.directory_root_pointer(), name))? {
+            Some(root_pointer) => root_pointer,
+            None => {
+                if pool.read_only() {
+                    return Err(format_err!("Tree {:?} does not exist and the pool is read-only", name.as_str()));
+                }
+
+                // like the transactions, so that its root is born in the transaction group which records it
+                let lock = await!(pool.lock())?;
+                let (root_pointer, allocator) = await!(create_btree::<K, V, B>(handle.clone(), pool.allocator()))?;
+                let (directory_root_pointer, allocator) = await!(put_tree::<K, V, B>(
+                    handle.clone(), pool.directory_root_pointer(), allocator, name, root_pointer.clone()))?;
+                await!(lock.commit_trees(pool.tree_root_pointer(), directory_root_pointer, allocator))?;
+                root_pointer
+            }
+        };
+
+        Ok(
+            SharedTree {
+                handle,
+                state: Rc::new(RefCell::new(SharedTreeState {
+                    tree,
+                    root_pointer,
+                })),
+                _kv: PhantomData,
+            }
+        )
+    }
+
+    /// Starts a transaction reading from the latest committed tree.
+    pub fn begin(&self) -> Transaction<K, V, B> {
+        let state = self.state.borrow();
+        Transaction {
+            tree: self.clone(),
+            snapshot: state.root_pointer.clone(),
+            _pin: state.tree.pool.pin(),
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Closes the pool once the transaction committing, if any, is done.
+    #[async]
+    pub fn close(self) -> Result<(), failure::Error> {
+        let pool = self.state.borrow().tree.pool.clone();
+        await!(pool.close())
+    }
+}
+
+impl<K: Serializable + Ord + Copy + 'static, V: Serializable + Clone + 'static, B: ConstUsize + 'static> Transaction<K, V, B> {
+    /// Returns the value of `key` as written by this transaction, or as found in its snapshot.
+    #[async]
+    pub fn get(self, key: K) -> Result<(Transaction<K, V, B>, Option<V>), failure::Error> {
+        let mut tx = self;
+
+        let written = tx.writes.get(&key).cloned();
+        if let Some(value) = written {
+            return Ok((tx, value));
+        }
+
+        let (value, leaf) = await!(get_with_leaf::<K, V, B>(tx.tree.handle.clone(), tx.snapshot.clone(), key))?;
+        tx.reads.insert(key, leaf);
+
+        Ok((tx, value))
+    }
+
+    pub fn put(&mut self, key: K, value: V) {
+        self.writes.insert(key, Some(value));
+    }
+
+    pub fn delete(&mut self, key: K) {
+        self.writes.insert(key, None);
+    }
+
+    /// Applies the writes in a new transaction group and returns its `tgx`.
+    ///
+    /// Waits for the transaction committing, if any, then fails with `TransactionConflict`
+    /// if a key read by the transaction is now in a different leaf than in the snapshot,
+    /// which happens when it or one of its neighbors has been modified.
+    #[async]
+    pub fn commit(self) -> Result<u64, failure::Error> {
+        let mut tx = self;
+        let handle = tx.tree.handle.clone();
+        let (pool, name) = {
+            let state = tx.tree.state.borrow();
+            (state.tree.pool.clone(), state.tree.name)
+        };
+
+        if pool.read_only() {
+            return Err(format_err!("Cannot commit: the pool is opened read-only"));
+        }
+
+        // other transactions can't commit until the lock is dropped, on success or on error
+        let lock = await!(pool.lock())?;
+
+        // the leaves are never rewritten in place, so an unchanged leaf means an unchanged key
+        let mut root_pointer = tx.tree.state.borrow().root_pointer.clone();
+        let reads = mem::replace(&mut tx.reads, BTreeMap::new());
+        for (key, leaf) in reads {
+            let (_, current_leaf) = await!(get_with_leaf::<K, V, B>(handle.clone(), root_pointer.clone(), key))?;
+            if (current_leaf.offset, current_leaf.birth_tgx) != (leaf.offset, leaf.birth_tgx) {
+                return Err(TransactionConflict.into());
+            }
+        }
+
+        let mut allocator = pool.allocator();
+        let writes = mem::replace(&mut tx.writes, BTreeMap::new());
+        for (key, value) in writes {
+            match value {
+                Some(value) => {
+                    let res = await!(insert_in_btree::<K, V, B>(handle.clone(), root_pointer, allocator, NodeEntry::new(key, value)))?;
+                    root_pointer = res.0;
+                    allocator = res.1;
+                }
+                None => {
+                    let res = await!(remove::<K, V, B>(handle.clone(), root_pointer, allocator, key))?;
+                    root_pointer = res.0;
+                    allocator = res.1;
+                }
+            }
+        }
+
+        let (directory_root_pointer, allocator) = await!(put_tree::<K, V, B>(
+            handle.clone(), pool.directory_root_pointer(), allocator, name, root_pointer.clone()))?;
+        let tgx = await!(lock.commit_trees(pool.tree_root_pointer(), directory_root_pointer, allocator))?;
+
+        // the next transactions read from it
+        tx.tree.state.borrow_mut().root_pointer = root_pointer;
+        Ok(tgx)
+    }
+}
@@ -0,0 +1,245 @@
+use super::*;
+
+// Like the txg_sync thread of ZFS: a task of the reactor commits the open transaction group
+// of the store every `SyncOptions::interval`, the writers commit it themselves once
+// `SyncOptions::dirty_bytes` have been written.
+
+impl Default for SyncOptions {
+    fn default() -> SyncOptions {
+        SyncOptions {
+            interval: Duration::from_secs(DEFAULT_SYNC_INTERVAL_SECS),
+            dirty_bytes: DEFAULT_SYNC_DIRTY_BYTES,
+        }
+    }
+}
+
+impl<K: Serializable + Ord + Copy, V: Serializable, B: ConstUsize> Clone for SyncedKvStore<K, V, B> {
+    fn clone(&self) -> SyncedKvStore<K, V, B> {
+        SyncedKvStore {
+            handle: self.handle.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static> SyncedKvStore<K, V, B> {
+    /// Spawns the task committing the transaction groups of `store`.
+    ///
+    /// It should be called from a task of the reactor, and the task only lives as long as
+    /// the future given to `Core::run()`.
+    pub fn open(store: KvStore<K, V, B>, options: SyncOptions) -> Result<SyncedKvStore<K, V, B>, failure::Error> {
+        if store.pool().read_only() {
+            return Err(format_err!("Cannot sync the store: the pool is opened read-only"));
+        }
+
+        let handle = store.pool().handle();
+        let synced = SyncedKvStore {
+            handle: handle.clone(),
+            state: Rc::new(RefCell::new(SyncedKvStoreState {
+                tgx: store.pool().tgx(),
+                store: Some(store),
+                waiters: Vec::new(),
+                sync_error: None,
+                options,
+                last_sync: Instant::now(),
+                closed: false,
+                sync_task: None,
+            })),
+        };
+
+        // its errors are reported by the next operation
+        let sync_task = handle.spawn(sync_task(synced.clone()));
+        synced.state.borrow_mut().sync_task = Some(sync_task);
+
+        Ok(synced)
+    }
+
+    /// Returns the `tgx` of the last commit.
+    pub fn tgx(&self) -> u64 {
+        self.state.borrow().tgx
+    }
+
+    #[async]
+    pub fn get(self, key: K) -> Result<Option<V>, failure::Error> {
+        self.take_sync_error()?;
+
+        // kept until the read is done, so that the nodes it reads are not freed by a commit
+        let (synced, store) = await!(self.take_store())?;
+        let res = await!(store.get(key));
+        synced.put_back(store);
+        res
+    }
+
+    /// Inserts or replaces the value of `key` and returns the previous one.
+    ///
+    /// If the commit it triggers fails, the error is returned but the modification is kept.
+    #[async]
+    pub fn put(self, key: K, value: V) -> Result<Option<V>, failure::Error> {
+        self.take_sync_error()?;
+        let (synced, mut store) = await!(self.take_store())?;
+        let mut res = await!(store.put(key, value));
+        if res.is_ok() {
+            res = await!(synced.throttle(&mut store)).and(res);
+        }
+        synced.put_back(store);
+        res
+    }
+
+    /// Combines the value of `key` with `operand` using the merge operator of the store.
+    #[async]
+    pub fn merge(self, key: K, operand: V) -> Result<(), failure::Error> {
+        self.take_sync_error()?;
+        let (synced, mut store) = await!(self.take_store())?;
+        let mut res = await!(store.merge(key, operand));
+        if res.is_ok() {
+            res = await!(synced.throttle(&mut store));
+        }
+        synced.put_back(store);
+        res
+    }
+
+    /// Removes `key` and returns its value.
+    #[async]
+    pub fn delete(self, key: K) -> Result<Option<V>, failure::Error> {
+        self.take_sync_error()?;
+        let (synced, mut store) = await!(self.take_store())?;
+        let mut res = await!(store.delete(key));
+        if res.is_ok() {
+            res = await!(synced.throttle(&mut store)).and(res);
+        }
+        synced.put_back(store);
+        res
+    }
+
+    /// Commits the open transaction group now, if anything has been written.
+    #[async]
+    pub fn sync(self) -> Result<(), failure::Error> {
+        self.take_sync_error()?;
+        let (synced, mut store) = await!(self.take_store())?;
+        let res = await!(synced.commit(&mut store));
+        synced.put_back(store);
+        res
+    }
+
+    /// Stops the background task, commits the open transaction group and closes the pool.
+    ///
+    /// The store is closed even if the commit fails.
+    #[async]
+    pub fn close(self) -> Result<(), failure::Error> {
+        let (synced, mut store) = await!(self.take_store())?;
+        {
+            let mut state = synced.state.borrow_mut();
+            state.closed = true;
+            if let Some(sync_task) = state.sync_task.take() {
+                sync_task.abort();
+            }
+        }
+        synced.wake_waiters();
+        let res = await!(synced.commit(&mut store));
+        await!(store.close())?;
+        res
+    }
+
+    /// Takes the store out of the shared state, waiting for the operation using it to finish.
+    fn take_store(self) -> impl Future<Item=(SyncedKvStore<K, V, B>, KvStore<K, V, B>), Error=failure::Error> {
+        let synced = self.clone();
+        future::poll_fn(move || {
+            let mut state = synced.state.borrow_mut();
+            if state.closed {
+                return Err(format_err!("The store is closed"));
+            }
+
+            match state.store.take() {
+                Some(store) => Ok(Async::Ready(store)),
+                None => {
+                    // woken up by put_back()
+                    let task_id = synced.handle.current_task_id();
+                    if !state.waiters.contains(&task_id) {
+                        state.waiters.push(task_id);
+                    }
+                    Ok(Async::NotReady)
+                }
+            }
+        }).map(move |store| (self, store))
+    }
+
+    fn put_back(&self, store: KvStore<K, V, B>) {
+        self.state.borrow_mut().store = Some(store);
+        self.wake_waiters();
+    }
+
+    /// Returns the error of the last commit of the background task, if it has not been reported yet.
+    fn take_sync_error(&self) -> Result<(), failure::Error> {
+        match self.state.borrow_mut().sync_error.take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Wakes up the tasks waiting in `take_store()`, the first one polled gets the store.
+    fn wake_waiters(&self) {
+        let waiters = mem::replace(&mut self.state.borrow_mut().waiters, Vec::new());
+        for task_id in waiters {
+            self.handle.wake(task_id);
+        }
+    }
+
+    /// Commits `store` after a modification if too many bytes are dirty.
+    fn throttle<'f>(&'f self, store: &'f mut KvStore<K, V, B>) -> Box<Future<Item=(), Error=failure::Error> + 'f> { // box not really needed
+        Box::new(async_block! {
+            if store.dirty_bytes() >= self.state.borrow().options.dirty_bytes {
+                await!(self.commit(store))?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Commits `store` if anything has been written since its last commit.
+    fn commit<'f>(&'f self, store: &'f mut KvStore<K, V, B>) -> Box<Future<Item=(), Error=failure::Error> + 'f> { // box not really needed
+        Box::new(async_block! {
+            if store.dirty_bytes() > 0 {
+                await!(store.commit())?;
+            }
+
+            let mut state = self.state.borrow_mut();
+            state.tgx = store.pool().tgx();
+            state.last_sync = Instant::now();
+            Ok(())
+        })
+    }
+}
+
+/// Commits the store every `SyncOptions::interval`, unless it has been committed meanwhile.
+#[async]
+fn sync_task<K: Serializable + Ord + Copy + 'static, V: Serializable + 'static, B: ConstUsize + 'static>(synced: SyncedKvStore<K, V, B>) -> Result<(), failure::Error> {
+    loop {
+        let deadline = {
+            let state = synced.state.borrow();
+            state.last_sync + state.options.interval
+        };
+        await!(synced.handle.sleep_until(deadline))?;
+
+        let (closed, deadline) = {
+            let state = synced.state.borrow();
+            (state.closed, state.last_sync + state.options.interval)
+        };
+        if closed {
+            return Ok(());
+        }
+        if deadline <= Instant::now() {
+            let mut store = match await!(synced.clone().take_store()) {
+                Ok((_, store)) => store,
+                Err(_) if synced.state.borrow().closed => return Ok(()), // closed while waiting for the store
+                Err(e) => return Err(e),
+            };
+
+            // retried at the next interval, the modifications being kept
+            if let Err(e) = await!(synced.commit(&mut store)) {
+                let mut state = synced.state.borrow_mut();
+                state.sync_error = Some(e);
+                state.last_sync = Instant::now();
+            }
+            synced.put_back(store);
+        }
+    }
+}
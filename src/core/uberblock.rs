@@ -1,17 +1,28 @@
 use super::*;
+use super::label::*;
+use super::util::*;
 
 impl Uberblock {
+    pub const SIZE: usize = 8 + 8 + 8 + 8 + 8 + ObjectPointer::SIZE + ObjectPointer::SIZE + 8;
 
-    pub fn new(tgx: u64, tree_root_pointer: ObjectPointer, free_space_offset: u64) -> Uberblock {
+    /// Creates a new uberblock timestamped with the current time and signed with our host id.
+    pub fn new(tgx: u64, tree_root_pointer: ObjectPointer, free_space_offset: u64, directory_root_pointer: ObjectPointer) -> Uberblock {
         Uberblock {
             tgx,
+            timestamp: timestamp(),
+            hostid: hostid(),
             free_space_offset,
             tree_root_pointer,
+            directory_root_pointer,
         }
     }
 
     pub fn from_bytes(bytes: &mut Cursor<&[u8]>) -> Result<Uberblock, failure::Error> {
-        assert!(bytes.remaining() >= 8 + 8 + 8 + (8 + 8 + 1));
+        assert!(bytes.remaining() >= Uberblock::SIZE);
+
+        // the checksum covers everything but itself
+        let start = bytes.position() as usize;
+        let checksum = content_hash(&bytes.get_ref()[start..start + Uberblock::SIZE - 8]);
 
         let mut magic= [0;8];
         bytes.copy_to_slice(&mut magic);
@@ -19,33 +30,75 @@ impl Uberblock {
             return Err(format_err!("Incorrect magic number. found: {:?}, expected: {:?}", magic, MAGIC_NUMBER));
         }
         let tgx = bytes.get_u64::<LittleEndian>();
+        let timestamp = bytes.get_u64::<LittleEndian>();
+        let hostid = bytes.get_u64::<LittleEndian>();
         let free_space_offset = bytes.get_u64::<LittleEndian>();
         let tree_root_pointer = ObjectPointer::from_bytes(bytes)?;
+        let directory_root_pointer = ObjectPointer::from_bytes(bytes)?;
+
+        let found = bytes.get_u64::<LittleEndian>();
+        if found != checksum {
+            return Err(format_err!("Incorrect uberblock checksum. found: {:#x}, expected: {:#x}", found, checksum));
+        }
 
         assert!(bytes.remaining() == 0);
 
         Ok(
             Uberblock {
                 tgx,
+                timestamp,
+                hostid,
                 tree_root_pointer,
                 free_space_offset,
+                directory_root_pointer,
             }
         )
     }
 
     pub fn to_bytes(&self, bytes: &mut Cursor<&mut [u8]>) {
-        assert!(bytes.remaining_mut() >= 8 + 8 + 8);
+        assert!(bytes.remaining_mut() >= Uberblock::SIZE);
+        let start = bytes.position() as usize;
         
         bytes.put_slice(MAGIC_NUMBER);
         bytes.put_u64::<LittleEndian>(self.tgx);
+        bytes.put_u64::<LittleEndian>(self.timestamp);
+        bytes.put_u64::<LittleEndian>(self.hostid);
         bytes.put_u64::<LittleEndian>(self.free_space_offset);
         self.tree_root_pointer.to_bytes(bytes);
+        self.directory_root_pointer.to_bytes(bytes);
+
+        let checksum = content_hash(&bytes.get_ref()[start..start + Uberblock::SIZE - 8]);
+        bytes.put_u64::<LittleEndian>(checksum);
     }
 
     pub fn to_mem(&self) -> Box<[u8]> {
-        let mut mem: Box<[u8;41]> = Box::new(unsafe{mem::uninitialized()});
-        self.to_bytes(&mut Cursor::new(&mut *mem));
-        return mem;
+        let mut mem = Vec::with_capacity(Uberblock::SIZE);
+        unsafe{mem.set_len(Uberblock::SIZE)};
+        self.to_bytes(&mut Cursor::new(&mut mem));
+        return mem.into_boxed_slice();
+    }
+
+    pub fn tgx(&self) -> u64 {
+        self.tgx
+    }
+
+    /// Seconds since the UNIX epoch at which the transaction group was committed.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Identifier of the host which committed the transaction group.
+    pub fn hostid(&self) -> u64 {
+        self.hostid
+    }
+
+    pub fn tree_root_pointer(&self) -> ObjectPointer {
+        self.tree_root_pointer.clone()
+    }
+
+    /// Root of the directory of the named trees.
+    pub fn directory_root_pointer(&self) -> ObjectPointer {
+        self.directory_root_pointer.clone()
     }
 
     pub fn async_write_at(&self, handle: Handle, offset: u64) -> impl Future<Item=u64, Error=failure::Error> {
@@ -53,50 +106,77 @@ impl Uberblock {
     }
 }
 
+/// Reads the uberblock rings of all label regions.
+///
+/// Returns, for each region, the uberblock of each slot or `None` if it is damaged.
+#[async]
+pub fn read_uberblock_rings(handle: Handle, label: Label) -> Result<Vec<Vec<Option<Uberblock>>>, failure::Error> {
+    let offsets = label.uberblock_ring_offsets();
+
+    let mut rings = vec![];
+    for i in 0..LABEL_REGIONS {
+        let data = await!(handle.read(offsets[i], label.uberblock_ring_len()))?;
+        let ring = data.chunks(label.uberblock_slot_size() as usize)
+            .map(|chunk| {
+                Uberblock::from_bytes(&mut Cursor::new(&chunk[0..Uberblock::SIZE])).ok()
+            })
+            .collect();
+        rings.push(ring);
+    }
+
+    Ok(rings)
+}
+
+/// Returns all the undamaged uberblocks, one per `tgx`, sorted by `tgx`.
+#[async]
+pub fn read_uberblocks(handle: Handle, label: Label) -> Result<Vec<Uberblock>, failure::Error> {
+    let rings = await!(read_uberblock_rings(handle.clone(), label))?;
+
+    let mut uberblocks: Vec<_> = rings.into_iter()
+        .flat_map(|ring| ring.into_iter())
+        .filter_map(|u| u)
+        .collect();
+    uberblocks.sort_by_key(|u| u.tgx);
+    uberblocks.dedup_by_key(|u| u.tgx); // the copies of the other regions
+
+    Ok(uberblocks)
+}
+
+/// Returns the uberblock with the highest `tgx` among all undamaged copies.
 #[async]
 pub fn find_latest_uberblock(handle: Handle) -> Result<Uberblock, failure::Error> {
-    let uberblocks = await!(handle.read(0, BLOCK_SIZE as u64 *10))?;
-    let uberblock = uberblocks.chunks(BLOCK_SIZE)
-        .map(|chunk| {
-            Uberblock::from_bytes(&mut Cursor::new(&chunk[0..(8 + 8 + 8 + (8 + 8 + 1))]))
-        })
-        .fold_results(None::<Uberblock>, |acc, u| { // compute max if no error
-            if let Some(acc) = acc {
-                if u.tgx <= acc.tgx {
-                    return Some(acc)
-                }
-            }
-            Some(u)
-        }).map(|o| {
-            o.unwrap() // guaranted to succeed
-        });
+    // refuse to go further if we don't understand the pool
+    let label = await!(read_label(handle.clone()))?;
 
-    uberblock
+    let mut uberblocks = await!(read_uberblocks(handle.clone(), label))?;
+    uberblocks.pop().ok_or(format_err!("All the uberblocks are damaged"))
 }
 
+/// Erases, in all the label regions, the uberblock slots of the given `tgx`s.
 #[async]
-pub fn write_new_uberblock(handle: Handle, uberblock: Uberblock) -> Result<(), failure::Error> {
-    // first we find the oldest uberblock offset
-    let data = await!(handle.read(0, BLOCK_SIZE as u64 *10))?;
-    let (offset, _tgx) = data.chunks(BLOCK_SIZE).enumerate()
-        .map(|(i, chunk)| {
-            Uberblock::from_bytes(&mut Cursor::new(&chunk[0..(8 + 8 + 8 + (8 + 8 + 1))])).map(|u|{
-                (i, u)
-            })
-        })
-        .fold_results(None::<(usize, u64)>, |acc, u| {
-            if let Some(acc) = acc {
-                if u.1.tgx <= acc.1 {
-                    return Some(acc)
-                }
-            }
-            Some((u.0, u.1.tgx))
-        }).map(|o| {
-            o.unwrap() // guaranted to succeed
-        })?;
+pub fn erase_uberblocks(handle: Handle, label: Label, tgxs: Vec<u64>) -> Result<(), failure::Error> {
+    let writes: Vec<_> = tgxs.iter()
+        .flat_map(|tgx| label.uberblock_slot_offsets(tgx % label.uberblock_ring_size()).to_vec())
+        .map(|offset| handle.write(vec![0; label.uberblock_slot_size() as usize], offset))
+        .collect();
+    await!(future::join_all(writes))?;
+
+    Ok(())
+}
+
+/// Writes `uberblock` in all the label regions, in place of the oldest one.
+///
+/// `tgx`s are consecutive so the slot of the oldest uberblock is simply `tgx % uberblock_ring_size`
+/// and the rings never need to be read back.
+#[async]
+pub fn write_new_uberblock(handle: Handle, label: Label, uberblock: Uberblock) -> Result<(), failure::Error> {
+    let slot = uberblock.tgx % label.uberblock_ring_size();
 
-    // now write the new uberblock in place of the oldest
-    await!(handle.write(uberblock.to_mem().into_vec(), (offset*BLOCK_SIZE) as u64))?;
+    let mem = uberblock.to_mem().into_vec();
+    let writes: Vec<_> = label.uberblock_slot_offsets(slot).iter()
+        .map(|offset| handle.write(mem.clone(), *offset))
+        .collect();
+    await!(future::join_all(writes))?;
 
     Ok(())
 }
@@ -1,8 +1,37 @@
 use super::*;
+use super::label::*;
+use std::fs::File;
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::hash::{BuildHasher, Hasher};
+use std::collections::hash_map::RandomState;
 
+impl Default for FormatOptions {
+    fn default() -> FormatOptions {
+        FormatOptions {
+            uberblock_ring_size: DEFAULT_UBERBLOCK_RING_SIZE,
+            uberblock_slot_size: DEFAULT_UBERBLOCK_SLOT_SIZE,
+        }
+    }
+}
+
+/// Formats the device with the default options.
 #[async]
 pub fn format(handle: Handle) -> Result<(), failure::Error> {
-    let mut free_space_offset = 10 * BLOCK_SIZE as u64;
+    await!(format_with_options(handle, FormatOptions::default()))
+}
+
+#[async]
+pub fn format_with_options(handle: Handle, options: FormatOptions) -> Result<(), failure::Error> {
+    let device_size = await!(handle.size())?;
+    let label = Label::new(device_size, ConstUsize2::USIZE as u64, &options);
+    label.check_geometry()?;
+
+    // the data goes right after the first label region
+    let mut free_space_offset = label.data_start();
+
+    // write all labels
+    await!(write_label(handle.clone(), label.clone()))?;
     
     // write tree
     let mut tree = Node::<u64, u64, ConstUsize2, Leaf>::new();
@@ -11,14 +40,25 @@ pub fn format(handle: Handle) -> Result<(), failure::Error> {
     free_space_offset += tree_len;
 
     // create pointer to tree
-    let op = ObjectPointer::new(tree_offset, tree_len, ObjectType::LeafNode);
+    let op = ObjectPointer::new(tree_offset, tree_len, 0, 0, ObjectType::LeafNode);
+
+    // write empty directory
+    let directory = Node::<TreeName, TreeEntry, ConstUsize2, Leaf>::new();
+    let directory_offset = free_space_offset;
+    let directory_len = await!(directory.async_write_at(handle.clone(), free_space_offset))?;
+    free_space_offset += directory_len;
 
-    // create all uberblocks
-    let writes: Vec<_> = (0..10)
-        .map(|i| {
-            let s: Box<[u8]> = Uberblock::new(i, op.clone(), free_space_offset).to_mem();
-            handle.write(s.into_vec(), i*BLOCK_SIZE as u64)
+    // create pointer to directory
+    let directory_op = ObjectPointer::new(directory_offset, directory_len, 0, 0, ObjectType::LeafNode);
+
+    // create all uberblocks, in all regions
+    let writes: Vec<_> = (0..label.uberblock_ring_size())
+        .flat_map(|i| {
+            let s: Box<[u8]> = Uberblock::new(i, op.clone(), free_space_offset, directory_op.clone()).to_mem();
+            label.uberblock_slot_offsets(i).to_vec().into_iter()
+                .map(move |offset| (offset, s.to_vec()))
         })
+        .map(|(offset, s)| handle.write(s, offset))
         .collect();
 
     // write all uberblocks
@@ -40,3 +80,67 @@ pub fn is_sorted<I: Iterator<Item=T>, T: PartialOrd>(mut it: I) -> bool {
     }
     true
 }
+
+/// 64 bits FNV-1a hash of `data`.
+///
+/// It only detects damaged metadata, it is not meant to resist tampering.
+#[inline]
+pub fn content_hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Generates a random (version 4) UUID.
+pub fn random_uuid() -> [u8; 16] {
+    let mut uuid = [0; 16];
+    {
+        let mut bytes = Cursor::new(&mut uuid[..]);
+        for _ in 0..2 {
+            // each RandomState is seeded with fresh random keys
+            let mut hasher = RandomState::new().build_hasher();
+            if let Ok(d) = SystemTime::now().duration_since(UNIX_EPOCH) {
+                hasher.write_u64(d.as_secs());
+                hasher.write_u32(d.subsec_nanos());
+            }
+            bytes.put_u64::<LittleEndian>(hasher.finish());
+        }
+    }
+
+    uuid[6] = (uuid[6] & 0x0f) | 0x40; // version 4
+    uuid[8] = (uuid[8] & 0x3f) | 0x80; // RFC 4122 variant
+    uuid
+}
+
+/// Returns the number of seconds since the UNIX epoch.
+pub fn timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0) // the clock is before 1970
+}
+
+/// Returns an identifier of the host we are running on.
+///
+/// It is derived from the machine-id, or from the hostname if there isn't any.
+/// The files are only read the first time it is called on a thread.
+pub fn hostid() -> u64 {
+    thread_local! {
+        static HOSTID: u64 = read_hostid();
+    }
+
+    HOSTID.with(|hostid| *hostid)
+}
+
+fn read_hostid() -> u64 {
+    ["/etc/machine-id", "/var/lib/dbus/machine-id", "/proc/sys/kernel/hostname"].iter()
+        .filter_map(|path| {
+            let mut id = String::new();
+            File::open(path).and_then(|mut f| f.read_to_string(&mut id)).ok().map(|_| id)
+        })
+        .map(|id| content_hash(id.trim().as_bytes()))
+        .next()
+        .unwrap_or(0)
+}
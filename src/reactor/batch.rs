@@ -0,0 +1,345 @@
+//! Vectored and batched block device requests: `FutureReadV`, `FutureWriteV` and `FutureBatch`.
+//!
+//! Each of them sends a single `BDRequest` through the block device channel,
+//! however many segments or requests it carries.
+
+use std::mem;
+use std::rc::Weak;
+use std::cell::RefCell;
+
+use futures::prelude::*;
+
+use failure;
+
+use super::*;
+
+#[derive(Debug)]
+enum FutureReadVState {
+    NotYet{
+        segments: Vec<ReadSegment>
+    },
+    Pending{
+        event_id: EventId
+    },
+    Done
+}
+
+/// `Future` returned by `Device::read_v()` which will resolve to the data of each segment.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureReadV {
+    device_id: DeviceId,
+    priority: IoPriority,
+    state: FutureReadVState,
+    inner: Weak<RefCell<Inner>>
+}
+
+impl FutureReadV {
+    pub fn new(device: &Device, segments: Vec<ReadSegment>) -> FutureReadV {
+        FutureReadV {
+            device_id: device.device_id,
+            priority: device.priority_or(IoPriority::SyncRead),
+            state: FutureReadVState::NotYet {
+                segments
+            },
+            inner: device.inner.clone()
+        }
+    }
+}
+
+impl Future for FutureReadV {
+    type Item=Vec<Vec<u8>>;
+    type Error=failure::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // get mut ref to inner
+        let inner = self.inner.upgrade().unwrap();
+        let mut inner = inner.borrow_mut();
+
+        let task_id = inner.current_task_id
+            .expect("trying to poll a future when the reactor is not running");
+
+        match mem::replace(&mut self.state, FutureReadVState::Done) {
+            // first time the future is polled, push command to queue
+            FutureReadVState::NotYet{segments} => {
+                let event_id = EventId(inner.id_counter);
+                inner.id_counter+=1;
+
+                let device_id = self.device_id;
+                let priority = self.priority;
+                inner.send_bd_request(device_id, BDRequest::ReadV(ReadVRequest{event_id, task_id, device_id, priority, segments}));
+
+                self.state = FutureReadVState::Pending{event_id};
+                Ok(Async::NotReady)
+            },
+            // we are waiting for the result of the command
+            FutureReadVState::Pending{event_id} => {
+                match inner.events_to_future.remove(&event_id) {
+                    Some(Ok(FutureEvent::ReadVResponse(ReadVResponse{data}))) => Ok(Async::Ready(data)),
+                    Some(Err(e)) => Err(e),
+                    None => {
+                        self.state = FutureReadVState::Pending{event_id};
+                        Ok(Async::NotReady)
+                    },
+                    _ => {
+                        unreachable!("logic error in reactor: mismatch of event type");
+                    }
+                }
+            },
+            FutureReadVState::Done => {
+                panic!("FutureReadV polled but already done");
+            }
+        }
+    }
+}
+
+impl Drop for FutureReadV {
+    fn drop(&mut self) {
+        if let FutureReadVState::Pending{event_id} = self.state {
+            cancel_bd_requests(&self.inner, &[event_id]);
+        }
+    }
+}
+
+#[derive(Debug)]
+enum FutureWriteVState {
+    NotYet{
+        segments: Vec<WriteSegment>
+    },
+    Pending{
+        event_id: EventId
+    },
+    Done
+}
+
+/// `Future` returned by `Device::write_v()` which will resolve to the total length written.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureWriteV {
+    device_id: DeviceId,
+    priority: IoPriority,
+    state: FutureWriteVState,
+    inner: Weak<RefCell<Inner>>
+}
+
+impl FutureWriteV {
+    pub fn new(device: &Device, segments: Vec<WriteSegment>) -> FutureWriteV {
+        FutureWriteV {
+            device_id: device.device_id,
+            priority: device.priority_or(IoPriority::SyncWrite),
+            state: FutureWriteVState::NotYet {
+                segments
+            },
+            inner: device.inner.clone()
+        }
+    }
+}
+
+impl Future for FutureWriteV {
+    type Item=u64;
+    type Error=failure::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // get mut ref to inner
+        let inner = self.inner.upgrade().unwrap();
+        let mut inner = inner.borrow_mut();
+
+        let task_id = inner.current_task_id
+            .expect("trying to poll a future when the reactor is not running");
+
+        match mem::replace(&mut self.state, FutureWriteVState::Done) {
+            // first time the future is polled, push command to queue
+            FutureWriteVState::NotYet{segments} => {
+                if inner.read_only {
+                    return Err(format_err!("FutureWriteV::poll: block device is read-only (write of {} segments)", segments.len()));
+                }
+
+                let event_id = EventId(inner.id_counter);
+                inner.id_counter+=1;
+
+                let device_id = self.device_id;
+                let priority = self.priority;
+                inner.send_bd_request(device_id, BDRequest::WriteV(WriteVRequest{event_id, task_id, device_id, priority, segments}));
+
+                self.state = FutureWriteVState::Pending{event_id};
+                Ok(Async::NotReady)
+            },
+            // we are waiting for the result of the command
+            FutureWriteVState::Pending{event_id} => {
+                match inner.events_to_future.remove(&event_id) {
+                    Some(Ok(FutureEvent::WriteResponse(WriteResponse{len}))) => Ok(Async::Ready(len)),
+                    Some(Err(e)) => Err(e),
+                    None => {
+                        self.state = FutureWriteVState::Pending{event_id};
+                        Ok(Async::NotReady)
+                    },
+                    _ => {
+                        unreachable!("logic error in reactor: mismatch of event type");
+                    }
+                }
+            },
+            FutureWriteVState::Done => {
+                panic!("FutureWriteV polled but already done");
+            }
+        }
+    }
+}
+
+impl Drop for FutureWriteV {
+    fn drop(&mut self) {
+        if let FutureWriteVState::Pending{event_id} = self.state {
+            cancel_bd_requests(&self.inner, &[event_id]);
+        }
+    }
+}
+
+/// An operation of a batch given to `Device::submit_batch()`.
+#[derive(Debug)]
+pub enum BatchOp {
+    Read{
+        offset: u64,
+        length: u64
+    },
+    Write{
+        offset: u64,
+        data: Vec<u8>
+    },
+    Flush
+}
+
+/// The result of a `BatchOp`.
+#[derive(Debug, PartialEq)]
+pub enum BatchResult {
+    Read(Vec<u8>),
+    Write(u64), // length written
+    Flush
+}
+
+#[derive(Debug)]
+enum FutureBatchState {
+    NotYet{
+        ops: Vec<BatchOp>
+    },
+    Pending{
+        event_ids: Vec<EventId>,
+        responses: Vec<Option<Result<FutureEvent, failure::Error>>>
+    },
+    Done
+}
+
+/// `Future` returned by `Device::submit_batch()` which will resolve to the result of each operation.
+///
+/// It resolves once all the operations are done, and fails with the error of the first failed one.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureBatch {
+    device_id: DeviceId,
+    priority: IoPriority,
+    state: FutureBatchState,
+    inner: Weak<RefCell<Inner>>
+}
+
+impl FutureBatch {
+    pub fn new(device: &Device, ops: Vec<BatchOp>) -> FutureBatch {
+        let writes = ops.iter().any(|op| if let BatchOp::Write{..} = op { true } else { false });
+        FutureBatch {
+            device_id: device.device_id,
+            priority: device.priority_or(if writes { IoPriority::SyncWrite } else { IoPriority::SyncRead }),
+            state: FutureBatchState::NotYet {
+                ops
+            },
+            inner: device.inner.clone()
+        }
+    }
+}
+
+impl Future for FutureBatch {
+    type Item=Vec<BatchResult>;
+    type Error=failure::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // get mut ref to inner
+        let inner = self.inner.upgrade().unwrap();
+        let mut inner = inner.borrow_mut();
+
+        let task_id = inner.current_task_id
+            .expect("trying to poll a future when the reactor is not running");
+
+        match mem::replace(&mut self.state, FutureBatchState::Done) {
+            // first time the future is polled, push all the commands to queue at once
+            FutureBatchState::NotYet{ops} => {
+                if ops.is_empty() {
+                    return Ok(Async::Ready(Vec::new()));
+                }
+
+                let writes = ops.iter().filter(|op| if let BatchOp::Write{..} = op { true } else { false }).count();
+                if inner.read_only && writes > 0 {
+                    return Err(format_err!("FutureBatch::poll: block device is read-only (batch of {} requests with {} writes)", ops.len(), writes));
+                }
+
+                let device_id = self.device_id;
+                let priority = self.priority;
+                let mut event_ids = Vec::with_capacity(ops.len());
+                let mut requests = Vec::with_capacity(ops.len());
+                for op in ops {
+                    let event_id = EventId(inner.id_counter);
+                    inner.id_counter+=1;
+                    event_ids.push(event_id);
+
+                    requests.push(match op {
+                        BatchOp::Read{offset, length} => BDRequest::Read(ReadRequest{event_id, task_id, device_id, priority, offset, length}),
+                        BatchOp::Write{offset, data} => BDRequest::Write(WriteRequest{event_id, task_id, device_id, priority, offset, data}),
+                        BatchOp::Flush => BDRequest::Flush(FlushRequest{event_id, task_id, device_id, priority}),
+                    });
+                }
+
+                inner.send_bd_request(device_id, BDRequest::Batch(BatchRequest{requests}));
+
+                let responses = event_ids.iter().map(|_| None).collect();
+                self.state = FutureBatchState::Pending{event_ids, responses};
+                Ok(Async::NotReady)
+            },
+            // we are waiting for the results of all the commands
+            FutureBatchState::Pending{event_ids, mut responses} => {
+                for (event_id, response) in event_ids.iter().zip(responses.iter_mut()) {
+                    if response.is_none() {
+                        *response = inner.events_to_future.remove(event_id);
+                    }
+                }
+
+                if responses.iter().any(|response| response.is_none()) {
+                    self.state = FutureBatchState::Pending{event_ids, responses};
+                    return Ok(Async::NotReady);
+                }
+
+                let mut results = Vec::with_capacity(responses.len());
+                for response in responses {
+                    results.push(match response.unwrap()? {
+                        FutureEvent::ReadResponse(ReadResponse{data}) => BatchResult::Read(data),
+                        FutureEvent::WriteResponse(WriteResponse{len}) => BatchResult::Write(len),
+                        FutureEvent::FlushResponse(FlushResponse{}) => BatchResult::Flush,
+                        _ => {
+                            unreachable!("logic error in reactor: mismatch of event type");
+                        }
+                    });
+                }
+                Ok(Async::Ready(results))
+            },
+            FutureBatchState::Done => {
+                panic!("FutureBatch polled but already done");
+            }
+        }
+    }
+}
+
+impl Drop for FutureBatch {
+    fn drop(&mut self) {
+        if let FutureBatchState::Pending{event_ids, responses} = &self.state {
+            let event_ids: Vec<EventId> = event_ids.iter().zip(responses)
+                .filter(|(_, response)| response.is_none())
+                .map(|(event_id, _)| *event_id)
+                .collect();
+            cancel_bd_requests(&self.inner, &event_ids);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! `JoinHandle`s of the tasks spawned with `Handle::spawn()`.
+
+use std::fmt;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::{Rc, Weak};
+use std::cell::RefCell;
+
+use futures::prelude::*;
+
+use failure;
+
+use super::*;
+
+/// Error of a `JoinHandle`.
+#[derive(Debug)]
+pub enum JoinError<E> {
+    /// the future of the task failed with this error
+    Failed(E),
+    /// the task has been stopped by `JoinHandle::abort()`
+    Aborted,
+    /// the future of the task panicked with this message, see `Handle::spawn_catch_unwind()`
+    Panicked(String),
+}
+
+impl<E: fmt::Display> fmt::Display for JoinError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Failed(e) => write!(f, "Task failed: {}", e),
+            JoinError::Aborted => write!(f, "Task aborted"),
+            JoinError::Panicked(message) => write!(f, "Task panicked: {}", message),
+        }
+    }
+}
+
+impl<E: fmt::Display + fmt::Debug + Send + Sync + 'static> failure::Fail for JoinError<E> {}
+
+/// State shared by a spawned task and its `JoinHandle`.
+#[derive(Debug)]
+pub struct JoinState<T, E> {
+    result: Option<Result<T, JoinError<E>>>,
+    finished: bool,
+    waiter: Option<TaskId>, // task to poll when the result is available
+}
+
+impl<T, E> JoinState<T, E> {
+    pub fn new() -> JoinState<T, E> {
+        JoinState {
+            result: None,
+            finished: false,
+            waiter: None,
+        }
+    }
+
+    /// Records the result of the task, unless it has been aborted, and wakes up its waiter.
+    pub fn finish(state: &Rc<RefCell<JoinState<T, E>>>, handle: &Handle, result: Result<T, JoinError<E>>) {
+        let waiter = {
+            let mut state = state.borrow_mut();
+            if state.finished {
+                return;
+            }
+            state.finished = true;
+            state.result = Some(result);
+            state.waiter.take()
+        };
+
+        if let Some(task_id) = waiter {
+            handle.wake(task_id);
+        }
+    }
+}
+
+/// Finishes the `JoinState` of a spawned task, owned by its future.
+///
+/// If the future is dropped before being done, e.g. with the reactor, the task is finished with `JoinError::Aborted`.
+pub struct JoinGuard<T, E> {
+    state: Rc<RefCell<JoinState<T, E>>>,
+    handle: Handle,
+}
+
+impl<T, E> JoinGuard<T, E> {
+    pub fn new(state: Rc<RefCell<JoinState<T, E>>>, handle: Handle) -> JoinGuard<T, E> {
+        JoinGuard {
+            state,
+            handle,
+        }
+    }
+
+    pub fn finish(&self, result: Result<T, JoinError<E>>) {
+        JoinState::finish(&self.state, &self.handle, result);
+    }
+}
+
+impl<T, E> Drop for JoinGuard<T, E> {
+    fn drop(&mut self) {
+        // nothing happens if the task is done already
+        JoinState::finish(&self.state, &self.handle, Err(JoinError::Aborted));
+    }
+}
+
+/// `Future` returned by `Handle::spawn()` which resolves to the result of the task.
+///
+/// Dropping it does not stop the task, use `abort()` for that.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct JoinHandle<T, E> {
+    task_id: TaskId,
+    state: Rc<RefCell<JoinState<T, E>>>,
+    inner: Weak<RefCell<Inner>>,
+}
+
+impl<T, E> JoinHandle<T, E> {
+    pub fn new(task_id: TaskId, state: Rc<RefCell<JoinState<T, E>>>, handle: &Handle) -> JoinHandle<T, E> {
+        JoinHandle {
+            task_id,
+            state,
+            inner: handle.inner.clone(),
+        }
+    }
+
+    pub fn task_id(&self) -> TaskId {
+        self.task_id
+    }
+
+    /// Returns `true` if the task is done, or has been aborted.
+    pub fn is_finished(&self) -> bool {
+        self.state.borrow().finished
+    }
+
+    /// Stops the task: its future is dropped by `Core::run()` before being polled again.
+    ///
+    /// The `JoinHandle` then fails with `JoinError::Aborted`. Nothing happens if the task is already done.
+    pub fn abort(&self) {
+        if self.is_finished() {
+            return;
+        }
+
+        let handle = Handle {inner: self.inner.clone(), priority: None};
+        handle.abort_task(self.task_id);
+        JoinState::finish(&self.state, &handle, Err(JoinError::Aborted));
+    }
+}
+
+impl<T, E> Future for JoinHandle<T, E> {
+    type Item=T;
+    type Error=JoinError<E>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut state = self.state.borrow_mut();
+
+        match state.result.take() {
+            Some(Ok(r)) => Ok(Async::Ready(r)),
+            Some(Err(e)) => Err(e),
+            None if state.finished => {
+                panic!("JoinHandle polled but already done");
+            }
+            None => {
+                // the task polling us is woken up when the result is available
+                let inner = self.inner.upgrade().unwrap();
+                let task_id = inner.borrow().current_task_id
+                    .expect("trying to poll a future when the reactor is not running");
+                state.waiter = Some(task_id);
+
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// Future of a task spawned with `Handle::spawn_catch_unwind()`, turning panics into errors.
+pub struct CatchUnwind<F> {
+    future: F,
+}
+
+impl<F> CatchUnwind<F> {
+    pub fn new(future: F) -> CatchUnwind<F> {
+        CatchUnwind {
+            future,
+        }
+    }
+}
+
+/// Returns the message given to `panic!()`.
+fn panic_message(payload: Box<Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic payload".into(),
+        }
+    }
+}
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Item=F::Item;
+    type Error=JoinError<F::Error>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // the future is never polled again after a panic, so whatever state it left can't be observed
+        let future = &mut self.future;
+        match panic::catch_unwind(AssertUnwindSafe(|| future.poll())) {
+            Ok(Ok(r)) => Ok(r),
+            Ok(Err(e)) => Err(JoinError::Failed(e)),
+            Err(payload) => Err(JoinError::Panicked(panic_message(payload))),
+        }
+    }
+}
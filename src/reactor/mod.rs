@@ -14,7 +14,7 @@
 //!   (kernel/nostd or userland/std) and the IO system.
 
 /* TODO
- - factorize Future{Read,Write,Flush}::poll()
+ - factorize Future{Read,Write,Flush,Size}::poll()
  - check ids boundaries
  - communicate to the outsite StreamIds
  - use slab instead of hashmap
@@ -28,20 +28,36 @@
 #[cfg(test)]
 mod tests;
 
+mod timer;
+mod join;
+mod batch;
+mod scheduler;
+
 extern crate futures;
 //extern crate slab;
 
 use std::fmt;
+use std::mem;
 use std::rc::{Rc, Weak};
 use std::cell::RefCell;
-use std::sync::mpsc::{Sender, Receiver};
-use std::collections::HashMap;
+use std::sync::mpsc::{Sender, Receiver, RecvTimeoutError, TryRecvError};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 
 use futures::prelude::*;
 
 use failure;
 //use slab::Slab;
 
+use self::timer::TimerWheel;
+use self::scheduler::IoScheduler;
+pub use self::scheduler::{IoPriority, DEFAULT_QUEUE_DEPTHS};
+pub use self::timer::{FutureSleep, Interval, Timeout, TimedOut};
+use self::join::{JoinState, JoinGuard};
+use self::join::CatchUnwind;
+pub use self::join::{JoinHandle, JoinError};
+pub use self::batch::{FutureReadV, FutureWriteV, FutureBatch, BatchOp, BatchResult};
+
 
 /// The ID of a `Stream`
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -59,6 +75,16 @@ pub struct EventId(pub u64);
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct TaskId(pub u64);
 
+/// The ID of a block device
+///
+/// The block device given to ```reactor::Core::new()``` is `DEFAULT_DEVICE`,
+/// the ones added with ```reactor::Core::add_device()``` are numbered from 1.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DeviceId(pub u64);
+
+/// The block device used by the I/O methods of `Handle`
+pub const DEFAULT_DEVICE: DeviceId = DeviceId(0);
+
 /// Represents a spawned task.
 ///
 /// Holds the `Future` it's running
@@ -76,8 +102,11 @@ impl fmt::Debug for SpawnedTask {
 #[derive(Debug)]
 pub enum FutureEvent {
     ReadResponse(ReadResponse),
+    ReadVResponse(ReadVResponse),
     WriteResponse(WriteResponse),
-    FlushResponse(FlushResponse)
+    FlushResponse(FlushResponse),
+    SizeResponse(SizeResponse),
+    TimerExpired
     /*
     ...
     */
@@ -112,6 +141,8 @@ pub enum Event {
 pub struct ReadRequest {
     pub event_id: EventId,
     pub task_id: TaskId,
+    pub device_id: DeviceId,
+    pub priority: IoPriority,
     pub offset: u64,
     pub length: u64
 }
@@ -122,23 +153,110 @@ pub struct ReadRequest {
 pub struct WriteRequest {
     pub event_id: EventId,
     pub task_id: TaskId,
+    pub device_id: DeviceId,
+    pub priority: IoPriority,
+    pub offset: u64,
+    pub data: Vec<u8>
+}
+
+/// A segment of a `ReadVRequest`
+#[derive(Clone, Copy, Debug)]
+pub struct ReadSegment {
+    pub offset: u64,
+    pub length: u64
+}
+
+/// A block device vectored read request, answered by a single `ReadVResponse`
+#[derive(Debug)]
+pub struct ReadVRequest {
+    pub event_id: EventId,
+    pub task_id: TaskId,
+    pub device_id: DeviceId,
+    pub priority: IoPriority,
+    pub segments: Vec<ReadSegment>
+}
+
+/// A segment of a `WriteVRequest`
+#[derive(Debug)]
+pub struct WriteSegment {
     pub offset: u64,
     pub data: Vec<u8>
 }
 
+/// A block device vectored write request, answered by a single `WriteResponse`
+/// with the total length written
+#[derive(Debug)]
+pub struct WriteVRequest {
+    pub event_id: EventId,
+    pub task_id: TaskId,
+    pub device_id: DeviceId,
+    pub priority: IoPriority,
+    pub segments: Vec<WriteSegment>
+}
+
 /// A block device flush request
 #[derive(Debug)]
 pub struct FlushRequest {
     pub event_id: EventId,
-    pub task_id: TaskId
+    pub task_id: TaskId,
+    pub device_id: DeviceId,
+    pub priority: IoPriority
+}
+
+/// A block device size request
+#[derive(Debug)]
+pub struct SizeRequest {
+    pub event_id: EventId,
+    pub task_id: TaskId,
+    pub device_id: DeviceId,
+    pub priority: IoPriority
+}
+
+/// Block device requests sent as a single message,
+/// each of them is answered by its own response
+#[derive(Debug)]
+pub struct BatchRequest {
+    pub requests: Vec<BDRequest>
 }
 
 /// A block device request
 #[derive(Debug)]
 pub enum BDRequest {
     Read(ReadRequest),
+    ReadV(ReadVRequest),
     Write(WriteRequest),
-    Flush(FlushRequest)
+    WriteV(WriteVRequest),
+    Flush(FlushRequest),
+    Size(SizeRequest),
+    Batch(BatchRequest)
+}
+
+impl BDRequest {
+    /// Returns the `EventId` of the response to the request, `None` for a batch.
+    pub fn event_id(&self) -> Option<EventId> {
+        match self {
+            BDRequest::Read(r) => Some(r.event_id),
+            BDRequest::ReadV(r) => Some(r.event_id),
+            BDRequest::Write(w) => Some(w.event_id),
+            BDRequest::WriteV(w) => Some(w.event_id),
+            BDRequest::Flush(f) => Some(f.event_id),
+            BDRequest::Size(s) => Some(s.event_id),
+            BDRequest::Batch(_) => None,
+        }
+    }
+
+    /// Returns the priority class of the request, the one of its first request for a batch.
+    pub fn priority(&self) -> IoPriority {
+        match self {
+            BDRequest::Read(r) => r.priority,
+            BDRequest::ReadV(r) => r.priority,
+            BDRequest::Write(w) => w.priority,
+            BDRequest::WriteV(w) => w.priority,
+            BDRequest::Flush(f) => f.priority,
+            BDRequest::Size(s) => s.priority,
+            BDRequest::Batch(b) => b.requests.first().map(|r| r.priority()).unwrap_or(IoPriority::Background),
+        }
+    }
 }
 
 /// A block device read response
@@ -147,6 +265,12 @@ pub struct ReadResponse {
     pub data: Vec<u8>
 }
 
+/// A block device vectored read response, with the data of each segment
+#[derive(Debug)]
+pub struct ReadVResponse {
+    pub data: Vec<Vec<u8>>
+}
+
 /// A block device write response
 #[derive(Debug)]
 pub struct WriteResponse {
@@ -158,6 +282,12 @@ pub struct WriteResponse {
 pub struct FlushResponse {
 }
 
+/// A block device size response
+#[derive(Debug)]
+pub struct SizeResponse {
+    pub size: u64
+}
+
 /// A filesystem request
 #[derive(Debug)]
 pub struct FSRequest {
@@ -178,12 +308,18 @@ struct Inner {
     id_counter: u64, // incrementing counter of events and streams
     task_id_counter: u64,
     events_to_future: HashMap<EventId, Result<FutureEvent, failure::Error>>,
+    abandoned_events: HashSet<EventId>, // of requests whose future has been dropped, their responses are discarded
     events_to_streams: HashMap<StreamId, Vec<Result<StreamEvent, failure::Error>>>,
     newly_spawned_tasks: Vec<(TaskId, SpawnedTask)>,
+    ready_tasks: Vec<TaskId>, // to poll without waiting for an event
+    aborted_tasks: Vec<TaskId>, // to drop
     current_task_id: Option<TaskId>,
+    read_only: bool, // writes are refused before reaching the block device
+    timers: TimerWheel,
+    io_scheduler: IoScheduler, // block device requests waiting to be sent
     
-    // channels to which send block device requests and filesystem responses
-    bd_sender: Sender<BDRequest>,
+    // channels to which send block device requests, indexed by DeviceId, and filesystem responses
+    bd_senders: Vec<Sender<BDRequest>>,
     fs_sender: Sender<FSResponse>,
 }
 
@@ -193,54 +329,184 @@ impl Inner {
             id_counter: 0,
             task_id_counter: 1, // 0 is reserved to the main task_id
             events_to_future: HashMap::new(),
+            abandoned_events: HashSet::new(),
             events_to_streams: HashMap::new(),
             newly_spawned_tasks: Vec::new(),
+            ready_tasks: Vec::new(),
+            aborted_tasks: Vec::new(),
             current_task_id: None,
-            bd_sender,
+            read_only: false,
+            timers: TimerWheel::new(),
+            io_scheduler: IoScheduler::new(),
+            bd_senders: vec![bd_sender],
             fs_sender
         }
     }
+
+    /// Queues `request` for the block device `device_id`, it is sent by `send_bd_requests()`.
+    fn send_bd_request(&mut self, device_id: DeviceId, request: BDRequest) {
+        self.io_scheduler.push(device_id, request);
+    }
+
+    /// Forgets the request `event_id` of a dropped future: it is not sent if it is still queued,
+    /// otherwise its response is discarded.
+    fn cancel_bd_request(&mut self, event_id: EventId) {
+        if self.io_scheduler.cancel(event_id) {
+            return;
+        }
+        if self.events_to_future.remove(&event_id).is_none() {
+            self.abandoned_events.insert(event_id);
+        }
+    }
+
+    /// Sends the queued requests to the block devices, once merged and sorted by the `IoScheduler`.
+    fn send_bd_requests(&mut self) {
+        for (device_id, request) in self.io_scheduler.schedule(&mut self.id_counter) {
+            self.bd_senders[device_id.0 as usize].send(request)
+                .unwrap_or_else(|_| panic!("reactor: block device channel {:?} has been closed", device_id));
+        }
+    }
 }
 
+/// Called when an I/O `Future` waiting for the responses to `event_ids` is dropped.
+fn cancel_bd_requests(inner: &Weak<RefCell<Inner>>, event_ids: &[EventId]) {
+    // the reactor may be gone already
+    if let Some(inner) = inner.upgrade() {
+        let mut inner = inner.borrow_mut();
+        for event_id in event_ids {
+            inner.cancel_bd_request(*event_id);
+        }
+    }
+}
 
 /// A handle to the `reactor::Inner` structure of `reactor::Core` (```Weak<RefCell<Inner>>```)
 ///
 /// It's used to create new I/O `Future`s and `Stream`s and spawn new `SpawnedTask`s.
 #[derive(Clone)]
 pub struct Handle {
-    inner: Weak<RefCell<Inner>>
+    inner: Weak<RefCell<Inner>>,
+    priority: Option<IoPriority> // of its I/Os, the default one of each kind of request if `None`
 }
 
 impl Handle {
-    /// Reads `length` bytes at `offset` and returns a `ReadFuture` which resolves to `Vec<u8>`.
-    pub fn read(&self, offset: u64, length: u64) -> FutureRead {
-        FutureRead {
-            state: FutureReadState::NotYet {
-                offset,
-                length
-            },
+    /// Returns a `Device` to send I/Os to the block device `device_id`.
+    ///
+    /// Panics if `device_id` has not been added to `Core`.
+    pub fn device(&self, device_id: DeviceId) -> Device {
+        // get ref to inner
+        let inner = self.inner.upgrade().unwrap();
+        let inner = inner.borrow();
+
+        assert!((device_id.0 as usize) < inner.bd_senders.len(), "reactor::Handle::device: unknown block device {:?}", device_id);
+
+        Device {
+            device_id,
+            priority: self.priority,
             inner: self.inner.clone()
         }
     }
 
-    /// Writes `data` bytes at `offset` and returns a `WriteFuture` which resolves when the write is done`.
-    pub fn write(&self, data: Vec<u8>, offset: u64) -> FutureWrite {
-        FutureWrite {
-            state: FutureWriteState::NotYet {
-                data,
-                offset
-            },
-            inner: self.inner.clone()
+    /// Returns a `Handle` whose I/Os, and the ones of its `Device`s, have the priority class `priority`.
+    ///
+    /// Otherwise, reads, size requests and batches without writes are `IoPriority::SyncRead`
+    /// and the other ones `IoPriority::SyncWrite`.
+    pub fn with_priority(&self, priority: IoPriority) -> Handle {
+        Handle {
+            inner: self.inner.clone(),
+            priority: Some(priority)
         }
     }
 
-    /// Flush block device queue and returns a `FlushFuture` which resolves when the flush is done`.
+    /// Returns the priority class given to `with_priority()`, if any.
+    pub fn priority(&self) -> Option<IoPriority> {
+        self.priority
+    }
+
+    /// Returns the IDs of all the block devices, starting with `DEFAULT_DEVICE`.
+    pub fn device_ids(&self) -> Vec<DeviceId> {
+        // get ref to inner
+        let inner = self.inner.upgrade().unwrap();
+        let inner = inner.borrow();
+
+        (0..inner.bd_senders.len() as u64).map(DeviceId).collect()
+    }
+
+    /// Reads `length` bytes at `offset` of `DEFAULT_DEVICE` and returns a `ReadFuture` which resolves to `Vec<u8>`.
+    pub fn read(&self, offset: u64, length: u64) -> FutureRead {
+        self.device(DEFAULT_DEVICE).read(offset, length)
+    }
+
+    /// Writes `data` bytes at `offset` of `DEFAULT_DEVICE` and returns a `WriteFuture` which resolves when the write is done`.
+    pub fn write(&self, data: Vec<u8>, offset: u64) -> FutureWrite {
+        self.device(DEFAULT_DEVICE).write(data, offset)
+    }
+
+    /// Reads the `segments` of `DEFAULT_DEVICE` with a single request, see `Device::read_v()`.
+    pub fn read_v(&self, segments: Vec<ReadSegment>) -> FutureReadV {
+        self.device(DEFAULT_DEVICE).read_v(segments)
+    }
+
+    /// Writes the `segments` of `DEFAULT_DEVICE` with a single request, see `Device::write_v()`.
+    pub fn write_v(&self, segments: Vec<WriteSegment>) -> FutureWriteV {
+        self.device(DEFAULT_DEVICE).write_v(segments)
+    }
+
+    /// Flush `DEFAULT_DEVICE` queue and returns a `FlushFuture` which resolves when the flush is done`.
     pub fn flush(&self) -> FutureFlush {
-        FutureFlush {
-            state: FutureFlushState::NotYet {
-            },
-            inner: self.inner.clone()
-        }
+        self.device(DEFAULT_DEVICE).flush()
+    }
+
+    /// Sends `ops` to `DEFAULT_DEVICE` as a single message, see `Device::submit_batch()`.
+    pub fn submit_batch(&self, ops: Vec<BatchOp>) -> FutureBatch {
+        self.device(DEFAULT_DEVICE).submit_batch(ops)
+    }
+
+    /// Makes the block devices read-only: from now on, all `FutureWrite`s fail without
+    /// sending anything to a block device.
+    ///
+    /// This can't be undone.
+    pub fn set_read_only(&self) {
+        // get mut ref to inner
+        let inner = self.inner.upgrade().unwrap();
+        let mut inner = inner.borrow_mut();
+
+        inner.read_only = true;
+    }
+
+    /// Returns `true` if `set_read_only()` has been called.
+    pub fn is_read_only(&self) -> bool {
+        // get ref to inner
+        let inner = self.inner.upgrade().unwrap();
+        let inner = inner.borrow();
+
+        inner.read_only
+    }
+
+    /// Queries the size of `DEFAULT_DEVICE` and returns a `FutureSize` which resolves to its size in bytes.
+    pub fn size(&self) -> FutureSize {
+        self.device(DEFAULT_DEVICE).size()
+    }
+
+    /// Returns a `FutureSleep` which resolves once `duration` has elapsed.
+    pub fn sleep(&self, duration: Duration) -> FutureSleep {
+        self.sleep_until(Instant::now() + duration)
+    }
+
+    /// Returns a `FutureSleep` which resolves at `deadline`.
+    pub fn sleep_until(&self, deadline: Instant) -> FutureSleep {
+        FutureSleep::new(self, deadline)
+    }
+
+    /// Returns an `Interval` which yields every `period`, the first time one `period` from now.
+    pub fn interval(&self, period: Duration) -> Interval {
+        Interval::new(self, period)
+    }
+
+    /// Returns a `Timeout` which resolves to the result of `future`,
+    /// or fails with `TimedOut` if `future` is not resolved after `duration`.
+    pub fn timeout<F>(&self, future: F, duration: Duration) -> Timeout<F>
+    where F: Future, F::Error: Into<failure::Error> {
+        Timeout::new(future, self.sleep(duration))
     }
 
     /// Return a `FSCallStream` which resolves to `FSRequest`s.
@@ -261,21 +527,33 @@ impl Handle {
             .expect("reactor::Handle::send_fs_response: filesystem channel has been closed");
     }
 
-    /// Spawns a new `SpawnedTask` in the event loop from a `Future` or a `Stream`
+    /// Spawns a new `SpawnedTask` in the event loop from a `Future`
     ///
     /// # Return value
     ///
-    /// This function returns the `TaskId` to which the `Future` as been assigned.
-    /// It's useful to then send `Event`s to this `Future`.
-    /// 
-    /// We don't yet provide a way to retreive the `StreamId` of `Stream`s.
-    /// 
+    /// This function returns a `JoinHandle` which resolves to the result of the `Future`
+    /// and can stop it with `JoinHandle::abort()`.
+    ///
+    /// Its `TaskId` is useful to then send `Event`s to this `Future`.
+    ///
     /// # About panics
     ///
     /// `SpawnedTask`s **do not** catch panics, it is the responsability of the `Future`
-    /// to do so if needed. 
-    pub fn spawn<F>(&self, mut f: F) -> TaskId
-    where F: Future<Item=(), Error=()> + 'static {
+    /// to do so if needed, or use `spawn_catch_unwind()`.
+    pub fn spawn<F>(&self, f: F) -> JoinHandle<F::Item, F::Error>
+    where F: Future + 'static {
+        self.spawn_join(f.map_err(JoinError::Failed))
+    }
+
+    /// Same as `spawn()` but a panic of the `Future` only stops its task:
+    /// the `JoinHandle` then fails with `JoinError::Panicked` and the other tasks keep running.
+    pub fn spawn_catch_unwind<F>(&self, f: F) -> JoinHandle<F::Item, F::Error>
+    where F: Future + 'static {
+        self.spawn_join(CatchUnwind::new(f))
+    }
+
+    fn spawn_join<F, T, E>(&self, f: F) -> JoinHandle<T, E>
+    where F: Future<Item=T, Error=JoinError<E>> + 'static, T: 'static, E: 'static {
         println!("handle: spawning new task");
         let saved_current_task_id;
         let task_id;
@@ -295,12 +573,21 @@ impl Handle {
             inner.current_task_id = Some(task_id);
         }
 
+        // the result of the future goes to the JoinHandle
+        let state = Rc::new(RefCell::new(JoinState::new()));
+        let join_handle = JoinHandle::new(task_id, state.clone(), self);
+        let guard = JoinGuard::new(state, self.clone());
+        let mut f = f.then(move |result| {
+            guard.finish(result);
+            Ok::<(), ()>(())
+        });
+
         // poll the future which will register itself with the current_task_id
-        match f.poll() {
-            Ok(Async::NotReady) => {}, // nothing to do
+        let finished = match f.poll() {
+            Ok(Async::NotReady) => false,
             Ok(Async::Ready(())) |
-            Err(()) => {return task_id}, // that task_id didn't have time to be instantiated but that's not a problem
-        }
+            Err(()) => true, // that task_id didn't have time to be instantiated but that's not a problem
+        };
 
         // get mut ref to inner
         let inner = self.inner.upgrade().unwrap();
@@ -310,10 +597,45 @@ impl Handle {
         inner.current_task_id = saved_current_task_id;
 
         // create and push new task to the newly_spawned_tasks list
-        let task = SpawnedTask{future: Box::new(f)};
-        inner.newly_spawned_tasks.push((task_id, task));
+        if !finished {
+            let task = SpawnedTask{future: Box::new(f)};
+            inner.newly_spawned_tasks.push((task_id, task));
+        }
+
+        join_handle
+    }
 
-        return task_id;
+    /// Returns the `TaskId` of the task being polled, e.g. to `wake()` it once what it waits for is available.
+    ///
+    /// Panics if the reactor is not running.
+    pub fn current_task_id(&self) -> TaskId {
+        // get ref to inner
+        let inner = self.inner.upgrade().unwrap();
+        let inner = inner.borrow();
+
+        inner.current_task_id
+            .expect("trying to poll a future when the reactor is not running")
+    }
+
+    /// Polls the task `task_id` at the next turn of the event loop, even if it has no new `Event`.
+    pub fn wake(&self, task_id: TaskId) {
+        // get mut ref to inner
+        let inner = self.inner.upgrade().unwrap();
+        let mut inner = inner.borrow_mut();
+
+        if !inner.ready_tasks.contains(&task_id) {
+            inner.ready_tasks.push(task_id);
+        }
+    }
+
+    /// Drops the future of the spawned task `task_id` before it is polled again, see `JoinHandle::abort()`.
+    pub fn abort_task(&self, task_id: TaskId) {
+        // get mut ref to inner
+        let inner = self.inner.upgrade().unwrap();
+        let mut inner = inner.borrow_mut();
+
+        assert_ne!(task_id, TaskId(0), "the main task can't be aborted");
+        inner.aborted_tasks.push(task_id);
     }
 }
 
@@ -325,6 +647,102 @@ impl Handle {
 pub struct Core {
     receiver: Receiver<Event>,
     inner: Rc<RefCell<Inner>>,
+    tasks: HashMap<TaskId, SpawnedTask>, // spawned tasks not done yet, kept from one run to the next
+}
+
+/// A block device of the `reactor`, returned by `Handle::device()`.
+///
+/// It's used to create the I/O `Future`s of this block device.
+#[derive(Clone)]
+pub struct Device {
+    device_id: DeviceId,
+    priority: Option<IoPriority>,
+    inner: Weak<RefCell<Inner>>
+}
+
+impl Device {
+    pub fn id(&self) -> DeviceId {
+        self.device_id
+    }
+
+    /// Same as `Handle::with_priority()`.
+    pub fn with_priority(&self, priority: IoPriority) -> Device {
+        Device {
+            priority: Some(priority),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the priority class of the I/Os whose default class is `default`.
+    fn priority_or(&self, default: IoPriority) -> IoPriority {
+        self.priority.unwrap_or(default)
+    }
+
+    /// Reads `length` bytes at `offset` and returns a `ReadFuture` which resolves to `Vec<u8>`.
+    pub fn read(&self, offset: u64, length: u64) -> FutureRead {
+        FutureRead {
+            device_id: self.device_id,
+            priority: self.priority_or(IoPriority::SyncRead),
+            state: FutureReadState::NotYet {
+                offset,
+                length
+            },
+            inner: self.inner.clone()
+        }
+    }
+
+    /// Writes `data` bytes at `offset` and returns a `WriteFuture` which resolves when the write is done`.
+    pub fn write(&self, data: Vec<u8>, offset: u64) -> FutureWrite {
+        FutureWrite {
+            device_id: self.device_id,
+            priority: self.priority_or(IoPriority::SyncWrite),
+            state: FutureWriteState::NotYet {
+                data,
+                offset
+            },
+            inner: self.inner.clone()
+        }
+    }
+
+    /// Reads the `segments` with a single request and returns a `FutureReadV`
+    /// which resolves to their data, in the same order.
+    pub fn read_v(&self, segments: Vec<ReadSegment>) -> FutureReadV {
+        FutureReadV::new(self, segments)
+    }
+
+    /// Writes the `segments` in order with a single request and returns a `FutureWriteV`
+    /// which resolves to the total length written.
+    pub fn write_v(&self, segments: Vec<WriteSegment>) -> FutureWriteV {
+        FutureWriteV::new(self, segments)
+    }
+
+    /// Flush block device queue and returns a `FlushFuture` which resolves when the flush is done`.
+    pub fn flush(&self) -> FutureFlush {
+        FutureFlush {
+            device_id: self.device_id,
+            priority: self.priority_or(IoPriority::SyncWrite),
+            state: FutureFlushState::NotYet {
+            },
+            inner: self.inner.clone()
+        }
+    }
+
+    /// Sends `ops` to the block device as a single message and returns a `FutureBatch`
+    /// which resolves to their results, in the same order, once they are all done.
+    pub fn submit_batch(&self, ops: Vec<BatchOp>) -> FutureBatch {
+        FutureBatch::new(self, ops)
+    }
+
+    /// Queries the size of the block device and returns a `FutureSize` which resolves to its size in bytes.
+    pub fn size(&self) -> FutureSize {
+        FutureSize {
+            device_id: self.device_id,
+            priority: self.priority_or(IoPriority::SyncRead),
+            state: FutureSizeState::NotYet {
+            },
+            inner: self.inner.clone()
+        }
+    }
 }
 
 impl Core {
@@ -332,7 +750,7 @@ impl Core {
     ///
     /// Needs:
     ///
-    /// * a channel to send block device requests
+    /// * a channel to send block device requests, to `DEFAULT_DEVICE`
     ///
     /// * a channel to send filesystem responses
     ///
@@ -340,27 +758,61 @@ impl Core {
     pub fn new(bd_sender: Sender<BDRequest>, fs_sender: Sender<FSResponse>, receiver: Receiver<Event>) -> Core {
         Core {
             receiver,
-            inner: Rc::new(RefCell::new(Inner::new(bd_sender, fs_sender)))
+            inner: Rc::new(RefCell::new(Inner::new(bd_sender, fs_sender))),
+            tasks: HashMap::new(),
         }
     }
 
+    /// Adds a block device receiving its requests from `bd_sender` and returns its `DeviceId`.
+    ///
+    /// Its responses are sent to the same channel of `Event`s as the other block devices.
+    pub fn add_device(&mut self, bd_sender: Sender<BDRequest>) -> DeviceId {
+        // borrow inner
+        let mut inner = self.inner.borrow_mut();
+
+        inner.bd_senders.push(bd_sender);
+        DeviceId(inner.bd_senders.len() as u64 - 1)
+    }
+
+    /// Sets the maximum number of requests of the priority class `priority` sent to a block device
+    /// and not answered yet, `DEFAULT_QUEUE_DEPTHS` otherwise.
+    ///
+    /// The other requests stay queued in the reactor meanwhile. Panics if `depth` is 0.
+    pub fn set_queue_depth(&mut self, priority: IoPriority, depth: usize) {
+        // borrow inner
+        let mut inner = self.inner.borrow_mut();
+
+        inner.io_scheduler.set_queue_depth(priority, depth);
+    }
+
     /// Returns a `Handle` to the `Inner` state of the `reactor`.
     pub fn handle(&mut self) -> Handle {
-        Handle {inner: Rc::downgrade(&self.inner)}
+        Handle {inner: Rc::downgrade(&self.inner), priority: None}
     }
 
     /// Runs a `future` until completion.
     ///
+    /// It can be called again afterwards to run other futures on the same `reactor`.
+    /// The spawned tasks which are not done yet when it returns go on during the next call,
+    /// they are aborted if the `Core` is dropped.
+    ///
     /// # Return value
     ///
     /// Returns the `Result` of `future` if any.
     ///
     /// If `future` is never resolved, this function never returns
-    pub fn run<F>(self, mut future: F) -> Result<F::Item, F::Error>
+    pub fn run<F>(&mut self, mut future: F) -> Result<F::Item, F::Error>
     where F: Future {
 
         // list of active tasks
-        let mut tasks: HashMap<TaskId, SpawnedTask> = HashMap::new();
+        let mut tasks = mem::replace(&mut self.tasks, HashMap::new());
+        let result = self.run_tasks(&mut future, &mut tasks);
+        self.tasks = tasks;
+        result
+    }
+
+    fn run_tasks<F>(&mut self, future: &mut F, tasks: &mut HashMap<TaskId, SpawnedTask>) -> Result<F::Item, F::Error>
+    where F: Future {
         {
             // borrow inner
             let mut inner = self.inner.borrow_mut();
@@ -378,75 +830,152 @@ impl Core {
 
         // event loop
         loop {
-            let task_id_to_poll;
+            self.update_tasks(tasks);
+            self.inner.borrow_mut().send_bd_requests();
+
+            let mut tasks_to_poll = Vec::new();
             {
                 // borrow inner
                 let mut inner = self.inner.borrow_mut();
 
-                // move the new tasks to our map of tasks
-                for (task_id, task) in inner.newly_spawned_tasks.drain(0..) {
-                    tasks.insert(task_id, task);
-                }
-
-                // read event from channel
+                // read event from channel, waiting at most until the next timer expires
+                // or not at all if some tasks are ready
                 //println!("reactor: waiting on channel");
-                let event = self.receiver.recv().unwrap();    
+                let event = match inner.timers.next_deadline() {
+                    _ if !inner.ready_tasks.is_empty() => {
+                        match self.receiver.try_recv() {
+                            Ok(event) => Some(event),
+                            Err(TryRecvError::Empty) => None,
+                            Err(TryRecvError::Disconnected) => panic!("reactor: event channel has been closed"),
+                        }
+                    }
+                    None => Some(self.receiver.recv().unwrap()),
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if deadline <= now {
+                            None
+                        } else {
+                            match self.receiver.recv_timeout(deadline - now) {
+                                Ok(event) => Some(event),
+                                Err(RecvTimeoutError::Timeout) => None,
+                                Err(RecvTimeoutError::Disconnected) => panic!("reactor: event channel has been closed"),
+                            }
+                        }
+                    }
+                };
                 //println!("reactor: received event {:?}", event);
 
                 // process event and extract the task_id we need to poll
-                task_id_to_poll = match event {
-                    Event::ToFuture{event_id, task_id, result} => {
-                        inner.events_to_future.insert(event_id, result);
-                        // return extracted task_id
-                        task_id
-                    },
-                    Event::ToStream{stream_id, task_id, result} => {
-                        if let Some(vec) = inner.events_to_streams.get_mut(&stream_id) {
-                            vec.push(result);
-                        } else {
-                            unreachable!("logic error in reactor: trying to add event to non-existing stream");
+                if let Some(event) = event {
+                    match event {
+                        Event::ToFuture{event_id, task_id, result} => {
+                            // the response to a merged request goes to all the requests it is made of
+                            for (event_id, task_id, result) in inner.io_scheduler.split(event_id, task_id, result) {
+                                // nobody is waiting for it anymore
+                                if inner.abandoned_events.remove(&event_id) {
+                                    continue;
+                                }
+                                inner.events_to_future.insert(event_id, result);
+                                if !tasks_to_poll.contains(&task_id) {
+                                    tasks_to_poll.push(task_id);
+                                }
+                            }
+                        },
+                        Event::ToStream{stream_id, task_id, result} => {
+                            if let Some(vec) = inner.events_to_streams.get_mut(&stream_id) {
+                                vec.push(result);
+                            } else {
+                                unreachable!("logic error in reactor: trying to add event to non-existing stream");
+                            }
+                            tasks_to_poll.push(task_id);
                         }
-                        // return extracted task_id
-                        task_id
                     }
-                };
+                }
 
-                // set current_task_id so that the future about to be polled knows
-                // from which task it's called
-                inner.current_task_id = Some(task_id_to_poll);
-            }
+                // the tasks whose timers expired are polled as well
+                for (event_id, task_id) in inner.timers.expire(Instant::now()) {
+                    inner.events_to_future.insert(event_id, Ok(FutureEvent::TimerExpired));
+                    if !tasks_to_poll.contains(&task_id) {
+                        tasks_to_poll.push(task_id);
+                    }
+                }
 
-            // if the task we're about to poll finishes, we'll remove it
-            let mut task_finished = false;
-
-            //println!("reactor: polling future");
-            match task_id_to_poll {
-                // main task
-                TaskId(0) => {
-                    match future.poll() {
-                        Ok(Async::Ready(r)) => return Ok(r),
-                        Err(e) => return Err(e),
-                        Ok(Async::NotReady) => {}
+                // and the tasks which have been woken up
+                for task_id in inner.ready_tasks.drain(0..) {
+                    if !tasks_to_poll.contains(&task_id) {
+                        tasks_to_poll.push(task_id);
                     }
-                },
-                // spawned task
-                TaskId(_) => {
-                    match tasks.get_mut(&task_id_to_poll)
-                            .expect("reactor: received event with unknown task_id").future.poll() {
-                        Ok(Async::NotReady) => {},
-                        Ok(Async::Ready(())) | 
-                        Err(()) => {task_finished = true;}
+                }
+            }
+
+            for task_id_to_poll in tasks_to_poll {
+                // a task polled before may have spawned or aborted tasks
+                self.update_tasks(tasks);
+
+                // set current_task_id so that the future about to be polled knows
+                // from which task it's called
+                self.inner.borrow_mut().current_task_id = Some(task_id_to_poll);
+
+                // if the task we're about to poll finishes, we'll remove it
+                let mut task_finished = false;
+
+                //println!("reactor: polling future");
+                match task_id_to_poll {
+                    // main task
+                    TaskId(0) => {
+                        match future.poll() {
+                            Ok(Async::Ready(r)) => return Ok(r),
+                            Err(e) => return Err(e),
+                            Ok(Async::NotReady) => {}
+                        }
+                    },
+                    // spawned task, unless it is already finished or aborted
+                    TaskId(_) => {
+                        if let Some(task) = tasks.get_mut(&task_id_to_poll) {
+                            match task.future.poll() {
+                                Ok(Async::NotReady) => {},
+                                Ok(Async::Ready(())) |
+                                Err(()) => {task_finished = true;}
+                            }
+                        }
                     }
                 }
+                if task_finished {
+                    // the task is finished, remove it
+                    tasks.remove(&task_id_to_poll);
+                }
             }
-            if task_finished {
-                // the task is finished, remove it
-                tasks.remove(&task_id_to_poll);
+        }
+    }
+
+    /// Moves the newly spawned tasks to `tasks` and drops the aborted ones.
+    fn update_tasks(&self, tasks: &mut HashMap<TaskId, SpawnedTask>) {
+        let aborted_tasks: Vec<TaskId> = {
+            // borrow inner
+            let mut inner = self.inner.borrow_mut();
+
+            for (task_id, task) in inner.newly_spawned_tasks.drain(0..) {
+                tasks.insert(task_id, task);
             }
+            inner.aborted_tasks.drain(0..).collect()
+        };
+
+        // inner is not borrowed anymore: the futures may use it when dropped
+        for task_id in aborted_tasks {
+            tasks.remove(&task_id);
         }
     }
 }
 
+impl Drop for Core {
+    fn drop(&mut self) {
+        // the tasks are dropped while the reactor is still there, finishing their `JoinHandle`s
+        let newly_spawned_tasks: Vec<_> = self.inner.borrow_mut().newly_spawned_tasks.drain(0..).collect();
+        drop(newly_spawned_tasks);
+        self.tasks.clear();
+    }
+}
+
 #[derive(Clone, Debug)]
 enum FutureReadState {
     NotYet{
@@ -463,6 +992,8 @@ enum FutureReadState {
 #[derive(Debug)]
 #[must_use = "futures do nothing unless polled"]
 pub struct FutureRead {
+    device_id: DeviceId,
+    priority: IoPriority,
     state: FutureReadState,
     inner: Weak<RefCell<Inner>>
 }
@@ -489,8 +1020,7 @@ impl Future for FutureRead {
                 let event_id = EventId(inner.id_counter);
                 inner.id_counter+=1;
 
-                inner.bd_sender.send(BDRequest::Read(ReadRequest{event_id, task_id, offset, length}))
-                    .expect("FutureRead::poll: block device channel has been closed");
+                inner.send_bd_request(self.device_id, BDRequest::Read(ReadRequest{event_id, task_id, device_id: self.device_id, priority: self.priority, offset, length}));
                 
                 // update state
                 self.state = FutureReadState::Pending{event_id};
@@ -528,6 +1058,14 @@ impl Future for FutureRead {
     }
 }
 
+impl Drop for FutureRead {
+    fn drop(&mut self) {
+        if let FutureReadState::Pending{event_id} = self.state {
+            cancel_bd_requests(&self.inner, &[event_id]);
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 enum FutureWriteState {
     NotYet{
@@ -541,17 +1079,13 @@ enum FutureWriteState {
 }
 
 impl FutureWriteState {
-    // this is a hack to be able to change to state in-place while moving its content out
+    // changes the state in-place while moving its content out,
+    // it is `Done` meanwhile so that `FutureWrite::drop()` never sees a moved-out state
     #[inline]
     pub fn replace<T, F: FnOnce(Self) -> (Self, T)>(&mut self, f: F) -> T {
-        use std::mem;
-        let mut tmp: Self = unsafe{mem::uninitialized()};
-        mem::swap(&mut tmp, self);
-        let r = f(tmp);
-        tmp = r.0;
-        mem::swap(&mut tmp, self);
-        mem::forget(tmp);
-        return r.1;
+        let (state, r) = f(mem::replace(self, FutureWriteState::Done));
+        *self = state;
+        r
     }
 }
 
@@ -559,6 +1093,8 @@ impl FutureWriteState {
 #[derive(Debug)]
 #[must_use = "futures do nothing unless polled"]
 pub struct FutureWrite {
+    device_id: DeviceId,
+    priority: IoPriority,
     state: FutureWriteState,
     inner: Weak<RefCell<Inner>>
 }
@@ -577,17 +1113,22 @@ impl Future for FutureWrite {
 
         //println!("FutureWrite is polled with task_id={:?}", task_id);
 
+        let device_id = self.device_id;
+        let priority = self.priority;
+
         self.state.replace(|state| {
             match state {
                 // first time the future is polled, push command to queue
                 FutureWriteState::NotYet{offset, data} => {
                     
+                    if inner.read_only {
+                        return (FutureWriteState::Done, Err(format_err!("FutureWrite::poll: block device is read-only (write of {} bytes at {})", data.len(), offset)));
+                    }
                     
                     let event_id = EventId(inner.id_counter);
                     inner.id_counter+=1;
 
-                    inner.bd_sender.send(BDRequest::Write(WriteRequest{event_id, task_id, offset, data}))
-                        .expect("FutureWrite::poll: block device channel has been closed");
+                    inner.send_bd_request(device_id, BDRequest::Write(WriteRequest{event_id, task_id, device_id, priority, offset, data}));
                     
                     // update state and return status
                     (FutureWriteState::Pending{event_id}, Ok(Async::NotReady))
@@ -620,6 +1161,14 @@ impl Future for FutureWrite {
     }
 }
 
+impl Drop for FutureWrite {
+    fn drop(&mut self) {
+        if let FutureWriteState::Pending{event_id} = self.state {
+            cancel_bd_requests(&self.inner, &[event_id]);
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 enum FutureFlushState {
     NotYet{
@@ -634,6 +1183,8 @@ enum FutureFlushState {
 #[derive(Debug)]
 #[must_use = "futures do nothing unless polled"]
 pub struct FutureFlush {
+    device_id: DeviceId,
+    priority: IoPriority,
     state: FutureFlushState,
     inner: Weak<RefCell<Inner>>
 }
@@ -660,8 +1211,7 @@ impl Future for FutureFlush {
                 let event_id = EventId(inner.id_counter);
                 inner.id_counter+=1;
 
-                inner.bd_sender.send(BDRequest::Flush(FlushRequest{event_id, task_id}))
-                    .expect("FutureFlush::poll: block device channel has been closed");
+                inner.send_bd_request(self.device_id, BDRequest::Flush(FlushRequest{event_id, task_id, device_id: self.device_id, priority: self.priority}));
                 
                 // update state
                 self.state = FutureFlushState::Pending{event_id};
@@ -699,6 +1249,100 @@ impl Future for FutureFlush {
     }
 }
 
+impl Drop for FutureFlush {
+    fn drop(&mut self) {
+        if let FutureFlushState::Pending{event_id} = self.state {
+            cancel_bd_requests(&self.inner, &[event_id]);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum FutureSizeState {
+    NotYet{
+    },
+    Pending{
+        event_id: EventId
+    },
+    Done
+}
+
+/// `Future` returned by `Handle::size()` which will resolve to the size of the block device.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureSize {
+    device_id: DeviceId,
+    priority: IoPriority,
+    state: FutureSizeState,
+    inner: Weak<RefCell<Inner>>
+}
+
+impl Future for FutureSize {
+    type Item=u64;
+    type Error=failure::Error;
+    
+    fn poll(&mut self) -> futures::prelude::Poll<Self::Item, Self::Error> {
+        // get mut ref to inner
+        let inner = self.inner.upgrade().unwrap();
+        let mut inner = inner.borrow_mut();
+
+        let task_id = inner.current_task_id
+            .expect("trying to poll a future when the reactor is not running");
+
+        match self.state {
+            // first time the future is polled, push command to queue
+            FutureSizeState::NotYet{} => {
+                
+                
+                let event_id = EventId(inner.id_counter);
+                inner.id_counter+=1;
+
+                inner.send_bd_request(self.device_id, BDRequest::Size(SizeRequest{event_id, task_id, device_id: self.device_id, priority: self.priority}));
+                
+                // update state
+                self.state = FutureSizeState::Pending{event_id};
+                
+                Ok(Async::NotReady)
+            },
+            // we are waiting for the result of the command
+            FutureSizeState::Pending{event_id} => {
+                // if we have the result
+                match inner.events_to_future.remove(&event_id) {
+                    Some(Ok(FutureEvent::SizeResponse(SizeResponse{size}))) => {
+                        // update state
+                        self.state = FutureSizeState::Done;
+                        
+                        Ok(Async::Ready(size))
+                    },
+                    Some(Err(e)) => {
+                        // update state
+                        self.state = FutureSizeState::Done;
+
+                        Err(e)
+                    }
+                    None => {
+                        Ok(Async::NotReady)
+                    },
+                    _ => {
+                        unreachable!("logic error in reactor: mismatch of event type");
+                    }
+                }
+            },
+            FutureSizeState::Done => {
+                panic!("FutureSize polled but already done");
+            }
+        }
+    }
+}
+
+impl Drop for FutureSize {
+    fn drop(&mut self) {
+        if let FutureSizeState::Pending{event_id} = self.state {
+            cancel_bd_requests(&self.inner, &[event_id]);
+        }
+    }
+}
+
 
 #[derive(Debug)]
 enum FSCallStreamState {
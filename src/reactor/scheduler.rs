@@ -0,0 +1,437 @@
+//! The I/O scheduler of the `reactor`, between the I/O `Future`s and the block devices.
+//!
+//! The requests of the `Future`s are queued while the tasks are polled, and `Core::run()`
+//! sends them before waiting for `Event`s. Meanwhile, for each block device:
+//!
+//! * each `IoPriority` class has a maximum number of requests in flight, the other ones stay queued,
+//!   and the most urgent classes are sent first
+//!
+//! * a read or a write never overtakes a queued request it overlaps with, unless both are reads
+//!
+//! * the reads sent together are sorted by offset, and contiguous ones are merged
+//!
+//! * the writes sent together are sorted by offset, and contiguous ones are merged,
+//!   such as the nodes written by a COW commit
+//!
+//! * nothing is reordered across a flush, a size, a vectored or a batch request
+//!
+//! The response to a merged request is split into the responses of the requests it is made of.
+//! If it fails, or is incomplete, they are sent again one by one before anything else,
+//! and meanwhile nothing which depends on the merged request is sent.
+
+use std::mem;
+use std::collections::{HashMap, BTreeMap};
+
+use failure;
+
+use super::*;
+
+/// Merged requests are not made larger than this, unless one of their requests is.
+const MAX_MERGED_BYTES: u64 = 1024 * 1024;
+
+const PRIORITY_CLASSES: usize = 5;
+
+/// The maximum number of requests in flight of each `IoPriority` class, by default.
+pub const DEFAULT_QUEUE_DEPTHS: [usize; PRIORITY_CLASSES] = [32, 32, 16, 4, 2];
+
+/// The priority class of a block device request, from the most to the least urgent.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum IoPriority {
+    /// reads a task is waiting for
+    SyncRead,
+    /// writes a task is waiting for, such as the ones of a commit
+    SyncWrite,
+    /// writes nobody is waiting for yet
+    AsyncWrite,
+    /// reads and writes checking the pool
+    Scrub,
+    /// anything else
+    Background,
+}
+
+/// A request sent to a block device in place of the contiguous requests it has been merged from.
+#[derive(Debug)]
+struct Merged {
+    device_id: DeviceId,
+    offset: u64,
+    length: u64,
+    requests: MergedRequests,
+}
+
+/// The requests a `Merged` request is made of, sent again one by one if it fails.
+#[derive(Debug)]
+enum MergedRequests {
+    Reads(Vec<ReadRequest>),
+    Writes(Vec<WriteRequest>),
+}
+
+/// Requests which can be reordered and merged.
+#[derive(Debug)]
+enum Run {
+    Reads(Vec<ReadRequest>),
+    Writes(Vec<WriteRequest>),
+}
+
+#[derive(Debug)]
+pub struct IoScheduler {
+    queue: Vec<(DeviceId, BDRequest)>,
+    merged: HashMap<EventId, Merged>, // by EventId of the request sent to the block device
+    depths: [usize; PRIORITY_CLASSES],
+    in_flight: [usize; PRIORITY_CLASSES], // by priority class
+    in_flight_events: HashMap<EventId, IoPriority>, // of the requests sent and not answered yet
+}
+
+impl IoScheduler {
+    pub fn new() -> IoScheduler {
+        IoScheduler {
+            queue: Vec::new(),
+            merged: HashMap::new(),
+            depths: DEFAULT_QUEUE_DEPTHS,
+            in_flight: [0; PRIORITY_CLASSES],
+            in_flight_events: HashMap::new(),
+        }
+    }
+
+    pub fn set_queue_depth(&mut self, priority: IoPriority, depth: usize) {
+        assert!(depth > 0, "reactor: the queue depth of {:?} must not be 0", priority);
+        self.depths[priority as usize] = depth;
+    }
+
+    /// Returns the number of requests of `priority` which have been sent and not answered yet.
+    pub fn in_flight(&self, priority: IoPriority) -> usize {
+        self.in_flight[priority as usize]
+    }
+
+    /// Queues `request` until the next call to `schedule()`.
+    pub fn push(&mut self, device_id: DeviceId, request: BDRequest) {
+        self.queue.push((device_id, request));
+    }
+
+    /// Returns the number of requests waiting to be sent.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Removes the request `event_id` from the queue, returns `false` if it is not there,
+    /// because it has already been sent.
+    pub fn cancel(&mut self, event_id: EventId) -> bool {
+        let mut found = false;
+        for (_, request) in self.queue.iter_mut() {
+            if let BDRequest::Batch(b) = request {
+                let len = b.requests.len();
+                b.requests.retain(|r| r.event_id() != Some(event_id));
+                found = b.requests.len() != len;
+            } else {
+                found = request.event_id() == Some(event_id);
+            }
+            if found {
+                break;
+            }
+        }
+
+        // a batch is removed with its last request
+        self.queue.retain(|(_, request)| match request {
+            BDRequest::Batch(b) => !b.requests.is_empty(),
+            request => request.event_id() != Some(event_id),
+        });
+        found
+    }
+
+    /// Returns `true` if `count` more requests of `priority` can be sent.
+    ///
+    /// A batch larger than the queue depth is sent once nothing else of its class is in flight.
+    fn has_room(&self, priority: IoPriority, count: usize) -> bool {
+        let in_flight = self.in_flight[priority as usize];
+        in_flight == 0 || in_flight + count <= self.depths[priority as usize]
+    }
+
+    /// Returns `true` if `request` depends on a merged request which has not been answered yet,
+    /// which may have to be sent again one by one.
+    fn overlaps_merged(&self, device_id: DeviceId, request: &BDRequest) -> bool {
+        self.merged.values()
+            .filter(|merged| merged.device_id == device_id)
+            .any(|merged| {
+                let write = match merged.requests {
+                    MergedRequests::Reads(_) => false,
+                    MergedRequests::Writes(_) => true,
+                };
+                ranges_overlap(Some((merged.offset, merged.offset + merged.length, write)), range(request))
+            })
+    }
+
+    /// Records that `request` is sent.
+    fn start(&mut self, request: &BDRequest) {
+        match (request, request.event_id()) {
+            (_, Some(event_id)) => {
+                let priority = request.priority();
+                self.in_flight_events.insert(event_id, priority);
+                self.in_flight[priority as usize] += 1;
+            },
+            // each request of a batch is answered separately
+            (BDRequest::Batch(b), None) => b.requests.iter().for_each(|r| self.start(r)),
+            _ => unreachable!("logic error in reactor: request without EventId"),
+        }
+    }
+
+    /// Empties the queue and returns the requests to send to each block device, in order.
+    ///
+    /// The requests which can't be sent yet are kept for the next call.
+    /// `id_counter` gives the `EventId`s of the merged requests.
+    pub fn schedule(&mut self, id_counter: &mut u64) -> Vec<(DeviceId, BDRequest)> {
+        let mut queues: BTreeMap<DeviceId, Vec<BDRequest>> = BTreeMap::new();
+        for (device_id, request) in self.queue.drain(0..) {
+            queues.entry(device_id).or_insert_with(Vec::new).push(request);
+        }
+
+        let mut scheduled = Vec::new();
+        for (device_id, requests) in queues {
+            let mut pending = Vec::new(); // still queued, in order
+            let mut selected: BTreeMap<IoPriority, Vec<BDRequest>> = BTreeMap::new(); // to send before the next barrier
+            let mut blocked = false; // by a barrier which can't be sent
+            for request in requests {
+                if blocked {
+                    pending.push(request);
+                    continue;
+                }
+
+                let priority = request.priority();
+                match request {
+                    BDRequest::Read(_) | BDRequest::Write(_) => {
+                        let overtakes = pending.iter()
+                            .chain(selected.values().flat_map(|requests| requests.iter()))
+                            .any(|other| overlap(other, &request))
+                            || self.overlaps_merged(device_id, &request);
+                        if !overtakes && self.has_room(priority, 1) {
+                            self.start(&request);
+                            selected.entry(priority).or_insert_with(Vec::new).push(request);
+                        } else {
+                            pending.push(request);
+                        }
+                    },
+                    // a barrier
+                    request => {
+                        let count = match &request {
+                            BDRequest::Batch(b) => b.requests.len(),
+                            _ => 1,
+                        };
+                        if pending.is_empty() && !self.overlaps_merged(device_id, &request) && self.has_room(priority, count) {
+                            self.schedule_selected(device_id, mem::replace(&mut selected, BTreeMap::new()), id_counter, &mut scheduled);
+                            self.start(&request);
+                            scheduled.push((device_id, request));
+                        } else {
+                            blocked = true;
+                            pending.push(request);
+                        }
+                    }
+                }
+            }
+            self.schedule_selected(device_id, selected, id_counter, &mut scheduled);
+            self.queue.extend(pending.into_iter().map(|request| (device_id, request)));
+        }
+
+        scheduled
+    }
+
+    /// Sends the reads and writes of each priority class, the most urgent first.
+    fn schedule_selected(&mut self, device_id: DeviceId, selected: BTreeMap<IoPriority, Vec<BDRequest>>, id_counter: &mut u64, scheduled: &mut Vec<(DeviceId, BDRequest)>) {
+        for (_, requests) in selected {
+            let mut reads = Vec::new();
+            let mut writes = Vec::new();
+            for request in requests {
+                match request {
+                    BDRequest::Read(r) => reads.push(r),
+                    BDRequest::Write(w) => writes.push(w),
+                    _ => unreachable!("logic error in reactor: only reads and writes are reordered"),
+                }
+            }
+            // they don't overlap with each other
+            self.schedule_run(device_id, Run::Reads(reads), id_counter, scheduled);
+            self.schedule_run(device_id, Run::Writes(writes), id_counter, scheduled);
+        }
+    }
+
+    fn schedule_run(&mut self, device_id: DeviceId, run: Run, id_counter: &mut u64, scheduled: &mut Vec<(DeviceId, BDRequest)>) {
+        match run {
+            Run::Reads(mut reads) => {
+                reads.sort_by_key(|r| r.offset);
+
+                let mut group: Vec<ReadRequest> = Vec::new();
+                let mut group_length = 0;
+                for r in reads {
+                    let contiguous = match group.last() {
+                        Some(last) => last.offset + last.length == r.offset && group_length + r.length <= MAX_MERGED_BYTES,
+                        None => true,
+                    };
+                    if !contiguous {
+                        self.merge_reads(device_id, mem::replace(&mut group, Vec::new()), id_counter, scheduled);
+                        group_length = 0;
+                    }
+                    group_length += r.length;
+                    group.push(r);
+                }
+                self.merge_reads(device_id, group, id_counter, scheduled);
+            },
+            Run::Writes(mut writes) => {
+                writes.sort_by_key(|w| w.offset);
+
+                // overlapping writes would have to stay in order, they are never selected together
+                debug_assert!(writes.windows(2).all(|w| w[0].offset + w[0].data.len() as u64 <= w[1].offset));
+
+                let mut group: Vec<WriteRequest> = Vec::new();
+                let mut group_length = 0;
+                for w in writes {
+                    let contiguous = match group.last() {
+                        Some(last) => last.offset + last.data.len() as u64 == w.offset && group_length + w.data.len() as u64 <= MAX_MERGED_BYTES,
+                        None => true,
+                    };
+                    if !contiguous {
+                        self.merge_writes(device_id, mem::replace(&mut group, Vec::new()), id_counter, scheduled);
+                        group_length = 0;
+                    }
+                    group_length += w.data.len() as u64;
+                    group.push(w);
+                }
+                self.merge_writes(device_id, group, id_counter, scheduled);
+            },
+        }
+    }
+
+    /// Turns contiguous reads into one.
+    fn merge_reads(&mut self, device_id: DeviceId, mut group: Vec<ReadRequest>, id_counter: &mut u64, scheduled: &mut Vec<(DeviceId, BDRequest)>) {
+        if group.len() <= 1 {
+            scheduled.extend(group.pop().map(|r| (device_id, BDRequest::Read(r))));
+            return;
+        }
+
+        let event_id = EventId(*id_counter);
+        *id_counter += 1;
+        let task_id = group[0].task_id;
+        let priority = group[0].priority; // they are all of the same class
+        let offset = group[0].offset;
+        let length: u64 = group.iter().map(|r| r.length).sum();
+
+        self.merged.insert(event_id, Merged{device_id, offset, length, requests: MergedRequests::Reads(group)});
+        scheduled.push((device_id, BDRequest::Read(ReadRequest{event_id, task_id, device_id, priority, offset, length})));
+    }
+
+    /// Turns contiguous writes into one.
+    fn merge_writes(&mut self, device_id: DeviceId, mut group: Vec<WriteRequest>, id_counter: &mut u64, scheduled: &mut Vec<(DeviceId, BDRequest)>) {
+        if group.len() <= 1 {
+            scheduled.extend(group.pop().map(|w| (device_id, BDRequest::Write(w))));
+            return;
+        }
+
+        let event_id = EventId(*id_counter);
+        *id_counter += 1;
+        let task_id = group[0].task_id;
+        let priority = group[0].priority; // they are all of the same class
+        let offset = group[0].offset;
+        // the data is copied: the requests are kept to be sent again if the merged one fails
+        let mut data = Vec::with_capacity(group.iter().map(|w| w.data.len()).sum::<usize>());
+        for w in &group {
+            data.extend_from_slice(&w.data);
+        }
+        let length = data.len() as u64;
+
+        self.merged.insert(event_id, Merged{device_id, offset, length, requests: MergedRequests::Writes(group)});
+        scheduled.push((device_id, BDRequest::Write(WriteRequest{event_id, task_id, device_id, priority, offset, data})));
+    }
+
+    /// Returns the responses to the requests which have been merged into `event_id`,
+    /// or the response itself if it has not been merged.
+    pub fn split(&mut self, event_id: EventId, task_id: TaskId, result: Result<FutureEvent, failure::Error>)
+    -> Vec<(EventId, TaskId, Result<FutureEvent, failure::Error>)> {
+        let responses = self.split_merged(event_id, task_id, result);
+        for (event_id, _, _) in &responses {
+            if let Some(priority) = self.in_flight_events.remove(event_id) {
+                self.in_flight[priority as usize] -= 1;
+            }
+        }
+        responses
+    }
+
+    /// Returns the responses to the requests merged into `event_id`, or nothing if they have to be sent again.
+    fn split_merged(&mut self, event_id: EventId, task_id: TaskId, result: Result<FutureEvent, failure::Error>)
+    -> Vec<(EventId, TaskId, Result<FutureEvent, failure::Error>)> {
+        let merged = match self.merged.remove(&event_id) {
+            Some(merged) => merged,
+            None => return vec![(event_id, task_id, result)],
+        };
+
+        match (merged.requests, result) {
+            (MergedRequests::Reads(reads), Ok(FutureEvent::ReadResponse(ReadResponse{data}))) => {
+                if data.len() as u64 != merged.length {
+                    self.resend(merged.device_id, MergedRequests::Reads(reads));
+                    return Vec::new();
+                }
+
+                let mut offset = 0;
+                reads.into_iter().map(|r| {
+                    let part = data[offset..offset + r.length as usize].to_vec();
+                    offset += r.length as usize;
+                    (r.event_id, r.task_id, Ok(FutureEvent::ReadResponse(ReadResponse{data: part})))
+                }).collect()
+            },
+            (MergedRequests::Writes(writes), Ok(FutureEvent::WriteResponse(WriteResponse{len}))) => {
+                if len != merged.length {
+                    self.resend(merged.device_id, MergedRequests::Writes(writes));
+                    return Vec::new();
+                }
+
+                writes.into_iter()
+                    .map(|w| (w.event_id, w.task_id, Ok(FutureEvent::WriteResponse(WriteResponse{len: w.data.len() as u64}))))
+                    .collect()
+            },
+            (requests, Err(_)) => {
+                // the error may come from any of them
+                self.resend(merged.device_id, requests);
+                Vec::new()
+            },
+            _ => {
+                unreachable!("logic error in reactor: mismatch of event type");
+            }
+        }
+    }
+
+    /// Queues the requests of a failed merged request to be sent again, one by one, before anything else of the device.
+    ///
+    /// They go in a batch so that they are not merged again, each of them gets its own response.
+    fn resend(&mut self, device_id: DeviceId, requests: MergedRequests) {
+        let requests: Vec<BDRequest> = match requests {
+            MergedRequests::Reads(reads) => reads.into_iter().map(BDRequest::Read).collect(),
+            MergedRequests::Writes(writes) => writes.into_iter().map(BDRequest::Write).collect(),
+        };
+
+        // they are in flight again once sent
+        for request in &requests {
+            if let Some(priority) = request.event_id().and_then(|event_id| self.in_flight_events.remove(&event_id)) {
+                self.in_flight[priority as usize] -= 1;
+            }
+        }
+
+        self.queue.insert(0, (device_id, BDRequest::Batch(BatchRequest{requests})));
+    }
+}
+
+/// Returns the bytes accessed by `request` and whether it writes them, `None` for a barrier.
+fn range(request: &BDRequest) -> Option<(u64, u64, bool)> {
+    match request {
+        BDRequest::Read(r) => Some((r.offset, r.offset + r.length, false)),
+        BDRequest::Write(w) => Some((w.offset, w.offset + w.data.len() as u64, true)),
+        _ => None,
+    }
+}
+
+/// Returns `true` if `a` and `b` access the same bytes and one of them writes them.
+fn overlap(a: &BDRequest, b: &BDRequest) -> bool {
+    ranges_overlap(range(a), range(b))
+}
+
+/// Same as `overlap()` with the results of `range()`.
+fn ranges_overlap(a: Option<(u64, u64, bool)>, b: Option<(u64, u64, bool)>) -> bool {
+    match (a, b) {
+        (Some((_, _, false)), Some((_, _, false))) => false,
+        (Some((a_start, a_end, _)), Some((b_start, b_end, _))) => a_start < b_end && b_start < a_end,
+        _ => true, // nothing overtakes a barrier
+    }
+}
@@ -1,8 +1,11 @@
 use futures::Future;
 use futures::prelude::*;
+use futures::future;
 use std::thread;
 use byteorder::{ByteOrder};
-use std::sync::mpsc::channel;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use ::*;
 use super::*;
 use ::backend::mem::*;
@@ -162,3 +165,569 @@ fn write_fibonacci_seq(h:Handle, n: u64) -> Result<()> {
 fn read_error(h:Handle) -> Result<u64> {
     await!(read_u64(&h, 1000000000))
 }
+
+#[test]
+fn timers() {
+    let (bd_sender, bd_receiver) = channel::<BDRequest>();
+    let (fs_sender, _fs_receiver) = channel::<FSResponse>();
+    let (react_sender, react_receiver) = channel::<Event>();
+
+    let react_sender_bd = react_sender.clone();
+    let _bd_thread = thread::spawn(move || {
+        mem_backend_loop(react_sender_bd, bd_receiver, 4096 * 1000);
+    });
+
+    let mut core = Core::new(bd_sender, fs_sender, react_receiver);
+    let handle = core.handle();
+
+    // sleep
+    let start = Instant::now();
+    core.run(handle.sleep(Duration::from_millis(20))).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(20));
+
+    // timeout of a future which is too slow
+    let start = Instant::now();
+    let err = core.run(handle.timeout(handle.sleep(Duration::from_secs(10)), Duration::from_millis(10))).unwrap_err();
+    assert!(err.downcast_ref::<TimedOut>().is_some());
+    assert!(start.elapsed() < Duration::from_secs(10));
+
+    // timeout of a future which resolves in time
+    let f = write_u64(&handle, 42, 0).and_then(|_| read_u64(&handle, 0));
+    assert!(core.run(handle.timeout(f, Duration::from_secs(10))).unwrap() == 42);
+
+    // interval
+    let start = Instant::now();
+    let ticks = core.run(handle.interval(Duration::from_millis(5)).take(3).collect()).unwrap();
+    assert!(ticks.len() == 3);
+    assert!(start.elapsed() >= Duration::from_millis(15));
+
+    // timers of spawned tasks
+    let r = core.run(spawn_sleepers(handle.clone()));
+    assert!(r.unwrap() == vec![1, 2, 3]);
+}
+
+#[async]
+fn spawn_sleepers(h: Handle) -> Result<Vec<u64>> {
+    let woken = Rc::new(RefCell::new(Vec::new()));
+
+    // spawned in the reverse order of their deadlines
+    for i in (1..4).rev() {
+        let woken = woken.clone();
+        let f = h.sleep(Duration::from_millis(10 * i))
+            .map(move |_| woken.borrow_mut().push(i))
+            .map_err(|_| ());
+        h.spawn(f);
+    }
+
+    await!(h.sleep(Duration::from_millis(50)))?;
+    let woken = woken.borrow().clone();
+    Ok(woken)
+}
+
+#[test]
+fn join_handles() {
+    let (bd_sender, bd_receiver) = channel::<BDRequest>();
+    let (fs_sender, _fs_receiver) = channel::<FSResponse>();
+    let (react_sender, react_receiver) = channel::<Event>();
+
+    let react_sender_bd = react_sender.clone();
+    let _bd_thread = thread::spawn(move || {
+        mem_backend_loop(react_sender_bd, bd_receiver, 4096 * 1000);
+    });
+
+    let mut core = Core::new(bd_sender, fs_sender, react_receiver);
+    let handle = core.handle();
+
+    let r = core.run(join_tasks(handle.clone()));
+    assert!(r.is_ok());
+}
+
+#[async]
+fn join_tasks(h: Handle) -> Result<()> {
+    // the result of a task doing I/O
+    let task = h.spawn(write_fibonacci_seq(h.clone(), 10).and_then({
+        let h = h.clone();
+        move |_| read_u64(&h, 10)
+    }));
+    assert!(!task.is_finished());
+    assert!(await!(task).unwrap() == 55);
+
+    // the error of a task
+    let task = h.spawn(read_error(h.clone()));
+    match await!(task) {
+        Err(JoinError::Failed(_)) => {},
+        _ => panic!("the task should have failed"),
+    }
+
+    // a task which is done right away
+    let task = h.spawn(future::ok::<u64, ()>(42));
+    assert!(task.is_finished());
+    assert!(await!(task).unwrap() == 42);
+
+    // an aborted task is never polled again
+    let woken = Rc::new(RefCell::new(false));
+    let task = {
+        let woken = woken.clone();
+        h.spawn(h.sleep(Duration::from_millis(10)).map(move |_| *woken.borrow_mut() = true))
+    };
+    task.abort();
+    assert!(task.is_finished());
+    match await!(task) {
+        Err(JoinError::Aborted) => {},
+        _ => panic!("the task should have been aborted"),
+    }
+    await!(h.sleep(Duration::from_millis(30)))?;
+    assert!(!*woken.borrow());
+
+    Ok(())
+}
+
+#[test]
+fn tasks_across_runs() {
+    let (bd_sender, bd_receiver) = channel::<BDRequest>();
+    let (fs_sender, _fs_receiver) = channel::<FSResponse>();
+    let (react_sender, react_receiver) = channel::<Event>();
+
+    let react_sender_bd = react_sender.clone();
+    let _bd_thread = thread::spawn(move || {
+        mem_backend_loop(react_sender_bd, bd_receiver, 4096 * 1000);
+    });
+
+    let mut core = Core::new(bd_sender, fs_sender, react_receiver);
+    let handle = core.handle();
+
+    // the first run returns before the task is done
+    let task = core.run(future::lazy({
+        let h = handle.clone();
+        move || Ok::<_, ()>(h.spawn(h.sleep(Duration::from_millis(10)).map(|_| 42)))
+    })).unwrap();
+    assert!(!task.is_finished());
+
+    // it goes on during the next one
+    assert!(core.run(task).unwrap() == 42);
+
+    // and is aborted if the reactor is dropped first
+    let mut task = core.run(future::lazy({
+        let h = handle.clone();
+        move || Ok::<_, ()>(h.spawn(h.sleep(Duration::from_secs(3600))))
+    })).unwrap();
+    drop(core);
+    assert!(task.is_finished());
+    match task.poll() {
+        Err(JoinError::Aborted) => {},
+        _ => panic!("the task should have been aborted"),
+    }
+}
+
+#[test]
+fn panicking_tasks() {
+    let (bd_sender, bd_receiver) = channel::<BDRequest>();
+    let (fs_sender, _fs_receiver) = channel::<FSResponse>();
+    let (react_sender, react_receiver) = channel::<Event>();
+
+    let react_sender_bd = react_sender.clone();
+    let _bd_thread = thread::spawn(move || {
+        mem_backend_loop(react_sender_bd, bd_receiver, 4096 * 1000);
+    });
+
+    let mut core = Core::new(bd_sender, fs_sender, react_receiver);
+    let handle = core.handle();
+
+    let r = core.run(panicking_tasks_async(handle.clone()));
+    assert!(r.is_ok());
+}
+
+#[async]
+fn panicking_tasks_async(h: Handle) -> Result<()> {
+    // a task panicking during its first poll
+    let task = h.spawn_catch_unwind(future::lazy(|| -> Result<u64> { panic!("first poll") }));
+    assert!(task.is_finished());
+
+    // a task panicking after having been woken up by the reactor
+    let late_task = h.spawn_catch_unwind(h.sleep(Duration::from_millis(10)).map(|_| -> u64 { panic!("after {}ms", 10) }));
+
+    // the other tasks keep running
+    let other_task = h.spawn(write_fibonacci_seq(h.clone(), 10).and_then({
+        let h = h.clone();
+        move |_| read_u64(&h, 10)
+    }));
+
+    match await!(task) {
+        Err(JoinError::Panicked(message)) => assert!(message == "first poll"),
+        _ => panic!("the task should have panicked"),
+    }
+    match await!(late_task) {
+        Err(JoinError::Panicked(message)) => assert!(message == "after 10ms"),
+        _ => panic!("the task should have panicked"),
+    }
+    assert!(await!(other_task).unwrap() == 55);
+
+    // errors are still reported as such
+    let task = h.spawn_catch_unwind(read_error(h.clone()));
+    match await!(task) {
+        Err(JoinError::Failed(_)) => {},
+        _ => panic!("the task should have failed"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn several_devices() {
+    let (bd_sender, bd_receiver) = channel::<BDRequest>();
+    let (bd_sender_2, bd_receiver_2) = channel::<BDRequest>();
+    let (fs_sender, _fs_receiver) = channel::<FSResponse>();
+    let (react_sender, react_receiver) = channel::<Event>();
+
+    let react_sender_bd = react_sender.clone();
+    let _bd_thread = thread::spawn(move || {
+        mem_backend_loop(react_sender_bd, bd_receiver, 4096 * 1000);
+    });
+    let react_sender_bd_2 = react_sender.clone();
+    let _bd_thread_2 = thread::spawn(move || {
+        mem_backend_loop(react_sender_bd_2, bd_receiver_2, 4096 * 10);
+    });
+
+    let mut core = Core::new(bd_sender, fs_sender, react_receiver);
+    let device_id = core.add_device(bd_sender_2);
+    assert!(device_id == DeviceId(1));
+    let handle = core.handle();
+    assert!(handle.device_ids() == vec![DEFAULT_DEVICE, device_id]);
+
+    let r = core.run(write_devices(handle.clone(), device_id));
+    assert!(r.is_ok());
+}
+
+#[async]
+fn write_devices(h: Handle, device_id: DeviceId) -> Result<()> {
+    let device = h.device(device_id);
+    assert!(await!(device.size())? == 4096 * 10);
+    assert!(await!(h.size())? == 4096 * 1000);
+
+    // the same offset on each device
+    await!(write_u64(&h, 1, 0))?;
+    let mut data = vec![0;8];
+    byteorder::BigEndian::write_u64(&mut data, 2);
+    await!(device.write(data, 0))?;
+    await!(device.flush())?;
+
+    assert!(await!(read_u64(&h, 0))? == 1);
+    let data = await!(device.read(0, 8))?;
+    assert!(byteorder::BigEndian::read_u64(&data) == 2);
+
+    // errors come from the device the request was sent to
+    assert!(await!(device.read(4096 * 10, 8)).is_err());
+    assert!(await!(h.read(4096 * 10, 8)).is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn vectored_and_batched_requests() {
+    let (bd_sender, bd_receiver) = channel::<BDRequest>();
+    let (fs_sender, _fs_receiver) = channel::<FSResponse>();
+    let (react_sender, react_receiver) = channel::<Event>();
+
+    let react_sender_bd = react_sender.clone();
+    let _bd_thread = thread::spawn(move || {
+        mem_backend_loop(react_sender_bd, bd_receiver, 4096 * 10);
+    });
+
+    let mut core = Core::new(bd_sender, fs_sender, react_receiver);
+    let handle = core.handle();
+
+    let r = core.run(vectored_and_batched_async(handle.clone()));
+    assert!(r.is_ok());
+}
+
+#[async]
+fn vectored_and_batched_async(h: Handle) -> Result<()> {
+    // vectored requests
+    let len = await!(h.write_v(vec![
+        WriteSegment{offset: 0, data: vec![1;16]},
+        WriteSegment{offset: 4096, data: vec![2;8]},
+    ]))?;
+    assert!(len == 24);
+    let data = await!(h.read_v(vec![
+        ReadSegment{offset: 4096, length: 8},
+        ReadSegment{offset: 8, length: 8},
+    ]))?;
+    assert!(data == vec![vec![2;8], vec![1;8]]);
+    assert!(await!(h.read_v(vec![ReadSegment{offset: 0, length: 8}, ReadSegment{offset: 4096 * 10, length: 8}])).is_err());
+
+    // a batch resolves once all its requests are done
+    let results = await!(h.submit_batch(vec![
+        BatchOp::Write{offset: 8192, data: vec![3;4]},
+        BatchOp::Flush,
+        BatchOp::Read{offset: 8192, length: 4},
+        BatchOp::Read{offset: 4096, length: 2},
+    ]))?;
+    assert!(results == vec![
+        BatchResult::Write(4),
+        BatchResult::Flush,
+        BatchResult::Read(vec![3;4]),
+        BatchResult::Read(vec![2;2]),
+    ]);
+    assert!(await!(h.submit_batch(Vec::new()))?.is_empty());
+
+    // a failed request fails the whole batch
+    assert!(await!(h.submit_batch(vec![
+        BatchOp::Read{offset: 0, length: 8},
+        BatchOp::Read{offset: 4096 * 10, length: 8},
+    ])).is_err());
+
+    // the reactor keeps working after the failed batch
+    assert!(await!(h.read(0, 16))? == vec![1;16]);
+
+    Ok(())
+}
+
+#[test]
+fn io_scheduler() {
+    let (bd_sender, bd_receiver) = channel::<BDRequest>();
+    let (mem_sender, mem_receiver) = channel::<BDRequest>();
+    let (fs_sender, _fs_receiver) = channel::<FSResponse>();
+    let (react_sender, react_receiver) = channel::<Event>();
+
+    let react_sender_bd = react_sender.clone();
+    let _bd_thread = thread::spawn(move || {
+        mem_backend_loop(react_sender_bd, mem_receiver, 4096 * 1000);
+    });
+
+    // records the (offset, length) of the reads and writes seen by the backend
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen_recorder = seen.clone();
+    let _recorder_thread = thread::spawn(move || {
+        for request in bd_receiver {
+            match &request {
+                BDRequest::Read(r) => seen_recorder.lock().unwrap().push(("read", r.offset, r.length)),
+                BDRequest::Write(w) => seen_recorder.lock().unwrap().push(("write", w.offset, w.data.len() as u64)),
+                BDRequest::Flush(_) => seen_recorder.lock().unwrap().push(("flush", 0, 0)),
+                _ => {},
+            }
+            mem_sender.send(request).unwrap();
+        }
+    });
+
+    let mut core = Core::new(bd_sender, fs_sender, react_receiver);
+    let handle = core.handle();
+
+    let r = core.run(schedule_ios(handle.clone()));
+    assert!(r.is_ok());
+
+    assert!(*seen.lock().unwrap() == vec![
+        // contiguous writes are merged, whatever their order
+        ("write", 0, 4 * 8),
+        ("write", 100 * 8, 8),
+        ("flush", 0, 0),
+        // reads are sorted and merged as well, but not across a flush
+        ("read", 0, 2 * 8),
+        ("flush", 0, 0),
+        ("read", 3 * 8, 8),
+        ("read", 100 * 8, 8),
+    ]);
+}
+
+#[async]
+fn schedule_ios(h: Handle) -> Result<()> {
+    let writes = vec![
+        write_u64(&h, 2, 2),
+        write_u64(&h, 0, 0),
+        write_u64(&h, 100, 100),
+        write_u64(&h, 1, 1),
+        write_u64(&h, 3, 3),
+    ];
+    let lens = await!(future::join_all(writes))?;
+    assert!(lens == vec![8; 5]);
+
+    let reads: Vec<Box<Future<Item=u64, Error=failure::Error>>> = vec![
+        Box::new(h.flush().and_then({ let h = h.clone(); move |_| read_u64(&h, 3) })),
+        Box::new(read_u64(&h, 1)),
+        Box::new(read_u64(&h, 0)),
+        Box::new(h.flush().and_then({ let h = h.clone(); move |_| read_u64(&h, 100) })),
+    ];
+    let values = await!(future::join_all(reads))?;
+    assert!(values == vec![3, 1, 0, 100]);
+
+    Ok(())
+}
+
+#[test]
+fn merged_write_failure() {
+    let (bd_sender, bd_receiver) = channel::<BDRequest>();
+    let (mem_sender, mem_receiver) = channel::<BDRequest>();
+    let (fs_sender, _fs_receiver) = channel::<FSResponse>();
+    let (react_sender, react_receiver) = channel::<Event>();
+
+    let react_sender_bd = react_sender.clone();
+    let _bd_thread = thread::spawn(move || {
+        mem_backend_loop(react_sender_bd, mem_receiver, 4096 * 1000);
+    });
+
+    // records the kind, offset and length of the requests seen by the backend
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen_recorder = seen.clone();
+    let _recorder_thread = thread::spawn(move || {
+        for request in bd_receiver {
+            match &request {
+                BDRequest::Write(w) => seen_recorder.lock().unwrap().push(("write", w.offset, w.data.len() as u64)),
+                BDRequest::Batch(b) => seen_recorder.lock().unwrap().push(("batch", 0, b.requests.len() as u64)),
+                _ => {},
+            }
+            mem_sender.send(request).unwrap();
+        }
+    });
+
+    let mut core = Core::new(bd_sender, fs_sender, react_receiver);
+    let handle = core.handle();
+
+    let r = core.run(merged_write_failure_async(handle.clone()));
+    assert!(r.is_ok());
+
+    assert!(*seen.lock().unwrap() == vec![
+        // the merged write goes past the end of the device
+        ("write", 4096 * 1000 - 8, 2 * 8),
+        // so both writes are sent again, one by one
+        ("batch", 0, 2),
+    ]);
+}
+
+#[async]
+fn merged_write_failure_async(h: Handle) -> Result<()> {
+    // the last 8 bytes of the device, and the 8 bytes after it
+    let last = 4096 * 1000 / 8 - 1;
+    let (inside, outside) = await!(write_u64(&h, 1, last).then(Ok::<_, failure::Error>)
+        .join(write_u64(&h, 2, last + 1).then(Ok::<_, failure::Error>)))?;
+
+    // only the write which can't be done fails
+    assert!(inside.unwrap() == 8);
+    assert!(outside.is_err());
+    assert!(await!(read_u64(&h, last))? == 1);
+
+    Ok(())
+}
+
+#[test]
+fn io_priorities() {
+    let (bd_sender, bd_receiver) = channel::<BDRequest>();
+    let (mem_sender, mem_receiver) = channel::<BDRequest>();
+    let (fs_sender, _fs_receiver) = channel::<FSResponse>();
+    let (react_sender, react_receiver) = channel::<Event>();
+
+    let react_sender_bd = react_sender.clone();
+    let _bd_thread = thread::spawn(move || {
+        mem_backend_loop(react_sender_bd, mem_receiver, 4096 * 1000);
+    });
+
+    // records the kind, offset and priority class of the requests seen by the backend
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen_recorder = seen.clone();
+    let _recorder_thread = thread::spawn(move || {
+        for request in bd_receiver {
+            match &request {
+                BDRequest::Read(r) => seen_recorder.lock().unwrap().push(("read", r.offset, r.priority)),
+                BDRequest::Write(w) => seen_recorder.lock().unwrap().push(("write", w.offset, w.priority)),
+                _ => {},
+            }
+            mem_sender.send(request).unwrap();
+        }
+    });
+
+    let mut core = Core::new(bd_sender, fs_sender, react_receiver);
+    core.set_queue_depth(IoPriority::Background, 1);
+    let handle = core.handle();
+
+    let r = core.run(prioritize_ios(handle.clone()));
+    assert!(r.is_ok());
+
+    assert!(*seen.lock().unwrap() == vec![
+        // one background read at a time, the sync read goes first
+        ("read", 8192, IoPriority::SyncRead),
+        ("read", 0, IoPriority::Background),
+        ("read", 4096, IoPriority::Background),
+        ("read", 8192, IoPriority::Background),
+        // a read never overtakes a write of the same bytes
+        ("write", 0, IoPriority::AsyncWrite),
+        ("read", 0, IoPriority::SyncRead),
+    ]);
+}
+
+#[async]
+fn prioritize_ios(h: Handle) -> Result<()> {
+    let background = h.with_priority(IoPriority::Background);
+    assert!(background.priority() == Some(IoPriority::Background));
+
+    let reads = vec![
+        background.read(0, 8),
+        background.read(4096, 8),
+        background.read(8192, 8),
+        h.read(8192, 8),
+    ];
+    await!(future::join_all(reads))?;
+
+    let write = h.with_priority(IoPriority::AsyncWrite).write(vec![42; 8], 0).map(|_| ());
+    let (_, data) = await!(write.join(h.read(0, 8)))?;
+    assert!(data == vec![42; 8]);
+
+    Ok(())
+}
+
+#[test]
+fn dropped_requests() {
+    let (bd_sender, bd_receiver) = channel::<BDRequest>();
+    let (gated_sender, gated_receiver) = channel::<BDRequest>();
+    let (open_sender, open_receiver) = channel::<()>();
+    let (fs_sender, _fs_receiver) = channel::<FSResponse>();
+    let (react_sender, react_receiver) = channel::<Event>();
+
+    let react_sender_bd = react_sender.clone();
+    let _bd_thread = thread::spawn(move || {
+        mem_backend_loop(react_sender_bd, gated_receiver, 4096 * 1000);
+    });
+
+    // a block device which answers nothing until the test opens the gate
+    let _gate_thread = thread::spawn(move || {
+        let _ = open_receiver.recv();
+        for request in bd_receiver {
+            gated_sender.send(request).unwrap();
+        }
+    });
+
+    let mut core = Core::new(bd_sender, fs_sender, react_receiver);
+    let handle = core.handle();
+
+    let r = core.run(drop_requests(handle.clone(), open_sender));
+    assert!(r.is_ok());
+}
+
+#[async]
+fn drop_requests(h: Handle, open: Sender<()>) -> Result<()> {
+    let leftovers = {
+        let h = h.clone();
+        move || {
+            let inner = h.inner.upgrade().unwrap();
+            let inner = inner.borrow();
+            (inner.io_scheduler.len(), inner.events_to_future.len(), inner.abandoned_events.len())
+        }
+    };
+
+    // dropped before being sent: never sent
+    let r = await!(h.timeout(h.read(0, 8), Duration::from_millis(0)));
+    assert!(r.is_err());
+    assert!(leftovers() == (0, 0, 0));
+
+    // dropped after being sent: the late responses are discarded
+    let r = await!(h.timeout(h.write(vec![1; 8], 0).join(h.read(4096, 8)), Duration::from_millis(5)));
+    assert!(r.is_err());
+    assert!(leftovers() == (0, 0, 2));
+    let r = await!(h.timeout(h.submit_batch(vec![BatchOp::Flush, BatchOp::Read{offset: 0, length: 8}]), Duration::from_millis(5)));
+    assert!(r.is_err());
+    assert!(leftovers() == (0, 0, 4));
+
+    // the reactor keeps working meanwhile
+    open.send(()).unwrap();
+    assert!(await!(h.read(0, 8))? == vec![1; 8]);
+    assert!(leftovers() == (0, 0, 0));
+
+    Ok(())
+}
@@ -0,0 +1,327 @@
+//! Timers of the `reactor`: `FutureSleep`, `Interval` and `Timeout`.
+//!
+//! They are registered in the `TimerWheel` of `Inner` and `Core::run()` stops waiting
+//! for `Event`s when the next one expires.
+
+use std::fmt;
+use std::rc::Weak;
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+
+use failure;
+
+use super::*;
+
+const WHEEL_SLOTS: u64 = 256;
+const TICK_NANOS: u64 = 1_000_000; // 1ms
+
+#[derive(Debug)]
+struct Timer {
+    tick: u64,
+    event_id: EventId,
+    task_id: TaskId,
+}
+
+/// A hashed timer wheel with a resolution of one tick.
+///
+/// A timer expiring at tick `t` is kept in the slot `t % WHEEL_SLOTS`,
+/// so timers more than one turn away share their slot with closer ones.
+#[derive(Debug)]
+pub struct TimerWheel {
+    start: Instant,
+    slots: Vec<Vec<Timer>>,
+    expired_tick: u64, // all the timers up to this tick have expired
+    len: usize,
+}
+
+impl TimerWheel {
+    pub fn new() -> TimerWheel {
+        TimerWheel {
+            start: Instant::now(),
+            slots: (0..WHEEL_SLOTS).map(|_| Vec::new()).collect(),
+            expired_tick: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns the number of ticks between the creation of the wheel and `instant`.
+    fn ticks(&self, instant: Instant, round_up: bool) -> u64 {
+        if instant <= self.start {
+            return 0;
+        }
+        let elapsed = instant.duration_since(self.start);
+        let nanos = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+        match round_up {
+            true => (nanos + TICK_NANOS - 1) / TICK_NANOS,
+            false => nanos / TICK_NANOS,
+        }
+    }
+
+    fn slot(tick: u64) -> usize {
+        (tick % WHEEL_SLOTS) as usize
+    }
+
+    /// Registers a timer for the task `task_id` expiring at `deadline`.
+    ///
+    /// Returns the tick to give to `cancel()`.
+    pub fn insert(&mut self, deadline: Instant, event_id: EventId, task_id: TaskId) -> u64 {
+        // rounded up so that timers never expire early
+        let tick = self.ticks(deadline, true).max(self.expired_tick + 1);
+        self.slots[Self::slot(tick)].push(Timer{tick, event_id, task_id});
+        self.len += 1;
+        tick
+    }
+
+    /// Removes a timer which has not expired yet.
+    pub fn cancel(&mut self, tick: u64, event_id: EventId) {
+        let slot = &mut self.slots[Self::slot(tick)];
+        if let Some(i) = slot.iter().position(|t| t.event_id == event_id) {
+            slot.swap_remove(i);
+            self.len -= 1;
+        }
+    }
+
+    /// Returns when the next timer expires.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.len == 0 {
+            return None;
+        }
+
+        // look for the first timer expiring during the next turn
+        for tick in (self.expired_tick + 1)..(self.expired_tick + 1 + WHEEL_SLOTS) {
+            if self.slots[Self::slot(tick)].iter().any(|t| t.tick == tick) {
+                return Some(self.instant(tick));
+            }
+        }
+
+        // all the timers are further away
+        self.slots.iter()
+            .flat_map(|slot| slot.iter().map(|t| t.tick))
+            .min()
+            .map(|tick| self.instant(tick))
+    }
+
+    fn instant(&self, tick: u64) -> Instant {
+        self.start + Duration::new(tick * TICK_NANOS / 1_000_000_000, (tick * TICK_NANOS % 1_000_000_000) as u32)
+    }
+
+    /// Removes the timers which have expired at `now` and returns them.
+    pub fn expire(&mut self, now: Instant) -> Vec<(EventId, TaskId)> {
+        let now_tick = self.ticks(now, false);
+        let mut expired = Vec::new();
+
+        // after a long wait, each slot is only visited once
+        let last_tick = now_tick.min(self.expired_tick + WHEEL_SLOTS);
+        for tick in (self.expired_tick + 1)..(last_tick + 1) {
+            let slot = &mut self.slots[Self::slot(tick)];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].tick <= now_tick {
+                    let timer = slot.swap_remove(i);
+                    expired.push((timer.event_id, timer.task_id));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        self.expired_tick = self.expired_tick.max(now_tick);
+        self.len -= expired.len();
+        expired
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum FutureSleepState {
+    NotYet,
+    Pending{
+        event_id: EventId,
+        tick: u64
+    },
+    Done
+}
+
+/// `Future` returned by `Handle::sleep()` and `Handle::sleep_until()` which resolves at its deadline.
+///
+/// Dropping it before then cancels its timer.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureSleep {
+    deadline: Instant,
+    state: FutureSleepState,
+    inner: Weak<RefCell<Inner>>
+}
+
+impl FutureSleep {
+    pub fn new(handle: &Handle, deadline: Instant) -> FutureSleep {
+        FutureSleep {
+            deadline,
+            state: FutureSleepState::NotYet,
+            inner: handle.inner.clone()
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+impl Future for FutureSleep {
+    type Item=();
+    type Error=failure::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // get mut ref to inner
+        let inner = self.inner.upgrade().unwrap();
+        let mut inner = inner.borrow_mut();
+
+        let task_id = inner.current_task_id
+            .expect("trying to poll a future when the reactor is not running");
+
+        match self.state {
+            // first time the future is polled, register the timer
+            FutureSleepState::NotYet => {
+                if self.deadline <= Instant::now() {
+                    self.state = FutureSleepState::Done;
+                    return Ok(Async::Ready(()));
+                }
+
+                let event_id = EventId(inner.id_counter);
+                inner.id_counter+=1;
+
+                let tick = inner.timers.insert(self.deadline, event_id, task_id);
+
+                // update state
+                self.state = FutureSleepState::Pending{event_id, tick};
+
+                Ok(Async::NotReady)
+            },
+            // we are waiting for the timer to expire
+            FutureSleepState::Pending{event_id, ..} => {
+                match inner.events_to_future.remove(&event_id) {
+                    Some(Ok(FutureEvent::TimerExpired)) => {
+                        // update state
+                        self.state = FutureSleepState::Done;
+
+                        Ok(Async::Ready(()))
+                    },
+                    None => {
+                        Ok(Async::NotReady)
+                    },
+                    _ => {
+                        unreachable!("logic error in reactor: mismatch of event type");
+                    }
+                }
+            },
+            FutureSleepState::Done => {
+                panic!("FutureSleep polled but already done");
+            }
+        }
+    }
+}
+
+impl Drop for FutureSleep {
+    fn drop(&mut self) {
+        if let FutureSleepState::Pending{event_id, tick} = self.state {
+            // the reactor may be gone already
+            if let Some(inner) = self.inner.upgrade() {
+                let mut inner = inner.borrow_mut();
+                inner.timers.cancel(tick, event_id);
+                inner.events_to_future.remove(&event_id); // in case it expired but was not polled
+            }
+        }
+    }
+}
+
+/// `Stream` returned by `Handle::interval()` which yields `()` every period.
+///
+/// The deadlines do not drift: when the consumer is late, the next items are yielded right away.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct Interval {
+    sleep: FutureSleep,
+    period: Duration,
+}
+
+impl Interval {
+    pub fn new(handle: &Handle, period: Duration) -> Interval {
+        Interval {
+            sleep: FutureSleep::new(handle, Instant::now() + period),
+            period,
+        }
+    }
+}
+
+impl Stream for Interval {
+    type Item=();
+    type Error=failure::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.sleep.poll()? {
+            Async::Ready(()) => {
+                let deadline = self.sleep.deadline + self.period;
+                self.sleep = FutureSleep {
+                    deadline,
+                    state: FutureSleepState::NotYet,
+                    inner: self.sleep.inner.clone()
+                };
+                Ok(Async::Ready(Some(())))
+            },
+            Async::NotReady => Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Error of a `Timeout` whose future did not resolve in time.
+#[derive(Debug)]
+pub struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Timed out")
+    }
+}
+
+impl failure::Fail for TimedOut {}
+
+/// `Future` returned by `Handle::timeout()`.
+///
+/// It resolves to the result of its future, or fails with `TimedOut` if its deadline comes first.
+/// The future is then dropped: its I/Os not sent to the block device yet are cancelled,
+/// and the responses to the other ones are discarded.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Timeout<F> {
+    future: F,
+    sleep: FutureSleep,
+}
+
+impl<F> Timeout<F> {
+    pub fn new(future: F, sleep: FutureSleep) -> Timeout<F> {
+        Timeout {
+            future,
+            sleep,
+        }
+    }
+}
+
+impl<F: Future> Future for Timeout<F>
+where F::Error: Into<failure::Error> {
+    type Item=F::Item;
+    type Error=failure::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.future.poll() {
+            Ok(Async::Ready(r)) => return Ok(Async::Ready(r)),
+            Err(e) => return Err(e.into()),
+            Ok(Async::NotReady) => {}
+        }
+
+        match self.sleep.poll()? {
+            Async::Ready(()) => Err(TimedOut.into()),
+            Async::NotReady => Ok(Async::NotReady)
+        }
+    }
+}